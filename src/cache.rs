@@ -0,0 +1,154 @@
+use image::{Rgba, RgbaImage};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::decode::ColorMap;
+use crate::notebook::Page;
+
+/// Composited page images saved as PNG files, so re-running a conversion on an unchanged notebook skips decoding.
+///
+/// Entries are keyed by the input file (path and modification time), the page and every setting that changes how
+/// the page is composited, and by the version of this crate, which may composite pages differently. Editing the
+/// notebook changes its modification time, so stale entries are never read; they are simply left behind.
+pub(crate) struct PageCache {
+    dir: PathBuf,
+    input_path: PathBuf,
+    input_mtime: SystemTime,
+}
+
+impl PageCache {
+    /// Prepares a cache in `dir` for pages of `input_path`. Returns `None`, telling `warn` why, if the cache can't be used.
+    pub(crate) fn new(dir: &Path, input_path: &Path, warn: &dyn Fn(&str)) -> Option<PageCache> {
+        let prepared = fs::create_dir_all(dir).and_then(|_| Ok((input_path.canonicalize()?, fs::metadata(input_path)?.modified()?)));
+        match prepared {
+            Ok((input_path, input_mtime)) => Some(PageCache {
+                dir: dir.to_path_buf(),
+                input_path,
+                input_mtime,
+            }),
+            Err(e) => {
+                warn(&format!("not using page cache '{}': {}", dir.display(), e));
+                None
+            }
+        }
+    }
+
+    /// Entries are named by a SHA-256 of the key, which, unlike std's hashers, stays the same across Rust releases,
+    /// so a toolchain upgrade doesn't orphan the whole cache.
+    fn entry_path(&self, page: &Page, layer_order: Option<&[String]>, color_map: &ColorMap, background: Rgba<u8>) -> PathBuf {
+        let mut hasher = Sha256::new();
+        // Each variable-length field is prefixed with its length, so different keys can't run together the same way.
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+        field(env!("CARGO_PKG_VERSION").as_bytes());
+        field(self.input_path.as_os_str().as_encoded_bytes());
+        let mtime_nanos = self.input_mtime.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+        field(&mtime_nanos.to_le_bytes());
+        field(&page.addr.to_le_bytes());
+        match layer_order {
+            Some(keys) => {
+                field(&(keys.len() as u64).to_le_bytes());
+                keys.iter().for_each(|key| field(key.as_bytes()));
+            }
+            None => field(b""),
+        }
+        field(color_map.as_bytes());
+        field(&background.0);
+        let hash: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        self.dir.join(format!("{}.png", hash))
+    }
+
+    /// The cached composite of a page, if there is one newer than the input file.
+    pub(crate) fn load(&self, page: &Page, layer_order: Option<&[String]>, color_map: &ColorMap, background: Rgba<u8>) -> Option<RgbaImage> {
+        let path = self.entry_path(page, layer_order, color_map, background);
+        let cached_at = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        if cached_at < self.input_mtime {
+            return None;
+        }
+        image::open(&path).ok().map(|image| image.to_rgba8())
+    }
+
+    /// Saves a page composite. Failing to write the cache only costs speed, so it goes to `warn`, not an error.
+    pub(crate) fn store(
+        &self,
+        page: &Page,
+        layer_order: Option<&[String]>,
+        color_map: &ColorMap,
+        background: Rgba<u8>,
+        canvas: &RgbaImage,
+        warn: &dyn Fn(&str),
+    ) {
+        let path = self.entry_path(page, layer_order, color_map, background);
+        if let Err(e) = canvas.save(&path) {
+            warn(&format!("could not write page cache '{}': {}", path.display(), e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::Duration;
+
+    fn no_warnings(message: &str) {
+        panic!("unexpected warning: {}", message);
+    }
+
+    fn page_image() -> RgbaImage {
+        RgbaImage::from_fn(30, 20, |x, y| Rgba([x as u8, y as u8, 0x80, 0xff]))
+    }
+
+    #[test]
+    fn stored_pages_are_loaded_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("a.note");
+        fs::write(&input_path, b"note").unwrap();
+        let cache = PageCache::new(&dir.path().join("cache"), &input_path, &no_warnings).unwrap();
+        let (page, color_map, white) = (Page::default(), ColorMap::default(), Rgba([255, 255, 255, 255]));
+
+        assert_eq!(cache.load(&page, None, &color_map, white), None);
+        cache.store(&page, None, &color_map, white, &page_image(), &no_warnings);
+        assert_eq!(cache.load(&page, None, &color_map, white), Some(page_image()));
+        // Any other setting is another entry.
+        assert_eq!(cache.load(&page, None, &color_map, Rgba([0, 0, 0, 0])), None);
+        assert_eq!(cache.load(&page, Some(&["MAINLAYER".to_string()]), &color_map, white), None);
+    }
+
+    #[test]
+    fn entries_older_than_the_input_are_not_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("a.note");
+        fs::write(&input_path, b"note").unwrap();
+        let cache_dir = dir.path().join("cache");
+        let cache = PageCache::new(&cache_dir, &input_path, &no_warnings).unwrap();
+        let (page, color_map, white) = (Page::default(), ColorMap::default(), Rgba([255, 255, 255, 255]));
+        cache.store(&page, None, &color_map, white, &page_image(), &no_warnings);
+
+        // An entry written before the input was last modified, under the same key.
+        let entry = fs::read_dir(&cache_dir).unwrap().next().unwrap().unwrap().path();
+        File::options()
+            .write(true)
+            .open(&entry)
+            .unwrap()
+            .set_modified(cache.input_mtime - Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(cache.load(&page, None, &color_map, white), None);
+
+        // Editing the input changes the key as well.
+        File::options()
+            .write(true)
+            .open(&input_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let edited = PageCache::new(&cache_dir, &input_path, &no_warnings).unwrap();
+        assert_eq!(edited.load(&page, None, &color_map, white), None);
+        edited.store(&page, None, &color_map, white, &page_image(), &no_warnings);
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 2);
+    }
+}
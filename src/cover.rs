@@ -0,0 +1,73 @@
+use ab_glyph::FontRef;
+use image::{Rgba, RgbaImage};
+use imageproc::drawing::{draw_text_mut, text_size};
+
+use crate::notebook::Notebook;
+
+/// DejaVu Sans, bundled so cover pages look the same on every system, and embedded in PDFs as their text font.
+/// See `assets/fonts/LICENSE-DejaVu.txt`.
+pub(crate) static FONT_DATA: &[u8] = include_bytes!("../assets/fonts/DejaVuSans.ttf");
+
+/// Cover text is drawn like pen ink, so post-processing (inverting, tinting) treats it the same way.
+const COVER_INK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+/// Draws a `width` by `height` title page on `paper`: the notebook's title (or `fallback_title` if it has none),
+/// the device it was written on, the number of pages that follow, and the date its first page was created.
+pub(crate) fn render_cover(notebook: &Notebook, fallback_title: &str, page_count: usize, width: u32, height: u32, paper: Rgba<u8>) -> RgbaImage {
+    let font = FontRef::try_from_slice(FONT_DATA).expect("the bundled font is a valid TrueType font");
+    let mut cover = RgbaImage::from_pixel(width, height, paper);
+
+    let title = notebook.header.note_title.as_deref().unwrap_or(fallback_title);
+    let title_size = width as f32 / 12.0;
+    let detail_size = title_size / 2.5;
+    let mut y = height / 3;
+    y += draw_centered(&mut cover, &font, title, title_size, y) + detail_size as u32 * 2;
+
+    let mut details = Vec::new();
+    if let Some(equipment) = notebook.header.equipment.as_deref() {
+        details.push(format!("Supernote {}", equipment));
+    }
+    details.push(if page_count == 1 {
+        "1 page".to_string()
+    } else {
+        format!("{} pages", page_count)
+    });
+    if let Some(created_at) = notebook.pages.iter().filter_map(|page| page.created_at).min() {
+        details.push(format!("Created {}", format_date(created_at)));
+    }
+    for line in &details {
+        y += draw_centered(&mut cover, &font, line, detail_size, y) + detail_size as u32 / 2;
+    }
+    cover
+}
+
+/// Draws one line of text centered horizontally with its top at `y`, and returns its height.
+/// Lines too wide for the page are drawn smaller rather than wrapped.
+fn draw_centered(image: &mut RgbaImage, font: &FontRef, text: &str, size: f32, y: u32) -> u32 {
+    let max_width = image.width() as f32 * 0.9;
+    let (text_width, _) = text_size(size, font, text);
+    let size = if text_width as f32 > max_width {
+        size * max_width / text_width as f32
+    } else {
+        size
+    };
+    let (text_width, text_height) = text_size(size, font, text);
+    let x = image.width().saturating_sub(text_width) / 2;
+    draw_text_mut(image, COVER_INK, x as i32, y as i32, size, font, text);
+    text_height
+}
+
+/// Formats milliseconds since the Unix epoch as a `YYYY-MM-DD` date, in UTC.
+fn format_date(ms: u64) -> String {
+    // Howard Hinnant's `civil_from_days`, with March as the first month of the year so leap days come last.
+    let days = (ms / 86_400_000) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
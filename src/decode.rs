@@ -0,0 +1,602 @@
+use image::{Rgba, RgbaImage};
+use itertools::Itertools;
+use rayon::prelude::*;
+use std::fs::{self, File};
+use std::path::Path;
+
+use crate::error::{Result, SupernoteError};
+use crate::notebook::{DEVICE_DIMENSIONS, Layer, Page, read_block};
+use crate::postprocess::luminance;
+
+/// Decodes a byte stream compressed with the RATTA_RLE algorithm into one color code per pixel.
+///
+/// The stream is a sequence of `(color_code, length_code)` byte pairs:
+/// - `length_code == 0xff` is a long run of `0x4000` pixels.
+/// - `length_code` with the high bit set starts a multi-byte run. It is held until the next pair: if that pair
+///   has the same color, the run is `1 + next_length + (((length_code & 0x7f) + 1) << 7)` pixels; otherwise the
+///   held run is `((length_code & 0x7f) + 1) << 7` pixels and the next pair is decoded on its own, by these same
+///   rules.
+/// - Any other `length_code` is a run of `length_code + 1` pixels.
+///
+/// The result always has `width * height` entries. A long stream is truncated; a short one is padded with
+/// transparent (`0x62`) pixels, keeping its rows intact if it is exactly another device's page size.
+/// Use [`to_rgba`] to turn the color codes into pixels.
+pub fn decode_rle(compressed_data: &[u8], width: usize, height: usize) -> Result<Vec<u8>> {
+    decode_rle_reporting(compressed_data, width, height, &|_| {})
+}
+
+/// [`decode_rle`], passing `warn` a message when a short stream had to be padded at the end.
+pub(crate) fn decode_rle_reporting(compressed_data: &[u8], width: usize, height: usize, warn: &dyn Fn(&str)) -> Result<Vec<u8>> {
+    // Screen dimensions
+    let expected_len = width * height;
+    let mut decompressed = Vec::with_capacity(expected_len);
+
+    let mut i = 0; // Our position in the compressed_data slice
+    let mut holder: Option<(u8, u8)> = None; // State for multi-byte lengths
+
+    while i < compressed_data.len() {
+        // Ensure we can read a pair of bytes
+        if i + 1 >= compressed_data.len() {
+            break;
+        }
+        let color_code = compressed_data[i];
+        let length_code = compressed_data[i + 1];
+        i += 2; // Move to the next pair
+
+        let length: usize;
+
+        if let Some((prev_color_code, prev_length_code)) = holder.take() {
+            // We are in the "holder" state from the previous iteration.
+            if color_code == prev_color_code {
+                // The colors match, so combine the lengths.
+                length = 1 + length_code as usize + (((prev_length_code & 0x7f) as usize + 1) << 7);
+                decompressed.extend(std::iter::repeat_n(color_code, length));
+                continue;
+            }
+            // Colors don't match. First, process the held-over length.
+            let held_length = ((prev_length_code & 0x7f) as usize + 1) << 7;
+            decompressed.extend(std::iter::repeat_n(prev_color_code, held_length));
+            // Then fall through to decode the current pair like any other: it may be a long run, or start a
+            // multi-byte length of its own.
+        }
+
+        if length_code == 0xff {
+            // Special marker for a long run
+            length = 0x4000; // 16384
+        } else if length_code & 0x80 != 0 {
+            // Most significant bit is set. This is a multi-byte length marker.
+            // We store the current pair in the `holder` and continue to the next iteration.
+            holder = Some((color_code, length_code));
+            continue;
+        } else {
+            // Standard case: length is just length_code + 1.
+            length = length_code as usize + 1;
+        }
+
+        // Add the `color_code` to our output `length` times.
+        decompressed.extend(std::iter::repeat_n(color_code, length));
+    }
+
+    // After the loop, check if there's a final item in the holder.
+    // This can happen if the last block was a multi-byte marker.
+    if let Some((color_code, length_code)) = holder {
+        let remaining_len = expected_len.saturating_sub(decompressed.len());
+        // A simple heuristic for the tail length
+        let tail_length = std::cmp::min(((length_code & 0x7f) as usize + 1) << 7, remaining_len);
+        if tail_length > 0 {
+            decompressed.extend(std::iter::repeat_n(color_code, tail_length));
+        }
+    }
+
+    if decompressed.len() < expected_len {
+        return Ok(fit_short_bitmap(decompressed, width, height, warn));
+    }
+    decompressed.truncate(expected_len);
+
+    Ok(decompressed)
+}
+
+/// Pads a bitmap that decoded to fewer than `width * height` pixels with transparent (`0x62`) pixels.
+///
+/// Some files declare one device's page size but hold layers drawn at another's. If the pixel count is exactly a
+/// known device's page that fits in `width x height`, the rows are laid out at that width in the top-left corner,
+/// instead of being wrapped at the wrong width. Otherwise the data is padded at the end, and `warn` is told.
+fn fit_short_bitmap(decompressed: Vec<u8>, width: usize, height: usize, warn: &dyn Fn(&str)) -> Vec<u8> {
+    let expected_len = width * height;
+    let device = DEVICE_DIMENSIONS
+        .iter()
+        .find(|&&(w, h)| w * h == decompressed.len() && w <= width && h <= height);
+
+    match device {
+        Some(&(source_width, _)) => {
+            let mut fitted = vec![0x62; expected_len];
+            for (row, source_row) in fitted.chunks_exact_mut(width).zip(decompressed.chunks_exact(source_width)) {
+                row[..source_width].copy_from_slice(source_row);
+            }
+            fitted
+        }
+        None => {
+            warn(&format!(
+                "RLE bitmap decoded to {} pixels, expected {} ({}x{}); padding with transparent pixels.",
+                decompressed.len(),
+                expected_len,
+                width,
+                height
+            ));
+            let mut padded = decompressed;
+            padded.resize(expected_len, 0x62);
+            padded
+        }
+    }
+}
+
+/// Decodes a RATTA_RLE stream of 16-bit pixels into one color code per pixel.
+///
+/// Layers with a `LAYERDEPTH` of 16 are expected to hold `(color_code, length_code)` pairs of little-endian `u16`s,
+/// each a run of `length_code + 1` pixels; with 16-bit lengths there is no need for the multi-byte runs of
+/// [`decode_rle`]. This depth has not been seen in released firmware yet.
+///
+/// Like [`decode_rle`], the result always has `width * height` entries, padded with transparent (`0x62`) pixels.
+/// Use [`to_rgba_16bit`] to turn the color codes into pixels.
+pub fn decode_rle_16bit(compressed_data: &[u8], width: usize, height: usize) -> Result<Vec<u16>> {
+    decode_rle_16bit_reporting(compressed_data, width, height, &|_| {})
+}
+
+/// [`decode_rle_16bit`], passing `warn` a message when a short stream had to be padded.
+pub(crate) fn decode_rle_16bit_reporting(compressed_data: &[u8], width: usize, height: usize, warn: &dyn Fn(&str)) -> Result<Vec<u16>> {
+    let expected_len = width * height;
+    let mut decompressed = Vec::with_capacity(expected_len);
+
+    for pair in compressed_data.chunks_exact(4) {
+        let color_code = u16::from_le_bytes([pair[0], pair[1]]);
+        let length = u16::from_le_bytes([pair[2], pair[3]]) as usize + 1;
+        decompressed.extend(std::iter::repeat_n(color_code, length));
+        if decompressed.len() >= expected_len {
+            break;
+        }
+    }
+
+    if decompressed.len() < expected_len {
+        warn(&format!(
+            "16-bit RLE bitmap decoded to {} pixels, expected {} ({}x{}); padding with transparent pixels.",
+            decompressed.len(),
+            expected_len,
+            width,
+            height
+        ));
+    }
+    decompressed.resize(expected_len, 0x62);
+
+    Ok(decompressed)
+}
+
+/// Encodes one color code per pixel into a RATTA_RLE byte stream that [`decode_rle`] reads back unchanged.
+///
+/// Each run of identical bytes is emitted as `0xff` pairs for every full `0x4000` pixels, then either a
+/// single pair for up to 128 pixels or a held multi-byte pair followed by a same-color pair for longer runs.
+pub fn encode_rle(pixels: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+
+    for (color_code, run) in pixels.iter().dedup_with_count().map(|(count, &color_code)| (color_code, count)) {
+        let mut remaining = run;
+        while remaining >= 0x4000 {
+            encoded.extend([color_code, 0xff]);
+            remaining -= 0x4000;
+        }
+        if remaining > 128 {
+            // remaining = 1 + low + (high << 7), with 1 <= high <= 127, so the marker never collides with 0xff.
+            let high = (remaining - 1) >> 7;
+            let low = (remaining - 1) & 0x7f;
+            encoded.extend([color_code, 0x80 | (high - 1) as u8, color_code, low as u8]);
+        } else if remaining > 0 {
+            encoded.extend([color_code, (remaining - 1) as u8]);
+        }
+    }
+
+    encoded
+}
+
+/// Decompresses an LZMA-compressed layer bitmap.
+/// This protocol has not been seen in released firmware yet; it is expected to hold the same
+/// one-byte-per-pixel color codes as RATTA_RLE, so the decompressed size must be exactly `width * height` bytes.
+pub(crate) fn decode_lzma(compressed_data: &[u8], width: usize, height: usize) -> Result<Vec<u8>> {
+    let expected_len = width * height;
+    let mut decompressed = Vec::with_capacity(expected_len);
+    lzma_rs::lzma_decompress(&mut &compressed_data[..], &mut decompressed)
+        .map_err(|e| SupernoteError::Decode(format!("invalid LZMA data: {}", e)))?;
+
+    if decompressed.len() != expected_len {
+        return Err(SupernoteError::Decode(format!(
+            "LZMA output length {} != expected {}",
+            decompressed.len(),
+            expected_len
+        )));
+    }
+
+    Ok(decompressed)
+}
+
+/// Decompresses an LZ4-compressed layer bitmap.
+/// Unlike RATTA_RLE, whose block is just the compressed stream, an LZ4 block is expected to start with
+/// its own 4-byte little-endian *decompressed* length, followed by a raw LZ4 block (not the framed format).
+/// The payload is the same one-byte-per-pixel color codes, so the decompressed size must be `width * height` bytes.
+pub(crate) fn decode_lz4(compressed_data: &[u8], width: usize, height: usize) -> Result<Vec<u8>> {
+    let expected_len = width * height;
+    let decompressed =
+        lz4_flex::decompress_size_prepended(compressed_data).map_err(|e| SupernoteError::Decode(format!("invalid LZ4 data: {}", e)))?;
+
+    if decompressed.len() != expected_len {
+        return Err(SupernoteError::Decode(format!(
+            "LZ4 output length {} != expected {}",
+            decompressed.len(),
+            expected_len
+        )));
+    }
+
+    Ok(decompressed)
+}
+
+/// Builds a layer image from one-byte-per-pixel Supernote color codes.
+pub(crate) fn pixels_to_image(pixel_data: &[u8], width: usize, height: usize, color_map: &ColorMap) -> RgbaImage {
+    let mut layer_image = RgbaImage::new(width as u32, height as u32);
+    write_pixels(&mut layer_image, pixel_data, color_map);
+    layer_image
+}
+
+/// Builds a layer image from 16-bit color codes, as decoded by [`decode_rle_16bit`].
+/// Codes below `0x100` go through the color map like 8-bit codes; the rest are gray levels.
+pub(crate) fn pixels16_to_image(pixel_data: &[u16], width: usize, height: usize, color_map: &ColorMap) -> RgbaImage {
+    let mut layer_image = RgbaImage::new(width as u32, height as u32);
+    write_pixels16(&mut layer_image, pixel_data, color_map);
+    layer_image
+}
+
+/// Overwrites an image, row by row, with the pixels for one-byte-per-pixel color codes.
+/// The pixels are written straight into the image buffer in parallel, which keeps large pages memory-bound.
+pub(crate) fn write_pixels(image: &mut RgbaImage, pixel_data: &[u8], color_map: &ColorMap) {
+    image
+        .as_mut()
+        .par_chunks_mut(4)
+        .zip(pixel_data.par_iter())
+        .for_each(|(rgba, &pixel_byte)| rgba.copy_from_slice(&color_map.get(pixel_byte).0));
+}
+
+/// [`write_pixels`] for 16-bit color codes.
+pub(crate) fn write_pixels16(image: &mut RgbaImage, pixel_data: &[u16], color_map: &ColorMap) {
+    image
+        .as_mut()
+        .par_chunks_mut(4)
+        .zip(pixel_data.par_iter())
+        .for_each(|(rgba, &pixel)| rgba.copy_from_slice(&color_map.get_16bit(pixel).0));
+}
+
+/// A complete mapping from Supernote color codes to RGBA pixels.
+/// The default is the built-in mapping of [`to_rgba`]; individual codes can be overridden from a TOML file.
+#[derive(Debug, Clone, Hash)]
+pub struct ColorMap {
+    colors: [[u8; 4]; 256],
+}
+
+impl Default for ColorMap {
+    fn default() -> Self {
+        let mut colors = [[0u8; 4]; 256];
+        for (code, color) in colors.iter_mut().enumerate() {
+            *color = COLOR_LUT[code].0;
+        }
+        ColorMap { colors }
+    }
+}
+
+impl ColorMap {
+    /// The RGBA bytes of every color code, in code order.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.colors.as_flattened()
+    }
+
+    /// Returns the pixel for a color code.
+    pub fn get(&self, color_code: u8) -> Rgba<u8> {
+        Rgba(self.colors[color_code as usize])
+    }
+
+    /// Returns the pixel for a 16-bit color code: codes below `0x100` are looked up like 8-bit ones,
+    /// the rest are gray levels as in [`to_rgba_16bit`].
+    pub(crate) fn get_16bit(&self, pixel: u16) -> Rgba<u8> {
+        match u8::try_from(pixel) {
+            Ok(color_code) => self.get(color_code),
+            Err(_) => to_rgba_16bit(pixel),
+        }
+    }
+
+    /// Loads the default mapping patched with the overrides in a TOML file.
+    /// Each entry maps a hex color code to an `[R, G, B, A]` array, e.g. `0x61 = [0, 0, 128, 255]`.
+    pub fn from_toml_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| SupernoteError::InvalidColorMap(format!("could not read '{}': {}", path.display(), e)))?;
+        Self::from_toml_str(&text).map_err(|e| match e {
+            SupernoteError::InvalidColorMap(msg) => SupernoteError::InvalidColorMap(format!("'{}': {}", path.display(), msg)),
+            other => other,
+        })
+    }
+
+    /// Same as [`ColorMap::from_toml_file`], for TOML text that is already in memory.
+    pub fn from_toml_str(text: &str) -> Result<Self> {
+        let table: toml::Table = text.parse().map_err(|e| SupernoteError::InvalidColorMap(format!("{}", e)))?;
+        let mut color_map = ColorMap::default();
+
+        for (key, value) in table.iter() {
+            let hex = key.strip_prefix("0x").or_else(|| key.strip_prefix("0X")).unwrap_or(key);
+            let color_code = u8::from_str_radix(hex, 16)
+                .map_err(|_| SupernoteError::InvalidColorMap(format!("'{}' is not a color code between 0x00 and 0xff", key)))?;
+
+            let channels = value
+                .as_array()
+                .filter(|a| a.len() == 4)
+                .ok_or_else(|| SupernoteError::InvalidColorMap(format!("color for {} must be an array of 4 values [R, G, B, A]", key)))?;
+            let mut color = [0u8; 4];
+            for (channel, value) in color.iter_mut().zip(channels) {
+                *channel = value
+                    .as_integer()
+                    .and_then(|v| u8::try_from(v).ok())
+                    .ok_or_else(|| SupernoteError::InvalidColorMap(format!("color for {} must only contain integers between 0 and 255", key)))?;
+            }
+            color_map.colors[color_code as usize] = color;
+        }
+
+        Ok(color_map)
+    }
+}
+
+/// Maps a Supernote color code to an RGBA pixel.
+///
+/// `0x61` is black, `0x65` white and `0x62` transparent; `0x63`/`0x64` (and their `0x9d`/`0x9e`, `0xc9`/`0xca`
+/// aliases) are the dark gray and gray pens. Any other byte is an anti-aliasing pixel whose value is its gray level.
+pub fn to_rgba(pixel_byte: u8) -> Rgba<u8> {
+    COLOR_LUT[pixel_byte as usize]
+}
+
+/// Maps a 16-bit Supernote color code to an RGBA pixel.
+///
+/// Codes below `0x100` are the 8-bit color codes of [`to_rgba`], so black, white, transparent and the gray pens keep
+/// their meaning. Any higher code is a 16-bit gray level, reduced to 8 bits by keeping its high byte.
+pub fn to_rgba_16bit(pixel: u16) -> Rgba<u8> {
+    match u8::try_from(pixel) {
+        Ok(color_code) => to_rgba(color_code),
+        Err(_) => {
+            let level = (pixel >> 8) as u8;
+            Rgba([level, level, level, 255])
+        }
+    }
+}
+
+/// [`to_rgba`] for every possible color code, computed at compile time so the pixel loop is a single array lookup.
+const COLOR_LUT: [Rgba<u8>; 256] = build_color_lut();
+
+const fn build_color_lut() -> [Rgba<u8>; 256] {
+    let mut lut = [Rgba([0, 0, 0, 0]); 256];
+    let mut code = 0;
+    while code < 256 {
+        lut[code] = color_for_code(code as u8);
+        code += 1;
+    }
+    lut
+}
+
+const fn color_for_code(pixel_byte: u8) -> Rgba<u8> {
+    match pixel_byte {
+        // --- Core Colors ---
+        0x61 => Rgba([0, 0, 0, 255]),       // Black
+        0x65 => Rgba([255, 255, 255, 255]), // White
+        0x62 => Rgba([0, 0, 0, 0]),         // Transparent (used for background layer)
+
+        // --- Grays (and their aliases/compat codes) ---
+        // Dark Gray
+        0x63 | 0x9d | 0x9e => Rgba([0x9d, 0x9d, 0x9d, 255]),
+        // Gray
+        0x64 | 0xc9 | 0xca => Rgba([0xc9, 0xc9, 0xc9, 255]),
+
+        // --- Handle all other bytes as anti-aliasing pixels ---
+        _ => {
+            // The byte value itself represents the grayscale intensity.
+            // This renders the smooth edges of handwritten strokes.
+            // this encoding is from the newer note format.
+            Rgba([pixel_byte, pixel_byte, pixel_byte, 255])
+        }
+    }
+}
+
+/// Reads a layer's bitmap block from the `.note` file it was parsed from and decompresses it, without mapping
+/// pixels to colors or compositing it with other layers.
+///
+/// The result has one byte per pixel, row by row, `width * height` in all. For RATTA_RLE, LZMA and LZ4 layers
+/// those are color codes, as returned by [`decode_rle`]; pass them to [`to_rgba`] for pixels. PNG layers store
+/// pixels rather than color codes, so for them each byte is the pixel's brightness on white paper (0 is black),
+/// and pixels outside the stored image are 255.
+///
+/// Layers without a bitmap (`bitmap_address == 0`) decode to a fully transparent page. Layers with a
+/// `LAYERDEPTH` of 16 need more than a byte per pixel and fail with [`SupernoteError::UnsupportedProtocol`];
+/// read their block and use [`decode_rle_16bit`] instead.
+pub fn parse_bitmap_block(file: &mut File, layer: &Layer, width: usize, height: usize) -> Result<Vec<u8>> {
+    if layer.bitmap_address == 0 {
+        return Ok(vec![0x62; width * height]);
+    }
+    if layer.protocol == "RATTA_RLE" && layer.depth == 16 {
+        return Err(SupernoteError::UnsupportedProtocol(format!("{} with LAYERDEPTH 16", layer.protocol)));
+    }
+    let data = read_block(file, layer.bitmap_address)?;
+    match layer.protocol.as_str() {
+        "RATTA_RLE" => decode_rle(&data, width, height),
+        "LZMA" => decode_lzma(&data, width, height),
+        "LZ4" => decode_lz4(&data, width, height),
+        "PNG" => {
+            let image = image::load_from_memory(&data)
+                .map_err(|e| SupernoteError::Decode(format!("invalid PNG data: {}", e)))?
+                .to_rgba8();
+            let mut brightness = vec![255; width * height];
+            for (x, y, &p) in image.enumerate_pixels() {
+                let (x, y) = (x as usize, y as usize);
+                if x < width && y < height {
+                    // Blend over white by the pixel's opacity.
+                    let alpha = p.0[3] as u32;
+                    brightness[y * width + x] = ((luminance(p) as u32 * alpha + 255 * (255 - alpha)) / 255) as u8;
+                }
+            }
+            Ok(brightness)
+        }
+        other => Err(SupernoteError::UnsupportedProtocol(other.to_string())),
+    }
+}
+
+/// Cheaply checks whether a layer of `page` carries any ink, without decoding its bitmap.
+/// The background layer of a page on one of the device's own templates only holds that template, so it never counts as
+/// ink; on a custom template or an imported image, it is inspected like any other layer.
+/// For RATTA_RLE data, the color code of every pair is inspected: only transparent (0x62) and white (0x65) runs mean blank.
+/// Other protocols can't be inspected without decoding, so they are conservatively treated as not blank.
+pub(crate) fn is_blank_layer(page: &Page, layer: &Layer, data: &[u8]) -> bool {
+    if layer.key == "BGLAYER" && !page.has_custom_template() {
+        return true;
+    }
+    match layer.protocol.as_str() {
+        "RATTA_RLE" if layer.depth == 16 => data
+            .chunks_exact(4)
+            .all(|pair| matches!(u16::from_le_bytes([pair[0], pair[1]]), 0x62 | 0x65)),
+        "RATTA_RLE" => data.chunks_exact(2).all(|pair| matches!(pair[0], 0x62 | 0x65)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::cell::RefCell;
+
+    /// Pixels made of runs of the given `(color_code, length)`.
+    fn runs(runs: &[(u8, usize)]) -> Vec<u8> {
+        runs.iter()
+            .flat_map(|&(color_code, length)| std::iter::repeat_n(color_code, length))
+            .collect()
+    }
+
+    #[test]
+    fn encode_rle_roundtrips() {
+        let pixels = runs(&[
+            (0x61, 1),
+            (0x62, 0x4000),
+            (0x61, 0x4001),
+            (0x63, 1),
+            (0x64, 128),
+            (0x65, 129),
+            (0x61, 0x3fff),
+            (0x62, 2 * 0x4000 + 300),
+            (0x9d, 1),
+        ]);
+        let encoded = encode_rle(&pixels);
+        assert_eq!(decode_rle(&encoded, pixels.len(), 1).unwrap(), pixels);
+    }
+
+    /// Run lengths around the encoding's boundaries: one pair up to 128, a held pair above, `0xff` pairs per 0x4000.
+    fn run_length() -> impl Strategy<Value = usize> {
+        prop_oneof![
+            1..=300usize,
+            prop::sample::select(vec![127, 128, 129, 0x3fff, 0x4000, 0x4001, 0x4000 + 128, 0x4000 + 129, 2 * 0x4000]),
+            1..=3 * 0x4000usize,
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn encode_rle_roundtrips_any_pixels(
+            width in 1..=1920usize,
+            height in 1..=24usize,
+            runs in prop::collection::vec((prop::sample::select(vec![0x61u8, 0x62, 0x63, 0x64, 0x65, 0x9d, 0x9e, 0xc9, 0xca, 0xff]), run_length()), 1..8),
+        ) {
+            let pixels = runs
+                .iter()
+                .flat_map(|&(color_code, length)| std::iter::repeat_n(color_code, length))
+                .cycle()
+                .take(width * height)
+                .collect::<Vec<_>>();
+            let encoded = encode_rle(&pixels);
+            prop_assert_eq!(decode_rle(&encoded, width, height).unwrap(), pixels);
+        }
+    }
+
+    #[test]
+    fn short_bitmaps_of_another_devices_size_keep_their_rows() {
+        let warnings = RefCell::new(Vec::new());
+        let warn = |message: &str| warnings.borrow_mut().push(message.to_string());
+        let (a5x_width, a5x_height) = DEVICE_DIMENSIONS[0];
+        let (a5x2_width, a5x2_height) = DEVICE_DIMENSIONS[1];
+        // Every A5X row starts with one ink pixel.
+        let a5x_page = (0..a5x_width * a5x_height)
+            .map(|i| if i % a5x_width == 0 { 0x61 } else { 0x62 })
+            .collect_vec();
+
+        let fitted = fit_short_bitmap(a5x_page, a5x2_width, a5x2_height, &warn);
+        assert_eq!(fitted.len(), a5x2_width * a5x2_height);
+        let ink = fitted.iter().positions(|&code| code == 0x61).collect_vec();
+        assert_eq!(ink, (0..a5x_height).map(|row| row * a5x2_width).collect_vec());
+        assert!(warnings.borrow().is_empty());
+
+        // Any other short length is padded at the end, with a warning.
+        let padded = fit_short_bitmap(vec![0x61; 1000], 40, 30, &warn);
+        assert_eq!(padded, runs(&[(0x61, 1000), (0x62, 200)]));
+        assert_eq!(
+            *warnings.borrow(),
+            ["RLE bitmap decoded to 1000 pixels, expected 1200 (40x30); padding with transparent pixels."]
+        );
+    }
+
+    /// The `(color_code, length)` runs of decoding `data` into exactly `len` pixels.
+    fn decoded_runs(data: &[u8], len: usize) -> Vec<(u8, usize)> {
+        let pixels = decode_rle(data, len, 1).unwrap();
+        pixels.iter().dedup_with_count().map(|(count, &color_code)| (color_code, count)).collect()
+    }
+
+    #[test]
+    fn held_run_is_emitted_before_a_pair_of_another_color() {
+        // A held 128-pixel run, then a plain 3-pixel run of another color.
+        assert_eq!(decoded_runs(&[0x61, 0x80, 0x62, 0x02], 131), [(0x61, 128), (0x62, 3)]);
+        // The pair after the held run is a 0x4000-pixel run of its own.
+        assert_eq!(decoded_runs(&[0x61, 0x80, 0x62, 0xff], 128 + 0x4000), [(0x61, 128), (0x62, 0x4000)]);
+        // The pair after the held run starts a multi-byte run of its own: 1 + 5 + (2 << 7) pixels.
+        assert_eq!(decoded_runs(&[0x61, 0x80, 0x62, 0x81, 0x62, 0x05], 128 + 262), [(0x61, 128), (0x62, 262)]);
+    }
+
+    fn rle_layer(key: &str) -> Layer {
+        Layer {
+            key: key.to_string(),
+            protocol: "RATTA_RLE".to_string(),
+            ..Layer::default()
+        }
+    }
+
+    #[test]
+    fn layers_without_ink_are_blank() {
+        let (width, height) = (1404, 1872);
+        let page = Page::default();
+        let mut pixels = vec![0x62; width * height];
+        assert!(is_blank_layer(&page, &rle_layer("MAINLAYER"), &encode_rle(&pixels)));
+        pixels[..width].fill(0x65);
+        assert!(is_blank_layer(&page, &rle_layer("MAINLAYER"), &encode_rle(&pixels)));
+
+        pixels[height / 2 * width + width / 2] = 0x61;
+        assert!(!is_blank_layer(&page, &rle_layer("MAINLAYER"), &encode_rle(&pixels)));
+    }
+
+    #[test]
+    fn background_layers_are_blank_unless_the_template_is_custom() {
+        let pixels = runs(&[(0x62, 1000), (0x9d, 1), (0x62, 1000)]);
+        let ruled = Page {
+            template: Some("style_8mm_ruled_line".to_string()),
+            ..Page::default()
+        };
+        let imported = Page {
+            template: Some("user_photo_1".to_string()),
+            ..Page::default()
+        };
+        assert!(is_blank_layer(&Page::default(), &rle_layer("BGLAYER"), &encode_rle(&pixels)));
+        assert!(is_blank_layer(&ruled, &rle_layer("BGLAYER"), &encode_rle(&pixels)));
+        assert!(!is_blank_layer(&imported, &rle_layer("BGLAYER"), &encode_rle(&pixels)));
+        assert!(is_blank_layer(&imported, &rle_layer("BGLAYER"), &encode_rle(&runs(&[(0x62, 2001)]))));
+    }
+}
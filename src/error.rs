@@ -0,0 +1,125 @@
+use image::ImageError;
+use std::fmt;
+use std::io;
+
+/// Errors returned by the library, so callers can tell a missing file from a malformed or unsupported one.
+#[derive(Debug)]
+pub enum SupernoteError {
+    /// Reading the `.note` file or writing the output failed.
+    IoError(io::Error),
+    /// The file was written by a device or format version this crate can't convert.
+    UnsupportedDevice(String),
+    /// The file structure (signature, block lengths, metadata) is corrupt.
+    MalformedMetadata(String),
+    /// A layer bitmap on a page could not be decoded.
+    MalformedBitmap { page: usize, layer: String, msg: String },
+    /// A layer uses a compression protocol this crate doesn't know.
+    UnsupportedProtocol(String),
+    /// Bitmap data could not be decompressed. The renderer turns this into [`SupernoteError::MalformedBitmap`]
+    /// once it knows which page and layer the data belongs to.
+    Decode(String),
+    /// A user-supplied color map file is invalid.
+    InvalidColorMap(String),
+    /// A page (1-based) was asked for that the notebook doesn't have.
+    PageNotFound { page: usize, page_count: usize },
+    /// Another error that happened while reading or rendering a specific page (1-based) and, if known, layer.
+    InPage {
+        page: usize,
+        layer: Option<String>,
+        source: Box<SupernoteError>,
+    },
+    /// Several pages failed; each error carries its own page and layer.
+    Multiple(Vec<SupernoteError>),
+}
+
+pub type Result<T> = std::result::Result<T, SupernoteError>;
+
+impl SupernoteError {
+    /// Attaches the page (1-based) being read or rendered to an error.
+    pub(crate) fn in_page(self, page: usize) -> Self {
+        match self {
+            SupernoteError::MalformedBitmap { .. } | SupernoteError::InPage { .. } | SupernoteError::Multiple(_) => self,
+            other => SupernoteError::InPage {
+                page,
+                layer: None,
+                source: Box::new(other),
+            },
+        }
+    }
+
+    /// Attaches the page (1-based) and layer being read or rendered to an error.
+    /// Decoding errors become [`SupernoteError::MalformedBitmap`]; anything else is wrapped in [`SupernoteError::InPage`].
+    pub(crate) fn in_layer(self, page: usize, layer: &str) -> Self {
+        match self {
+            SupernoteError::Decode(msg) => SupernoteError::MalformedBitmap {
+                page,
+                layer: layer.to_string(),
+                msg,
+            },
+            SupernoteError::MalformedBitmap { .. } | SupernoteError::InPage { .. } | SupernoteError::Multiple(_) => self,
+            other => SupernoteError::InPage {
+                page,
+                layer: Some(layer.to_string()),
+                source: Box::new(other),
+            },
+        }
+    }
+}
+
+impl fmt::Display for SupernoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SupernoteError::IoError(e) => write!(f, "{}", e),
+            SupernoteError::UnsupportedDevice(msg) => write!(f, "unsupported device: {}", msg),
+            SupernoteError::MalformedMetadata(msg) => write!(f, "malformed metadata: {}", msg),
+            SupernoteError::MalformedBitmap { page, layer, msg } => write!(f, "page {}, layer {}: {}", page, layer, msg),
+            SupernoteError::UnsupportedProtocol(protocol) => write!(f, "unsupported layer protocol '{}'", protocol),
+            SupernoteError::Decode(msg) => write!(f, "{}", msg),
+            SupernoteError::InvalidColorMap(msg) => write!(f, "invalid color map: {}", msg),
+            SupernoteError::PageNotFound { page, page_count } => {
+                write!(f, "there is no page {}; the notebook has {} pages", page, page_count)
+            }
+            SupernoteError::InPage {
+                page,
+                layer: Some(layer),
+                source,
+            } => write!(f, "page {}, layer {}: {}", page, layer, source),
+            SupernoteError::InPage { page, layer: None, source } => write!(f, "page {}: {}", page, source),
+            SupernoteError::Multiple(errors) => {
+                write!(f, "{} pages failed:", errors.len())?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// `Display` already prints the wrapped error's message, so `source` skips to whatever is below it.
+// Otherwise reporters that walk the chain (like anyhow) would print the same message twice.
+impl std::error::Error for SupernoteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SupernoteError::IoError(e) => e.source(),
+            SupernoteError::InPage { source, .. } => source.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SupernoteError {
+    fn from(e: io::Error) -> Self {
+        SupernoteError::IoError(e)
+    }
+}
+
+/// Writing an image file failed; encoder errors count as I/O errors too.
+impl From<ImageError> for SupernoteError {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::IoError(e) => SupernoteError::IoError(e),
+            other => SupernoteError::IoError(io::Error::other(other)),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use ab_glyph::{Font, FontRef};
+use std::sync::LazyLock;
+use subsetter::GlyphRemapper;
+
+use crate::cover::FONT_DATA;
+
+/// The characters of the embedded text font: printable ASCII.
+const TEXT_FONT_CHARS: std::ops::RangeInclusive<char> = ' '..='~';
+
+/// A subset of the bundled DejaVu Sans for embedding in PDFs, ready to be written as a CID-keyed TrueType font.
+/// Its glyph ids are also its CIDs: glyph 0 is `.notdef`, and glyphs 1, 2, ... are [`TextFont::chars`].
+pub(crate) struct TextFont {
+    /// The subsetted TrueType font file.
+    pub data: Vec<u8>,
+    /// The character each glyph after `.notdef` draws.
+    pub chars: Vec<char>,
+    /// Advance width of each glyph after `.notdef`, in thousandths of an em.
+    pub widths: Vec<i32>,
+    /// Union of the glyphs' bounding boxes, in thousandths of an em.
+    pub bbox: [i32; 4],
+    pub ascent: i32,
+    pub descent: i32,
+    pub cap_height: i32,
+}
+
+/// Fonts in a PDF are named with a six-letter tag when they are a subset.
+pub(crate) const TEXT_FONT_NAME: &str = "SNPDFA+DejaVuSans";
+
+/// The text font, subsetted the first time a PDF embeds it.
+pub(crate) static TEXT_FONT: LazyLock<TextFont> = LazyLock::new(|| {
+    let font = FontRef::try_from_slice(FONT_DATA).expect("the bundled font is a valid TrueType font");
+    let scale = 1000.0 / font.units_per_em().expect("the bundled font has a unit size");
+    let to_em = |units: f32| (units * scale).round() as i32;
+
+    let mut remapper = GlyphRemapper::new();
+    let mut widths = Vec::new();
+    let mut bbox = [0, 0, 0, 0];
+    for c in TEXT_FONT_CHARS {
+        let glyph = font.glyph_id(c);
+        remapper.remap(glyph.0);
+        widths.push(to_em(font.h_advance_unscaled(glyph)));
+        if let Some([x_min, y_min, x_max, y_max]) = glyph_bounds(&font, c) {
+            bbox = [
+                bbox[0].min(to_em(x_min)),
+                bbox[1].min(to_em(y_min)),
+                bbox[2].max(to_em(x_max)),
+                bbox[3].max(to_em(y_max)),
+            ];
+        }
+    }
+    let cap_height = glyph_bounds(&font, 'H').map_or(0, |[_, _, _, y_max]| to_em(y_max));
+    TextFont {
+        data: subsetter::subset(FONT_DATA, 0, &remapper).expect("the bundled font can be subsetted"),
+        chars: TEXT_FONT_CHARS.collect(),
+        widths,
+        bbox,
+        ascent: to_em(font.ascent_unscaled()),
+        descent: to_em(font.descent_unscaled()),
+        cap_height,
+    }
+});
+
+/// The bounding box of a character's glyph in font units, y up, or `None` if it draws nothing (like a space).
+fn glyph_bounds(font: &FontRef, c: char) -> Option<[f32; 4]> {
+    // ab_glyph's bounds are flipped for drawing top-down: `min.y` is the top of the glyph.
+    let bounds = font.outline(font.glyph_id(c))?.bounds;
+    Some([bounds.min.x, bounds.min.y.min(bounds.max.y), bounds.max.x, bounds.min.y.max(bounds.max.y)])
+}
@@ -0,0 +1,42 @@
+use image::RgbaImage;
+use std::fmt;
+
+use crate::error::SupernoteError;
+
+/// Callbacks from inside a conversion, set through [`ConvertOptions::hook`], for progress UI, live previews or
+/// collecting errors without changing the pipeline.
+///
+/// Pages are rendered in parallel, so the callbacks for different pages can come from different threads and in any
+/// order. Every method does nothing by default.
+///
+/// [`ConvertOptions::hook`]: crate::ConvertOptions::hook
+pub trait ConversionHook: fmt::Debug + Send + Sync {
+    /// A page is about to be rendered. `page_idx` is its 0-based index in the notebook, and `total` the number of
+    /// pages in the notebook.
+    fn on_page_start(&self, page_idx: usize, total: usize) {
+        let _ = (page_idx, total);
+    }
+
+    /// A page has been composited and post-processed into `image`, the picture that goes into the PDF. Not called
+    /// for blank pages left out by [`ConvertOptions::skip_blank_pages`].
+    ///
+    /// [`ConvertOptions::skip_blank_pages`]: crate::ConvertOptions::skip_blank_pages
+    fn on_page_done(&self, page_idx: usize, image: &RgbaImage) {
+        let _ = (page_idx, image);
+    }
+
+    /// A page failed to render with `error`, which the conversion then returns (or, with
+    /// [`ConvertOptions::all_errors`], collects).
+    ///
+    /// [`ConvertOptions::all_errors`]: crate::ConvertOptions::all_errors
+    fn on_error(&self, page_idx: usize, error: &SupernoteError) {
+        let _ = (page_idx, error);
+    }
+
+    /// Something was off in the notebook or the options, but the conversion carries on: a layer bitmap that decodes
+    /// to too few pixels, metadata that isn't valid UTF-8, an unrecognized signature, a page cache that can't be used.
+    /// `message` says what happened and, where it applies, on which page and layer. The command-line tool prints it.
+    fn on_warning(&self, message: &str) {
+        let _ = message;
+    }
+}
@@ -0,0 +1,42 @@
+//! Parsing and conversion of Supernote `.note` files.
+//!
+//! A `.note` file is a sequence of length-prefixed blocks. The last 4 bytes of the file point at a footer
+//! metadata block, which in turn points at the header (`FILE_FEATURE`) and at each page's metadata block.
+//! Each page lists its layers, and each layer points at a compressed bitmap block.
+//!
+//! [`convert_note_to_pdf`] does the whole conversion; the lower-level pieces ([`parse_metadata_block`],
+//! [`parse_bitmap_block`], [`decode_rle`], [`to_rgba`], ...) are exposed for building custom rendering pipelines.
+
+mod cache;
+#[cfg(feature = "fonts")]
+mod cover;
+mod decode;
+mod error;
+#[cfg(feature = "fonts")]
+mod font;
+mod hook;
+mod notebook;
+mod pdf;
+pub mod pipeline;
+mod postprocess;
+#[cfg(feature = "python")]
+mod python;
+mod renderer;
+mod repair;
+mod sidecar;
+mod verify;
+
+pub use decode::{ColorMap, decode_rle, decode_rle_16bit, encode_rle, parse_bitmap_block, to_rgba, to_rgba_16bit};
+pub use error::{Result, SupernoteError};
+pub use hook::ConversionHook;
+pub use notebook::{
+    FileHeader, KNOWN_GOOD_SIGNATURES, KNOWN_SIGNATURES, Layer, Notebook, Page, bitmap_block_size, parse_metadata_block, parse_notebook,
+    read_signature,
+};
+pub use pdf::{
+    ConversionSummary, ConvertOptions, CropMargin, ImageEncoding, MergeManifest, MergeSource, PageCompression, PageFormat, PageLabelStyle,
+    ViewerPreferences, compare_notes_to_pdf, convert_note_to_pdf, detect_optimal_encoding, measure_compression, merge_notes_to_pdf,
+};
+pub use renderer::{DefaultLayerRenderer, LayerRenderer};
+pub use repair::{RepairSummary, repair_pdf};
+pub use verify::{VerifyWarning, verify_pdf};
@@ -1,473 +1,1243 @@
-use anyhow::{Result, bail};
-use clap::Parser;
-use flate2::Compression;
-use flate2::write::ZlibEncoder;
-use image::{Rgba, RgbaImage, imageops};
-use indicatif::ProgressBar;
+use anyhow::{Context, Result, bail};
+use clap::{CommandFactory, Parser, ValueEnum};
+use clap_complete::Shell;
+use clap_mangen::roff::{Roff, bold, roman};
+use filetime::FileTime;
+use ignore::Match;
+use ignore::gitignore::Gitignore;
+use image::Rgba;
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use itertools::Itertools;
-use lazy_static::lazy_static;
 use rayon::prelude::*;
-use regex::Regex;
-use std::collections::HashMap;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{BufWriter, ErrorKind, IsTerminal, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use supernote_pdf::{
+    ColorMap, ConversionHook, ConversionSummary, ConvertOptions, CropMargin, ImageEncoding, KNOWN_GOOD_SIGNATURES, Notebook, PageFormat,
+    PageLabelStyle, SupernoteError, ViewerPreferences, bitmap_block_size, compare_notes_to_pdf, convert_note_to_pdf, measure_compression,
+    merge_notes_to_pdf, parse_notebook, read_signature, repair_pdf, verify_pdf,
+};
 use walkdir::WalkDir;
 
+mod s3;
+mod server;
+
+use s3::{S3Output, is_s3_url};
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// Input file (.note) or directory containing .note files
-    #[arg(short, long)]
-    input: PathBuf,
+    /// Input file (.note) or directory containing .note files. May be given multiple times.
+    #[arg(short, long, required_unless_present_any = ["layer_info", "device_dump", "generate_completion", "generate_man", "compare", "merge", "serve", "repair"])]
+    input: Vec<PathBuf>,
+
+    /// Output file (.pdf) or directory. Must be a directory when multiple inputs are given.
+    #[arg(short, long, required_unless_present_any = ["layer_info", "device_dump", "generate_completion", "generate_man", "serve", "compress_only"])]
+    output: Option<PathBuf>,
+
+    /// Omit pages that contain no ink from the output PDF. The device's own templates (lined, grid, ...) don't count as
+    /// ink; custom templates and imported images do
+    #[arg(long)]
+    skip_blank_pages: bool,
+
+    /// TOML file overriding the colors used for Supernote color codes, e.g. `0x61 = [0, 0, 128, 255]`
+    #[arg(long, value_name = "FILE")]
+    color_map: Option<PathBuf>,
+
+    /// Fail on files with an unrecognized signature instead of warning and converting anyway
+    #[arg(long)]
+    strict: bool,
+
+    /// Only convert pages that were starred on the device
+    #[arg(long, conflicts_with = "exclude_starred")]
+    starred_only: bool,
+
+    /// Leave out pages that were starred on the device
+    #[arg(long)]
+    exclude_starred: bool,
+
+    /// Leave out these pages, e.g. "1,5-7,10". The remaining pages are numbered from 1 in the PDF
+    #[arg(long, value_name = "PAGES", value_parser = parse_page_set)]
+    exclude_pages: Option<BTreeSet<usize>>,
+
+    /// Also write a JSON file with the notebook's metadata next to each PDF
+    #[arg(long)]
+    sidecar: bool,
+
+    /// Embed a font in every page, ready for a text layer (such as OCR output) to be added later. Nothing is
+    /// drawn with it yet
+    #[arg(long)]
+    embed_fonts: bool,
+
+    /// Flate-compress the page content streams and XMP metadata too, not just the images, for the smallest files
+    #[arg(long)]
+    compress_metadata: bool,
+
+    /// Also save a PNG thumbnail of each page next to each PDF, named `<stem>_thumb_001.png`, ...
+    #[arg(long)]
+    extract_thumbnails: bool,
+
+    /// Width of the thumbnails in pixels; the height keeps each page's proportions
+    #[arg(long, value_name = "PIXELS", default_value_t = 300, value_parser = clap::value_parser!(u32).range(1..), requires = "extract_thumbnails")]
+    thumbnail_size: u32,
+
+    /// Keep each layer as a separate, toggleable PDF layer (optional content group) instead of flattening pages
+    #[arg(long)]
+    pdf_layers: bool,
+
+    /// Draw only these layers, bottom to top, instead of each page's own layer order (LAYERSEQ), e.g.
+    /// "MAINLAYER,LAYER1" to leave out the background template. Keys are listed by --layer-info
+    #[arg(long, value_name = "LAYERS", value_delimiter = ',')]
+    layer_order: Option<Vec<String>>,
+
+    /// Render every stroke pure black on pure white, without gray levels or anti-aliasing, for maximum legibility
+    #[arg(long)]
+    high_contrast: bool,
+
+    /// Draw light ink on dark paper; colorful photo regions keep their original colors
+    #[arg(long)]
+    invert: bool,
+
+    /// Draw ink in this color instead of black, as hex RRGGBB (e.g. `#1a237e`)
+    #[arg(long, value_name = "COLOR", value_parser = parse_hex_color)]
+    ink_tint: Option<Rgba<u8>>,
+
+    /// Paper color, as hex RRGGBB (e.g. `#fffff0` for cream) or `transparent` to leave the paper out
+    #[arg(long, value_name = "COLOR", value_parser = parse_bg_color)]
+    bg_color: Option<Rgba<u8>>,
+
+    /// Resample page images by this factor (e.g. 1.5), keeping the page size: below 1 is smaller, above 1 is sharper
+    #[arg(long, value_name = "FACTOR", value_parser = parse_scale)]
+    scale: Option<f32>,
+
+    /// Write one CSV row per converted file (pages, dimensions, device, timing, size, errors) to this file
+    #[arg(long, value_name = "FILE")]
+    stats_output: Option<PathBuf>,
+
+    /// After converting, print the files, pages, output size and mean time per file for each kind of device the
+    /// notebooks were written on
+    #[arg(long)]
+    stats: bool,
+
+    /// Don't convert anything; list the PDFs that would be written, with each one's page count, page size and
+    /// estimated file size
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Size of a PDF as a fraction of its pages' uncompressed RGB pixels, for the --dry-run estimates. Pages that are
+    /// mostly blank paper compress far better than this; photos and JPEG pages compress worse
+    #[arg(long, value_name = "RATIO", default_value_t = 0.15, value_parser = parse_scale, requires = "dry_run")]
+    estimated_compression_ratio: f32,
+
+    /// Split each PDF into numbered parts (`name_001.pdf`, ...) no larger than this, e.g. `10MB` (units: B, KB, MB, GB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_output_size: Option<u64>,
+
+    /// Split each PDF into numbered parts (`name_001.pdf`, ...) of at most this many pages
+    #[arg(long, value_name = "N")]
+    pages_per_file: Option<NonZeroUsize>,
+
+    /// Split each PDF into one per chapter (`name_chapter_01_<title>.pdf`, ...); a titled page after an untitled one starts a chapter
+    #[arg(long, conflicts_with_all = ["max_output_size", "pages_per_file", "cover_page"])]
+    auto_split_pdf: bool,
+
+    /// Crop each page to the area with ink on it
+    #[arg(long)]
+    crop_to_ink: bool,
+
+    /// Pixels of space to keep around the ink with --crop-to-ink [default: 50]
+    #[arg(long, value_name = "N", requires = "crop_to_ink", conflicts_with = "crop_margin_pct")]
+    crop_margin: Option<u32>,
+
+    /// Space to keep around the ink with --crop-to-ink, as a percentage of the page size (e.g. `5%`)
+    #[arg(long, value_name = "PERCENT", requires = "crop_to_ink", value_parser = parse_percent)]
+    crop_margin_pct: Option<f32>,
+
+    /// Pixels per inch used to size PDF pages [default: the device's screen density, 226 for A5X, 300 for A5X2]
+    #[arg(long, value_name = "DPI", value_parser = parse_scale)]
+    dpi: Option<f32>,
+
+    /// Cache rendered pages as PNG files in this directory (e.g. `~/.cache/supernote_pdf`) to speed up re-runs
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// Skip inputs whose contents (SHA-256) match a notebook converted before; hashes are kept in `hashes.db`
+    /// in --cache-dir, or `~/.cache/supernote_pdf`
+    #[arg(long)]
+    dedup_check: bool,
+
+    /// Keep rendering after a page fails and report every failing page, not just the first
+    #[arg(long)]
+    all_errors: bool,
+
+    /// Retry a conversion up to N times when reading fails with a transient I/O error, e.g. on a network share
+    /// that is still syncing
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retry: u32,
+
+    /// Milliseconds to wait before the first retry; the wait doubles after each attempt
+    #[arg(long, value_name = "MS", default_value_t = 500, requires = "retry")]
+    retry_delay: u64,
+
+    /// Before converting each file, print whether its format version is known to convert correctly
+    #[arg(long)]
+    version_check: bool,
+
+    /// After writing each PDF, read it back and warn about structural problems: objects not where the
+    /// cross-reference table says, a wrong trailer /Size, pages without a /Pages parent, or wrong stream lengths
+    #[arg(long)]
+    verify: bool,
+
+    /// Give each output PDF the modification time of its .note file instead of the conversion time, so backup
+    /// tools that compare mtimes don't see re-converted PDFs as changed
+    #[arg(long)]
+    preserve_timestamps: bool,
+
+    /// Follow symbolic links when scanning an input directory. Links that loop back to a parent directory are skipped
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Only look this many directories deep when scanning an input directory; 1 means just its own files
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// How PDFs converted from an input directory are laid out in the output directory
+    #[arg(long, visible_alias = "output-dir-structure", value_enum, default_value_t = DirStructure::Mirror)]
+    dir_structure: DirStructure,
+
+    /// What to do when an output PDF already exists
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t = OnConflict::Error)]
+    on_conflict: OnConflict,
+
+    /// Skip notebooks whose PDF already exists; the same as --on-conflict skip
+    #[arg(long, conflicts_with = "on_conflict")]
+    skip_if_exists: bool,
+
+    /// Convert every .note file in an input directory, even those matched by a .supernote_pdf_ignore file
+    #[arg(long)]
+    no_ignore: bool,
+
+    /// Rotate specific pages clockwise, e.g. "1:90,3:180,5-7:270". Angles are 90, 180 or 270
+    #[arg(long, value_name = "PAGES:DEGREES", value_parser = parse_page_rotations)]
+    rotate_page: Option<HashMap<usize, u32>>,
+
+    /// How page images are compressed: flate (lossless), jpeg (for photos), ccitt (1-bit, for black-and-white ink),
+    /// or auto to pick one per page from its content
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = PageFormatArg::Flate)]
+    page_format: PageFormatArg,
+
+    /// zlib level of flate-compressed page images, from 0 (stored uncompressed) through 1 (fastest, largest) to 9
+    /// (slowest, smallest). Lower levels speed up large batches; 9 suits archiving [default: 6]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..=9))]
+    zlib_level: Option<u8>,
+
+    /// Append blank pages so each PDF has at least N pages, e.g. for printing on a fixed number of sheets
+    #[arg(long, value_name = "N")]
+    pad_to_count: Option<usize>,
+
+    /// Start each PDF with a title page showing the notebook's title, device, page count and creation date
+    #[arg(long)]
+    cover_page: bool,
+
+    /// Don't write a PDF; instead report how well each page of the input files compresses with
+    /// --compression-method and --compression-level, for tuning
+    #[arg(long, conflicts_with_all = ["serve", "compare"])]
+    compress_only: bool,
+
+    /// Method whose compression --compress-only measures
+    #[arg(long, value_enum, value_name = "METHOD", default_value_t = CompressionMethod::Flate, requires = "compress_only")]
+    compression_method: CompressionMethod,
+
+    /// Level --compress-only measures: zlib level 0-9 for flate [default: 6], JPEG quality 1-100 for jpeg [default: 85]
+    #[arg(long, value_name = "LEVEL", requires = "compress_only")]
+    compression_level: Option<u8>,
+
+    /// Print each page's layers as they are decoded: key, protocol, bitmap address, compressed and decoded size,
+    /// and decode and compositing times
+    #[arg(long)]
+    print_layers: bool,
+
+    /// Number PDF viewers show for the first page, e.g. 41 for a notebook that continues a previous one
+    #[arg(long, value_name = "N")]
+    page_label_offset: Option<NonZeroUsize>,
+
+    /// Style of the page numbers PDF viewers show
+    #[arg(long, value_enum, value_name = "STYLE")]
+    page_label_style: Option<LabelStyle>,
+
+    /// Ask PDF viewers to size their window to the first page
+    #[arg(long)]
+    viewer_fit_window: bool,
+
+    /// Ask PDF viewers to show two pages side by side
+    #[arg(long)]
+    viewer_two_page: bool,
+
+    /// Ask PDF viewers to open the document full screen
+    #[arg(long)]
+    viewer_fullscreen: bool,
+
+    /// Write a tagged PDF with alt text for each page, as PDF/UA-1 asks for screen readers
+    #[arg(long)]
+    pdf_ua: bool,
+
+    /// Language of the notes, e.g. en-US or ja, for screen readers and search. Without it, the PDF's /Lang follows the
+    /// locale, so the same notes convert to different bytes under a different LC_ALL or LANG [default: from LC_ALL or
+    /// LANG]
+    #[arg(long, value_name = "TAG", value_parser = parse_language_tag)]
+    lang: Option<String>,
+
+    /// Value of the PDF's Producer field [default: supernote_pdf <version>]
+    #[arg(long, value_name = "TEXT")]
+    producer: Option<String>,
+
+    /// Value of the PDF's Creator field [default: supernote_pdf <version>]
+    #[arg(long, value_name = "TEXT")]
+    creator: Option<String>,
+
+    /// Value of the PDF's Author field [default: none, or the source files with --merge]
+    #[arg(long, value_name = "TEXT")]
+    author: Option<String>,
+
+    /// Don't draw progress bars; print plain status lines instead. Implied when stderr is not a terminal
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Render and convert on a single thread. Output is byte-for-byte identical either way, since PDFs carry no
+    /// timestamps (no `/CreationDate` or `/ModDate`) and nothing is randomized; this makes timing and resource use
+    /// reproducible too. Pass --lang as well to keep the output from depending on the locale
+    #[arg(long, conflicts_with_all = ["no_rayon", "fd_limit"])]
+    deterministic: bool,
+
+    /// Do everything on the main thread, without starting any worker threads, for environments that limit how many
+    /// threads a process may have. Output is byte-for-byte identical
+    #[arg(long, conflicts_with = "fd_limit")]
+    no_rayon: bool,
+
+    /// Run few enough conversions at once that no more than N files are open. By default the open file limit is
+    /// raised as far as the system allows, with a warning if that isn't enough
+    #[arg(long, value_name = "N")]
+    fd_limit: Option<NonZeroUsize>,
+
+    /// Write a PDF showing each page of BEFORE next to the same page of AFTER, for reviewing edits
+    #[arg(long, num_args = 2, value_names = ["BEFORE", "AFTER"], conflicts_with = "input")]
+    compare: Option<Vec<PathBuf>>,
+
+    /// Write one PDF with the pages of all these .note files in order, bookmarked as "<file>: <page title>"
+    #[arg(long, value_name = "FILES", value_delimiter = ',', num_args = 1.., conflicts_with_all = ["input", "compare"])]
+    merge: Option<Vec<PathBuf>>,
+
+    /// Run a local HTTP server instead: POST a .note file to /convert to get the PDF back, or open / in a browser.
+    /// The other conversion options become the defaults for every request
+    #[arg(long, conflicts_with_all = ["input", "output", "compare", "merge"])]
+    serve: bool,
+
+    /// Port for --serve, on 127.0.0.1
+    #[arg(long, default_value_t = 8080, requires = "serve")]
+    port: u16,
+
+    /// Print the page and layer structure of a .note file without rendering it
+    #[arg(long, value_name = "FILE")]
+    layer_info: Option<PathBuf>,
+
+    /// Print every key-value pair in the FILE_FEATURE header of a .note file (device, firmware, creation platform,
+    /// ...) without rendering it
+    #[arg(long, value_name = "FILE")]
+    device_dump: Option<PathBuf>,
+
+    /// Salvage a PDF whose conversion was interrupted: keep its complete objects, rebuild the page tree and
+    /// cross-reference table, and write the result to --output
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["input", "serve", "compare", "merge"])]
+    repair: Option<PathBuf>,
+
+    /// Print a completion script for this shell to stdout and exit
+    #[arg(long, value_name = "SHELL")]
+    generate_completion: Option<Shell>,
+
+    /// Print a man page to stdout and exit
+    #[arg(long)]
+    generate_man: bool,
+
+    /// Output format for --layer-info and --device-dump
+    #[arg(long, value_enum, default_value_t = InfoFormat::Table)]
+    format: InfoFormat,
+}
 
-    /// Output file (.pdf) or directory
-    #[arg(short, long)]
-    output: PathBuf,
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum InfoFormat {
+    Table,
+    Json,
 }
-const A5X_WIDTH: usize = 1404;
-const A5X_HEIGHT: usize = 1872;
-const A5X2_WIDTH: usize = 1920;
-const A5X2_HEIGHT: usize = 2560;
 
-// precompile regex
-lazy_static! {
-    static ref METADATA_RE: Regex = Regex::new(r"<(?P<key>[^:]+?):(?P<value>.*?)>").unwrap();
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PageFormatArg {
+    Auto,
+    Jpeg,
+    Flate,
+    Ccitt,
 }
 
-#[derive(Debug)]
-pub struct Notebook {
-    pub signature: String,
-    pub pages: Vec<Page>,
-    pub width: usize,
-    pub height: usize,
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CompressionMethod {
+    Flate,
+    Jpeg,
+    Ccitt,
 }
 
-#[derive(Debug)]
-pub struct Page {
-    pub addr: u64,
-    pub layers: Vec<Layer>,
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DirStructure {
+    /// Recreate the input's subdirectories: notebooks/daily/jan.note becomes notebooks/daily/jan.pdf
+    Mirror,
+    /// Put every PDF directly in the output directory, named after its path: notebooks_daily_jan.pdf
+    Flat,
 }
 
-#[derive(Debug, Default)]
-pub struct Layer {
-    pub key: String,
-    pub protocol: String,
-    pub bitmap_address: u64,
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnConflict {
+    /// Stop without converting anything
+    Error,
+    /// Leave the existing PDF alone and convert the other notebooks, e.g. to pick up where an earlier run stopped
+    Skip,
 }
 
-#[derive(Debug)]
-struct PdfPageChunk {
-    page_object: Vec<u8>,
-    contents_object: Vec<u8>,
-    image_object: Vec<u8>,
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LabelStyle {
+    /// 1, 2, 3, ...
+    Decimal,
+    /// i, ii, iii, ...
+    Roman,
 }
 
-fn get_signature(file: &mut File) -> Result<String> {
-    const SIGNATURE_OFFSET: u64 = 4;
-    const SIGNATURE_LENGTH: usize = 20;
+/// Parses an opaque color written as hex `RRGGBB`, with or without a leading `#`.
+fn parse_hex_color(s: &str) -> std::result::Result<Rgba<u8>, String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not a hex color like #1a237e", s));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok(Rgba([channel(0), channel(2), channel(4), 255]))
+}
 
-    // The `?` operator is used here. If `File::open` returns an `Err`, the `?`
-    // will immediately stop this function and return that `Err` to the caller.
-    // If it returns `Ok(file)`, it unwraps the value and assigns it to `file`.
+/// Parses a positive, finite number, such as a scale factor or a DPI.
+fn parse_scale(s: &str) -> std::result::Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(scale) if scale.is_finite() && scale > 0.0 => Ok(scale),
+        _ => Err(format!("'{}' is not a positive number", s)),
+    }
+}
 
-    // Seek to the signature's starting position.
-    file.seek(SeekFrom::Start(SIGNATURE_OFFSET))?;
+/// Parses a percentage between 0 and 100, with or without a trailing `%`.
+fn parse_percent(s: &str) -> std::result::Result<f32, String> {
+    match s.trim().trim_end_matches('%').parse::<f32>() {
+        Ok(pct) if (0.0..=100.0).contains(&pct) => Ok(pct),
+        _ => Err(format!("'{}' is not a percentage between 0 and 100", s)),
+    }
+}
 
-    // Read the signature bytes.
-    let mut signature_bytes = vec![0; SIGNATURE_LENGTH];
-    file.read_exact(&mut signature_bytes)?;
+/// Parses a BCP 47 language tag such as `en-US`. Underscores, as in POSIX locales, become hyphens.
+fn parse_language_tag(s: &str) -> std::result::Result<String, String> {
+    let tag = s.trim().replace('_', "-");
+    if !tag.is_empty()
+        && tag
+            .split('-')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+    {
+        Ok(tag)
+    } else {
+        Err(format!("'{}' is not a language tag like en-US", s))
+    }
+}
+
+/// The language of the user's locale, from `LC_ALL` or else `LANG`. See [`locale_language`].
+fn system_language() -> Option<String> {
+    let locale = ["LC_ALL", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|locale| !locale.is_empty())?;
+    locale_language(&locale)
+}
 
-    // Convert the bytes into a readable string.
-    // since it is an anyhow result, "?" can propagate any type of error back in a generic way.
-    let signature_string = String::from_utf8(signature_bytes)?;
+/// The language of a POSIX locale name, e.g. `de-DE` for `de_DE.UTF-8`. None for the `C` and `POSIX` locales.
+fn locale_language(locale: &str) -> Option<String> {
+    // Drop the encoding and modifier, as in `de_DE.UTF-8@euro`.
+    let language = locale.split(['.', '@']).next()?;
+    if language == "C" || language == "POSIX" {
+        return None;
+    }
+    parse_language_tag(language).ok()
+}
+
+/// Parses a 1-based page number such as `3` or an inclusive range such as `5-7`.
+fn parse_page_range(pages: &str) -> Option<RangeInclusive<usize>> {
+    let (first, last) = pages.split_once('-').unwrap_or((pages, pages));
+    match (first.trim().parse::<usize>(), last.trim().parse::<usize>()) {
+        (Ok(first), Ok(last)) if first >= 1 && first <= last => Some(first..=last),
+        _ => None,
+    }
+}
 
-    Ok(signature_string)
+/// Parses a list of pages and ranges such as `1,5-7,10` into 1-based page numbers.
+fn parse_page_set(s: &str) -> std::result::Result<BTreeSet<usize>, String> {
+    let mut pages = BTreeSet::new();
+    for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let range = parse_page_range(entry).ok_or_else(|| format!("'{}' is not a page or range, like 3 or 5-7", entry))?;
+        pages.extend(range);
+    }
+    Ok(pages)
 }
 
-/// Reads a metadata block at a given address and parses it into a HashMap.
-/// Metadata format is `<KEY1:VALUE1><KEY2:VALUE2>...`
-fn parse_metadata_block(file: &mut File, address: u64) -> Result<HashMap<String, String>> {
-    // The regex for parsing the key-value format.
-    // It's "lazy" (`*?`) to handle nested or unusual values correctly.
-    if address == 0 {
-        let empty: HashMap<String, String> = HashMap::new();
-        return Ok(empty);
+/// Parses per-page rotations such as `1:90,3:180,5-7:270` into clockwise degrees by 1-based page number.
+fn parse_page_rotations(s: &str) -> std::result::Result<HashMap<usize, u32>, String> {
+    let mut rotations = HashMap::new();
+    for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let invalid = || format!("'{}' is not a page or range with an angle, like 3:90 or 5-7:270", entry);
+        let (pages, degrees) = entry.split_once(':').ok_or_else(invalid)?;
+        let degrees = match degrees.trim().parse::<u32>() {
+            Ok(degrees @ (0 | 90 | 180 | 270)) => degrees,
+            _ => return Err(format!("'{}': pages can only be rotated by 0, 90, 180 or 270 degrees", entry)),
+        };
+        for page in parse_page_range(pages).ok_or_else(invalid)? {
+            rotations.insert(page, degrees);
+        }
     }
+    Ok(rotations)
+}
 
-    file.seek(SeekFrom::Start(address))?;
+/// Parses a byte size such as `500KB` or `10MB`. Units are powers of 1024; a bare number is bytes.
+fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let upper = s.trim().to_ascii_uppercase();
+    let digits_end = upper.find(|c: char| !c.is_ascii_digit()).unwrap_or(upper.len());
+    let (number, unit) = upper.split_at(digits_end);
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("'{}' has an unknown unit; use B, KB, MB or GB", s)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("'{}' is not a positive size like 10MB", s))
+}
 
-    // Read the 4-byte block length
-    let mut len_bytes = [0u8; 4];
-    file.read_exact(&mut len_bytes)?;
-    let block_len = u32::from_le_bytes(len_bytes) as usize;
+/// Parses a hex color like [`parse_hex_color`], or `transparent`.
+fn parse_bg_color(s: &str) -> std::result::Result<Rgba<u8>, String> {
+    if s.eq_ignore_ascii_case("transparent") {
+        return Ok(Rgba([0, 0, 0, 0]));
+    }
+    parse_hex_color(s)
+}
 
-    // Read the block content
-    let mut content_bytes = vec![0; block_len];
-    file.read_exact(&mut content_bytes)?;
-    let content = String::from_utf8(content_bytes)?;
+/// Usage examples for the man page, as (description, command) pairs.
+const MAN_EXAMPLES: &[(&str, &str)] = &[
+    ("Convert a single notebook:", "supernote_pdf -i Meeting.note -o Meeting.pdf"),
+    (
+        "Convert a whole Note folder, mirroring its structure:",
+        "supernote_pdf -i Note -o Note_PDFs",
+    ),
+    (
+        "Convert several inputs into one directory:",
+        "supernote_pdf -i Meeting.note -i projects -o Archive",
+    ),
+    (
+        "Show a notebook's pages and layers without converting it:",
+        "supernote_pdf --layer-info Meeting.note",
+    ),
+];
+
+/// Prints a man page (NAME, SYNOPSIS, DESCRIPTION, OPTIONS, EXAMPLES, VERSION) generated from the CLI definition.
+fn print_man_page() -> Result<()> {
+    let man = clap_mangen::Man::new(Cli::command());
+    let mut out = std::io::stdout().lock();
+    man.render_title(&mut out)?;
+    man.render_name_section(&mut out)?;
+    man.render_synopsis_section(&mut out)?;
+    man.render_description_section(&mut out)?;
+    man.render_options_section(&mut out)?;
+
+    let mut examples = Roff::new();
+    examples.control("SH", ["EXAMPLES"]);
+    for (description, command) in MAN_EXAMPLES {
+        examples
+            .control("PP", [])
+            .text([roman(*description)])
+            .control("RS", [])
+            .text([bold(*command)])
+            .control("RE", []);
+    }
+    examples.to_writer(&mut out)?;
 
-    // Use the regex to find all key-value pairs and collect them into a map.
-    let map: HashMap<String, String> = METADATA_RE
-        .captures_iter(&content)
-        .map(|cap| {
-            let key = cap.name("key").unwrap().as_str().to_string();
-            let value = cap.name("value").unwrap().as_str().to_string();
-            (key, value)
-        })
-        .collect();
+    man.render_version_section(&mut out)?;
+    Ok(())
+}
 
-    Ok(map)
+/// Prints each page's compressed size and compression ratio for `--compress-only`, then totals for each file.
+fn print_compression(inputs: &[PathBuf], method: CompressionMethod, level: Option<u8>, options: &ConvertOptions) -> Result<()> {
+    let (encoding, level) = match (method, level) {
+        (CompressionMethod::Flate, Some(level @ 10..)) => bail!("--compression-level {} is out of range; zlib levels are 0 to 9", level),
+        (CompressionMethod::Flate, level) => (ImageEncoding::Flate, level.unwrap_or(6)),
+        (CompressionMethod::Jpeg, Some(level @ (0 | 101..))) => {
+            bail!("--compression-level {} is out of range; JPEG quality is 1 to 100", level)
+        }
+        (CompressionMethod::Jpeg, level) => (ImageEncoding::Jpeg, level.unwrap_or(85)),
+        (CompressionMethod::Ccitt, Some(_)) => bail!("CCITT compression has no levels; leave out --compression-level"),
+        (CompressionMethod::Ccitt, None) => (ImageEncoding::Ccitt, 0),
+    };
+    for input in inputs {
+        let pages = measure_compression(input, encoding, level, options)?;
+        println!("{}", input.display());
+        println!("  {:>5} {:>12} {:>12} {:>7} {:>8}", "Page", "Raw", "Compressed", "Ratio", "Time");
+        for page in &pages {
+            println!(
+                "  {:>5} {:>12} {:>12} {:>6.1}x {:>6}ms",
+                page.page,
+                page.raw_len,
+                page.compressed_len,
+                page.raw_len as f64 / page.compressed_len.max(1) as f64,
+                page.time.as_millis()
+            );
+        }
+        let raw: usize = pages.iter().map(|page| page.raw_len).sum();
+        let compressed: usize = pages.iter().map(|page| page.compressed_len).sum();
+        let time: Duration = pages.iter().map(|page| page.time).sum();
+        println!(
+            "  {:>5} {:>12} {:>12} {:>6.1}x {:>6}ms",
+            "Total",
+            raw,
+            compressed,
+            raw as f64 / compressed.max(1) as f64,
+            time.as_millis()
+        );
+    }
+    Ok(())
 }
 
-/// Detects the device type and returns the appropriate width and height dimensions
-fn detect_device_dimensions(file: &mut File, footer_map: &HashMap<String, String>) -> Result<(usize, usize)> {
-    if let Some(header_addr_str) = footer_map.get("FILE_FEATURE") {
-        if let Ok(header_addr) = header_addr_str.parse::<u64>() {
-            let header_map = parse_metadata_block(file, header_addr)?;
-            if let Some(equipment) = header_map.get("APPLY_EQUIPMENT") {
-                if equipment == "N5" {
-                    return Ok((A5X2_WIDTH, A5X2_HEIGHT));
+/// Writes a repaired copy of `broken_file` for `--repair`, and reports what was recovered.
+fn repair_file(broken_file: &Path, output_file: &Path) -> Result<()> {
+    if output_file.exists() {
+        bail!(
+            "Output file '{}' already exists. Please remove it or choose a different name.",
+            output_file.display()
+        );
+    }
+    let summary = repair_pdf(broken_file, output_file)?;
+    eprintln!(
+        "Recovered {} objects and {} pages from '{}'{}; dropped {} trailing bytes. Wrote '{}'.",
+        summary.objects,
+        summary.pages,
+        broken_file.display(),
+        if summary.rebuilt_page_tree { ", rebuilding the page tree" } else { "" },
+        summary.bytes_dropped,
+        output_file.display()
+    );
+    Ok(())
+}
+
+/// Prints every field of the `FILE_FEATURE` header, sorted by key.
+fn print_device_dump(input_file: &Path, format: InfoFormat) -> Result<()> {
+    let notebook = Notebook::from_path(input_file)?;
+    print_notebook_warnings(input_file, &notebook);
+    let fields = &notebook.header.fields;
+    match format {
+        InfoFormat::Table => {
+            println!("{} ({})", input_file.display(), notebook.signature);
+            let key_width = fields.keys().map(|key| key.len()).max().unwrap_or(0);
+            for (key, value) in fields {
+                println!("{:<width$}  {}", key, value, width = key_width);
+            }
+        }
+        InfoFormat::Json => {
+            let dump = json!({
+                "file": input_file.display().to_string(),
+                "signature": notebook.signature,
+                "fields": fields,
+            });
+            println!("{}", serde_json::to_string_pretty(&dump)?);
+        }
+    }
+    Ok(())
+}
+
+/// Prints every page's layers (key, protocol, bitmap address and bitmap block size) without decoding any bitmaps.
+fn print_layer_info(input_file: &Path, format: InfoFormat) -> Result<()> {
+    let mut file = File::open(input_file)?;
+    let notebook = parse_notebook(&mut file)?;
+    print_notebook_warnings(input_file, &notebook);
+
+    let mut pages = Vec::new();
+    for (page_idx, page) in notebook.pages.iter().enumerate() {
+        let mut layers = Vec::new();
+        for layer in page.layers.iter() {
+            let size = bitmap_block_size(&mut file, layer.bitmap_address)?;
+            layers.push((layer, size));
+        }
+        pages.push((page_idx + 1, layers));
+    }
+
+    match format {
+        InfoFormat::Table => {
+            println!(
+                "{} ({}, {}x{})",
+                input_file.display(),
+                notebook.signature,
+                notebook.width,
+                notebook.height
+            );
+            let header = &notebook.header;
+            for (label, value) in [
+                ("Device", &header.equipment),
+                ("Firmware", &header.firmware_version),
+                ("Title", &header.note_title),
+            ] {
+                if let Some(value) = value {
+                    println!("{}: {}", label, value);
+                }
+            }
+            for (page_num, layers) in pages.iter() {
+                let page = &notebook.pages[page_num - 1];
+                let mut details = Vec::new();
+                if let Some(title) = &page.title {
+                    details.push(format!("title \"{}\"", title));
+                }
+                if page.is_starred {
+                    details.push("starred".to_string());
+                }
+                if page.actual_width.is_some() || page.actual_height.is_some() {
+                    let (width, height) = notebook.page_size(page);
+                    details.push(format!("{}x{}", width, height));
+                }
+                if let Some(created_at) = page.created_at {
+                    details.push(format!("created {}", created_at));
+                }
+                if let Some(modified_at) = page.modified_at {
+                    details.push(format!("modified {}", modified_at));
+                }
+                let details = if details.is_empty() {
+                    String::new()
                 } else {
-                    return Ok((A5X_WIDTH, A5X_HEIGHT));
+                    format!(" ({})", details.join(", "))
+                };
+                println!("Page {}: {} layers{}", page_num, layers.len(), details);
+                for (layer, size) in layers.iter() {
+                    println!(
+                        "  {:<12} {:<10} {:#010x} {:>10} bytes",
+                        layer.key, layer.protocol, layer.bitmap_address, size
+                    );
                 }
             }
         }
+        InfoFormat::Json => {
+            let pages: Vec<_> = pages
+                .iter()
+                .map(|(page_num, layers)| {
+                    let page = &notebook.pages[page_num - 1];
+                    let (width, height) = notebook.page_size(page);
+                    json!({
+                        "page": page_num,
+                        "width": width,
+                        "height": height,
+                        "title": page.title,
+                        "starred": page.is_starred,
+                        "created_at": page.created_at,
+                        "modified_at": page.modified_at,
+                        "layers": layers
+                            .iter()
+                            .map(|(layer, size)| json!({
+                                "key": layer.key,
+                                "protocol": layer.protocol,
+                                "bitmap_address": format!("{:#x}", layer.bitmap_address),
+                                "bitmap_size": size,
+                            }))
+                            .collect::<Vec<_>>(),
+                    })
+                })
+                .collect();
+            let info = json!({
+                "file": input_file.display().to_string(),
+                "signature": notebook.signature,
+                "width": notebook.width,
+                "height": notebook.height,
+                "equipment": notebook.header.equipment,
+                "firmware_version": notebook.header.firmware_version,
+                "note_title": notebook.header.note_title,
+                "pages": pages,
+            });
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        }
     }
-    Ok((A5X_WIDTH, A5X_HEIGHT))
+
+    Ok(())
 }
 
-fn parse_notebook(file: &mut File) -> Result<Notebook> {
-    let file_signature = get_signature(file)?;
+/// Prints the warnings of conversions to stderr.
+#[derive(Debug)]
+struct PrintWarnings;
 
-    // Get footer address and map
-    file.seek(SeekFrom::End(-4))?;
-    let mut addr_bytes = [0u8; 4];
-    file.read_exact(&mut addr_bytes)?;
-    let footer_addr = u32::from_le_bytes(addr_bytes) as u64; // Convert the little-endian bytes to a u32, then cast to u64
-    let footer_map = parse_metadata_block(file, footer_addr)?;
+impl ConversionHook for PrintWarnings {
+    fn on_warning(&self, message: &str) {
+        eprintln!("Warning: {}", message);
+    }
+}
 
-    // Detect device dimensions by parsing header
-    let (width, height) = detect_device_dimensions(file, &footer_map)?;
+/// Prints the [`Notebook::warnings`] of a notebook that is inspected rather than converted.
+fn print_notebook_warnings(input_path: &Path, notebook: &Notebook) {
+    for warning in &notebook.warnings {
+        PrintWarnings.on_warning(&format!("'{}': {}", input_path.display(), warning));
+    }
+}
 
-    // get page addresses from the hashmap, sorted
-    let page_addrs = footer_map
-        .iter()
-        .filter(|(k, _v)| k.starts_with("PAGE"))
-        // .map(|(k, v)| (k.strip_prefix("PAGE").unwrap().parse::<u64>().unwrap(), v))
-        .sorted_by_key(|(k, _v)| k.strip_prefix("PAGE").unwrap().parse::<u64>().unwrap())
-        .map(|(_k, v)| v.parse::<u64>())
-        .collect::<std::result::Result<Vec<u64>, _>>()?;
-
-    // let page_map = parse_metadata_block(&mut file, *page_addrs.get(0).unwrap());
-    // println!("{:?}", page_map);
-
-    let mut pages: Vec<Page> = Vec::new();
-    for addr in page_addrs {
-        let page_map = parse_metadata_block(file, addr)?;
-        let layer_order = page_map
-            .get("LAYERSEQ")
-            .map(|s| s.split(',').map(String::from).collect())
-            .unwrap_or_else(|| {
-                // Default order if LAYERSEQ is missing
-                vec![
-                    "BGLAYER".to_string(),
-                    "MAINLAYER".to_string(),
-                    "LAYER1".to_string(),
-                    "LAYER2".to_string(),
-                    "LAYER3".to_string(),
-                ]
-            });
-        let mut layers: Vec<Layer> = Vec::new();
-        for layer_key in layer_order.iter() {
-            // if page_map.contains_key(layer_key.as_str()) {
-            if let Some(addr_str) = page_map.get(layer_key.as_str()) {
-                let layer_addr = addr_str.parse::<u64>()?;
-                let data = parse_metadata_block(file, layer_addr)?;
-                layers.push(Layer {
-                    key: layer_key.to_string(),
-                    protocol: data.get("LAYERPROTOCOL").cloned().unwrap_or_default(),
-                    bitmap_address: data.get("LAYERBITMAP").and_then(|s| s.parse::<u64>().ok()).unwrap_or(0),
-                });
-            }
+/// Outcome of converting one file, for `--stats-output` and `--stats`.
+struct FileStats {
+    input_path: PathBuf,
+    output_path: PathBuf,
+    result: std::result::Result<ConversionSummary, String>,
+    total_ms: u128,
+    output_bytes: u64,
+}
+
+/// Everything shared by the conversions of one invocation.
+struct Run {
+    options: ConvertOptions,
+    /// One entry per converted file, for `--stats-output` and `--stats`.
+    stats: Mutex<Vec<FileStats>>,
+    /// Inputs converted by earlier runs, for `--dedup-check`.
+    dedup: Option<DedupIndex>,
+    /// Whether to draw progress bars; off with `--no-progress` or when stderr isn't a terminal.
+    progress: bool,
+    /// How many times to retry a conversion that hit a transient I/O error, for `--retry`.
+    retries: u32,
+    /// Wait before the first retry, for `--retry-delay`.
+    retry_delay: Duration,
+    /// Whether directory scans follow symbolic links, for `--follow-symlinks`.
+    follow_symlinks: bool,
+    /// How deep directory scans go, for `--max-depth`.
+    max_depth: Option<usize>,
+    /// Whether directory conversions recreate the input's subdirectories, for `--dir-structure`.
+    dir_structure: DirStructure,
+    /// What to do with notebooks whose PDF already exists, for `--on-conflict` and `--skip-if-exists`.
+    on_conflict: OnConflict,
+    /// Whether directory scans skip files matched by `.supernote_pdf_ignore` files; off with `--no-ignore`.
+    use_ignore_files: bool,
+    /// Whether to report each file's format version before converting it, for `--version-check`.
+    version_check: bool,
+    /// Whether to read written PDFs back and check their structure, for `--verify`.
+    verify: bool,
+    /// Whether output PDFs take their input's modification time, for `--preserve-timestamps`.
+    preserve_timestamps: bool,
+    /// Whether to only list what would be converted, for `--dry-run`.
+    dry_run: bool,
+    /// Size of a PDF relative to its raw pixels, for `--estimated-compression-ratio`.
+    estimated_compression_ratio: f32,
+    /// The bucket converted files are uploaded to, when the output is an `s3://` URL.
+    s3: Option<S3Output>,
+}
+
+impl Run {
+    /// A progress bar over `len` items (`None` until it is known), drawn with `template`. Hidden when progress is off.
+    fn progress_bar(&self, len: Option<u64>, template: &str) -> ProgressBar {
+        if self.progress {
+            ProgressBar::with_draw_target(len, ProgressDrawTarget::stderr())
+                .with_style(ProgressStyle::with_template(template).expect("valid template"))
+        } else {
+            ProgressBar::hidden()
         }
-        pages.push(Page { addr: addr, layers: layers });
     }
 
-    Ok(Notebook {
-        signature: file_signature,
-        pages: pages,
-        width,
-        height,
-    })
+    /// Prints a status line above the progress bar, or on its own when progress is off
+    /// (a hidden bar would swallow it).
+    fn status(&self, pb: &ProgressBar, msg: String) {
+        if self.progress {
+            pb.println(msg);
+        } else {
+            eprintln!("{}", msg);
+        }
+    }
+}
+
+/// SHA-256 hashes of converted inputs and the PDF each was converted to, persisted as JSON between runs.
+struct DedupIndex {
+    path: PathBuf,
+    hashes: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl DedupIndex {
+    /// Loads the index at `path`; a missing file is an empty index.
+    fn load(path: PathBuf) -> Result<DedupIndex> {
+        let hashes = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).with_context(|| format!("'{}' is not a valid hash index", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("could not read '{}'", path.display())),
+        };
+        Ok(DedupIndex {
+            path,
+            hashes: Mutex::new(hashes),
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let hashes = self.hashes.lock().unwrap();
+        fs::write(&self.path, serde_json::to_string_pretty(&*hashes)?)?;
+        Ok(())
+    }
 }
 
-/// Decodes a byte stream compressed with the RATTA_RLE algorithm.
-fn decode_rle(compressed_data: &[u8], width: usize, height: usize) -> Result<Vec<u8>> {
-    // Screen dimensions
-    let expected_len = width * height;
-    let mut decompressed = Vec::with_capacity(expected_len);
+/// Name of the files listing, as gitignore patterns, the notebooks a directory scan should skip.
+const IGNORE_FILE_NAME: &str = ".supernote_pdf_ignore";
 
-    let mut i = 0; // Our position in the compressed_data slice
-    let mut holder: Option<(u8, u8)> = None; // State for multi-byte lengths
+/// The `.supernote_pdf_ignore` files of a directory tree, read as the scan reaches each directory.
+/// As with `.gitignore`, a file's patterns apply to everything below its directory, and deeper files take precedence.
+struct IgnoreFiles {
+    root: PathBuf,
+    loaded: HashMap<PathBuf, Option<Gitignore>>,
+}
 
-    while i < compressed_data.len() {
-        // Ensure we can read a pair of bytes
-        if i + 1 >= compressed_data.len() {
-            break;
+impl IgnoreFiles {
+    fn new(root: &Path) -> Self {
+        IgnoreFiles {
+            root: root.to_path_buf(),
+            loaded: HashMap::new(),
         }
-        let color_code = compressed_data[i];
-        let length_code = compressed_data[i + 1];
-        i += 2; // Move to the next pair
+    }
 
-        let length: usize;
+    /// Whether the ignore files in the directories between the root and `path` exclude `path`.
+    fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        // The closest ignore file that has an opinion decides, so `!pattern` in a subdirectory can re-include a file.
+        for dir in path.ancestors().skip(1).take_while(|dir| dir.starts_with(&self.root)) {
+            let ignore_file = self.loaded.entry(dir.to_path_buf()).or_insert_with(|| load_ignore_file(dir));
+            match ignore_file.as_ref().map(|ignore_file| ignore_file.matched(path, is_dir)) {
+                Some(Match::Ignore(_)) => return true,
+                Some(Match::Whitelist(_)) => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+}
 
-        if let Some((prev_color_code, prev_length_code)) = holder.take() {
-            // We are in the "holder" state from the previous iteration.
-            if color_code == prev_color_code {
-                // The colors match, so combine the lengths.
-                length = 1 + length_code as usize + (((prev_length_code & 0x7f) as usize + 1) << 7);
-            } else {
-                // Colors don't match. First, process the held-over length.
-                let held_length = ((prev_length_code & 0x7f) as usize + 1) << 7;
-                decompressed.extend(std::iter::repeat(prev_color_code).take(held_length));
-                // Then, process the current pair normally.
-                length = length_code as usize + 1;
+/// Reads `dir/.supernote_pdf_ignore`, if there is one. Invalid patterns are skipped with a warning.
+fn load_ignore_file(dir: &Path) -> Option<Gitignore> {
+    let path = dir.join(IGNORE_FILE_NAME);
+    if !path.is_file() {
+        return None;
+    }
+    let (ignore_file, error) = Gitignore::new(&path);
+    if let Some(e) = error {
+        eprintln!("Warning: '{}': {}", path.display(), e);
+    }
+    Some(ignore_file)
+}
+
+/// Hex-encoded SHA-256 of a file's contents.
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Prints whether a file's signature is known to convert correctly, e.g.
+/// `✓ noteSN_FILE_VER_20200001 – known good (Chauvet ≥2.x): notes/a.note`.
+/// Files with other signatures are still converted.
+fn print_version_check(input_file: &Path) -> Result<()> {
+    let signature = read_signature(input_file)?;
+    match KNOWN_GOOD_SIGNATURES.iter().find(|(known, _)| *known == signature) {
+        Some((_, firmware)) => eprintln!("✓ note{} – known good ({}): {}", signature, firmware, input_file.display()),
+        None => eprintln!("? note{} – unverified: {}", signature.escape_debug(), input_file.display()),
+    }
+    Ok(())
+}
+
+/// Calls `f`, calling it again up to `retries` more times while it fails with a transient I/O error.
+/// The first retry waits `delay`, and each one after that waits twice as long as the one before.
+fn retry<T>(retries: u32, delay: Duration, mut f: impl FnMut() -> supernote_pdf::Result<T>) -> supernote_pdf::Result<T> {
+    let mut delay = delay;
+    for attempt in 1..=retries {
+        match f() {
+            Err(e) if is_transient(&e) => {
+                eprintln!("Warning: {} (attempt {} of {}); retrying in {:?}", e, attempt, retries + 1, delay);
+                thread::sleep(delay);
+                delay *= 2;
             }
-        } else if length_code == 0xff {
-            // Special marker for a long run
-            length = 0x4000; // 16384
-        } else if length_code & 0x80 != 0 {
-            // Most significant bit is set. This is a multi-byte length marker.
-            // We store the current pair in the `holder` and continue to the next iteration.
-            holder = Some((color_code, length_code));
-            continue;
-        } else {
-            // Standard case: length is just length_code + 1.
-            length = length_code as usize + 1;
+            result => return result,
         }
+    }
+    f()
+}
 
-        // Add the `color_code` to our output `length` times.
-        decompressed.extend(std::iter::repeat(color_code).take(length));
+/// Whether an error may go away on its own, like a file that a sync client hasn't finished writing.
+fn is_transient(error: &SupernoteError) -> bool {
+    match error {
+        SupernoteError::IoError(e) => matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::NotFound),
+        SupernoteError::InPage { source, .. } => is_transient(source),
+        _ => false,
     }
+}
+
+/// What a conversion wrote, for the batch progress estimates.
+struct ConvertedFile {
+    pages: usize,
+    output_bytes: u64,
+}
 
-    // After the loop, check if there's a final item in the holder.
-    // This can happen if the last block was a multi-byte marker.
-    if let Some((color_code, length_code)) = holder {
-        let remaining_len = expected_len.saturating_sub(decompressed.len());
-        // A simple heuristic for the tail length
-        let tail_length = std::cmp::min(((length_code & 0x7f) as usize + 1) << 7, remaining_len);
-        if tail_length > 0 {
-            decompressed.extend(std::iter::repeat(color_code).take(tail_length));
+/// Converts one file and records how it went in the run's stats.
+/// With `--dedup-check`, a file identical to one converted before (whose PDF still exists) is skipped,
+/// and `None` is returned. `page_progress` is advanced as pages are rendered.
+fn convert_file(input_file: &Path, output_file: &Path, run: &Run, page_progress: Option<&ProgressBar>) -> Result<Option<ConvertedFile>> {
+    let hash = match &run.dedup {
+        Some(dedup) => {
+            let hash = sha256_file(input_file)?;
+            if let Some(previous) = dedup.hashes.lock().unwrap().get(&hash).filter(|previous| previous.exists()) {
+                eprintln!(
+                    "Skipping '{}': identical to a notebook already converted to '{}'",
+                    input_file.display(),
+                    previous.display()
+                );
+                return Ok(None);
+            }
+            Some(hash)
         }
+        None => None,
+    };
+
+    if run.version_check {
+        print_version_check(input_file)?;
     }
 
-    // Final sanity check
-    if decompressed.len() != expected_len {
-        // In a real app, you might want a more robust way to handle this,
-        // but for now, we can pad or truncate to the expected size.
-        decompressed.resize(expected_len, 0x62); // Pad with transparent if too short
+    let options = match page_progress {
+        Some(pb) => &ConvertOptions {
+            page_progress: Some(pb.clone()),
+            ..run.options.clone()
+        },
+        None => &run.options,
+    };
+    let start = Instant::now();
+    let result = retry(run.retries, run.retry_delay, || convert_note_to_pdf(input_file, output_file, options));
+    let result = match (result, run.preserve_timestamps) {
+        (Ok(summary), true) => copy_mtime(input_file, &summary.output_files).map(|()| summary),
+        (result, _) => result,
+    };
+    let total_ms = start.elapsed().as_millis();
+    let output_bytes = match &result {
+        Ok(summary) => summary.output_files.iter().filter_map(|f| fs::metadata(f).ok()).map(|m| m.len()).sum(),
+        Err(_) => 0,
+    };
+    if let (true, Ok(summary)) = (run.verify, &result) {
+        summary.output_files.iter().for_each(|path| print_verify_warnings(path));
     }
 
-    Ok(decompressed)
+    let result = match (result, &run.s3) {
+        (Ok(summary), Some(s3)) => upload_outputs(s3, &summary, output_file, run).map(|()| summary),
+        (result, _) => result.map_err(anyhow::Error::from),
+    };
+    let (result, outcome) = match result {
+        Ok(summary) => (
+            Ok(Some(ConvertedFile {
+                pages: summary.pages_written,
+                output_bytes,
+            })),
+            Ok(summary),
+        ),
+        Err(e) => {
+            let msg = format!("{:#}", e);
+            (Err(e), Err(msg))
+        }
+    };
+    if let (Some(dedup), Some(hash), Ok(_)) = (&run.dedup, hash, &outcome) {
+        dedup.hashes.lock().unwrap().insert(hash, output_file.to_path_buf());
+    }
+    run.stats.lock().unwrap().push(FileStats {
+        input_path: input_file.to_path_buf(),
+        output_path: output_file.to_path_buf(),
+        result: outcome,
+        total_ms,
+        output_bytes,
+    });
+    result
 }
 
-/// Maps a Supernote color codes to an RGBA pixel.
-fn to_rgba(pixel_byte: u8) -> Rgba<u8> {
-    match pixel_byte {
-        // --- Core Colors ---
-        0x61 => Rgba([0, 0, 0, 255]),       // Black
-        0x65 => Rgba([255, 255, 255, 255]), // White
-        0x62 => Rgba([0, 0, 0, 0]),         // Transparent (used for background layer)
+/// Sets the modification time of `output_files` to that of `input_file`, for `--preserve-timestamps`.
+fn copy_mtime(input_file: &Path, output_files: &[PathBuf]) -> std::result::Result<(), SupernoteError> {
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(input_file)?);
+    for output_file in output_files {
+        filetime::set_file_mtime(output_file, mtime)?;
+    }
+    Ok(())
+}
 
-        // --- Grays (and their aliases/compat codes) ---
-        // Dark Gray
-        0x63 | 0x9d | 0x9e => Rgba([0x9d, 0x9d, 0x9d, 255]),
-        // Gray
-        0x64 | 0xc9 | 0xca => Rgba([0xc9, 0xc9, 0xc9, 255]),
+/// Reads a written PDF back for `--verify`, and prints what is wrong with it.
+fn print_verify_warnings(path: &Path) {
+    match verify_pdf(path) {
+        Ok(warnings) => {
+            for warning in warnings {
+                eprintln!("Warning: '{}' is not a valid PDF: {}", path.display(), warning);
+            }
+        }
+        Err(e) => eprintln!("Warning: could not verify '{}': {}", path.display(), e),
+    }
+}
 
-        // --- Handle all other bytes as anti-aliasing pixels ---
-        _ => {
-            // The byte value itself represents the grayscale intensity.
-            // This renders the smooth edges of handwritten strokes.
-            // this encoding is from the newer note format.
-            Rgba([pixel_byte, pixel_byte, pixel_byte, 255])
+/// Uploads the files a conversion wrote (the PDFs, and the sidecar and thumbnails if there are any) to the S3 output.
+fn upload_outputs(s3: &S3Output, summary: &ConversionSummary, output_file: &Path, run: &Run) -> Result<()> {
+    let sidecar = run.options.sidecar.then(|| output_file.with_extension("json"));
+    for local_path in summary.output_files.iter().chain(sidecar.iter()).chain(&summary.thumbnail_files) {
+        let url = s3.upload(local_path)?;
+        if !run.progress {
+            eprintln!("Uploaded {}", url);
         }
     }
+    Ok(())
 }
 
-fn convert_note_to_pdf(input_path: &Path, output_path: &Path) -> Result<()> {
-    // file handle dropped outside this scope
-    let notebook = {
-        let mut file = File::open(input_path)?;
-        parse_notebook(&mut file)?
-    };
+/// `~/.cache/supernote_pdf`, or `None` if the home directory is unknown.
+fn default_cache_dir() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".cache").join("supernote_pdf"))
+}
 
-    let width = notebook.width;
-    let height = notebook.height;
+/// Quotes a CSV field if it contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
 
-    let page_images = notebook
-        .pages
-        .par_iter()
-        .map(|page| {
-            let mut file = File::open(input_path)?;
-
-            let mut base_canvas = RgbaImage::from_pixel(width as u32, height as u32, Rgba([255, 255, 255, 255]));
-
-            for layer in page.layers.iter() {
-                if layer.bitmap_address == 0 {
-                    continue;
-                } else if layer.protocol.as_str() == "RATTA_RLE" {
-                    file.seek(SeekFrom::Start(layer.bitmap_address))?;
-                    let mut len_bytes = [0u8; 4];
-                    file.read_exact(&mut len_bytes)?;
-                    let block_len = u32::from_le_bytes(len_bytes) as usize;
-                    let mut compressed_data = vec![0; block_len];
-                    file.read_exact(&mut compressed_data)?;
-                    let pixel_data = decode_rle(&compressed_data, width, height)?;
-
-                    let mut layer_image = RgbaImage::new(width as u32, height as u32);
-                    for (i, &pixel_byte) in pixel_data.iter().enumerate() {
-                        let x = (i % width) as u32;
-                        let y = (i / width) as u32;
-                        layer_image.put_pixel(x, y, to_rgba(pixel_byte));
-                    }
-                    imageops::overlay(&mut base_canvas, &layer_image, 0, 0);
-                } else if layer.protocol.as_str() == "PNG" {
-                    file.seek(SeekFrom::Start(layer.bitmap_address))?;
-                    let mut len_bytes = [0u8; 4];
-                    file.read_exact(&mut len_bytes)?;
-                    let block_len = u32::from_le_bytes(len_bytes) as usize;
-
-                    let mut png_bytes = vec![0; block_len];
-                    file.read_exact(&mut png_bytes)?;
-                    let png_image = image::load_from_memory(&png_bytes)?.to_rgba8();
-                    imageops::overlay(&mut base_canvas, &png_image, 0, 0);
-                }
-            }
+/// Writes the `--stats-output` CSV, one row per file in the order they were converted.
+fn write_stats_csv(path: &Path, stats: &[FileStats]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(
+        writer,
+        "input_path,output_path,page_count,width,height,device,total_ms,output_bytes,status,error_message"
+    )?;
+    for row in stats {
+        let (page_count, width, height, device, status, error_message) = match &row.result {
+            Ok(summary) => (
+                summary.pages_written.to_string(),
+                summary.notebook.width.to_string(),
+                summary.notebook.height.to_string(),
+                summary.notebook.header.equipment.clone().unwrap_or_default(),
+                "success",
+                String::new(),
+            ),
+            Err(msg) => (String::new(), String::new(), String::new(), String::new(), "error", msg.clone()),
+        };
+        let fields = [
+            row.input_path.display().to_string(),
+            row.output_path.display().to_string(),
+            page_count,
+            width,
+            height,
+            device,
+            row.total_ms.to_string(),
+            row.output_bytes.to_string(),
+            status.to_string(),
+            error_message,
+        ];
+        writeln!(writer, "{}", fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
 
-            Ok(base_canvas)
-        })
-        .collect::<Result<Vec<_>>>()?;
-    let total_pages = page_images.len();
-    let page_chunks: Vec<PdfPageChunk> = page_images
-        .into_par_iter()
-        .enumerate()
-        .map(|(i, canvas)| {
-            // Each page will use 3 objects: Page, Contents, Image
-            let page_obj_id = (i * 3) + 3;
-            let contents_obj_id = (i * 3) + 4;
-            let image_obj_id = (i * 3) + 5;
-
-            let dynamic_image = image::DynamicImage::ImageRgba8(canvas);
-
-            let raw_pixels = dynamic_image.to_rgb8().into_raw();
-
-            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(&raw_pixels).unwrap();
-            let compressed_pixels = encoder.finish().unwrap();
-
-            let page_object = format!(
-                "{} 0 obj\n<< /Type /Page\n   /Parent 2 0 R\n   /MediaBox [0 0 595 842]\n   /Contents {} 0 R\n   /Resources << /XObject << /Im1 {} 0 R >> >>\n>>\nendobj\n",
-                page_obj_id,
-                contents_obj_id,
-                image_obj_id
-            ).into_bytes();
-
-            let contents = "q\n595 0 0 842 0 0 cm\n/Im1 Do\nQ\n";
-            let contents_object = format!(
-                "{} 0 obj\n<< /Length {} >>\nstream\n{}\nendstream\nendobj\n",
-                contents_obj_id,
-                contents.len(),
-                contents
-            ).into_bytes();
-            let image_header = format!(
-                "{} 0 obj\n<< /Type /XObject\n   /Subtype /Image\n   /Width {}\n   /Height {}\n   /ColorSpace /DeviceRGB\n   /BitsPerComponent 8\n   /Filter /FlateDecode\n   /Length {} >>\nstream\n",
-                image_obj_id,
-                width,
-                height,
-                compressed_pixels.len()
-            ).into_bytes();
-
-            // Combine the header, the compressed data, and the footer for the image object
-            let final_image_object = [
-                image_header,
-                compressed_pixels,
-                b"\nendstream\nendobj\n".to_vec()
-            ].concat();
-
-            PdfPageChunk {
-                page_object,
-                contents_object,
-                image_object: final_image_object,
+/// Lists, for `--dry-run`, the PDF each `(input, output)` job would write with an estimate of its size, and the
+/// estimated total. Notebooks that can't be read are reported and left out of the total.
+fn print_dry_run(jobs: &[(PathBuf, PathBuf)], run: &Run) {
+    let mut total_pages = 0;
+    let mut total_bytes = 0;
+    for (input_path, output_path) in jobs {
+        let notebook = match Notebook::from_path(input_path) {
+            Ok(notebook) => {
+                print_notebook_warnings(input_path, &notebook);
+                notebook
             }
-        })
-        .collect();
+            Err(e) => {
+                eprintln!("'{}': cannot be read: {}", input_path.display(), e);
+                continue;
+            }
+        };
+        let estimated_bytes = estimate_output_bytes(&notebook, run.estimated_compression_ratio);
+        eprintln!(
+            "'{}' -> '{}': {} pages, {}x{}, est. {}",
+            input_path.display(),
+            output_path.display(),
+            notebook.pages.len(),
+            notebook.width,
+            notebook.height,
+            HumanBytes(estimated_bytes)
+        );
+        total_pages += notebook.pages.len();
+        total_bytes += estimated_bytes;
+    }
+    eprintln!(
+        "Would convert {} files: {} pages, est. {} in total",
+        jobs.len(),
+        total_pages,
+        HumanBytes(total_bytes)
+    );
+}
 
-    // Write everything to a file sequentially
-    let out_file = File::create(output_path)?;
-    let mut writer = BufWriter::new(out_file);
-    let mut byte_offset = 0u64;
-    let mut xref_offsets = vec![0u64; total_pages * 3 + 2]; // Room for all objects
-
-    // Write PDF Header
-    let header = b"%PDF-1.7\n%\xE2\xE3\xCF\xD3\n"; // Header + binary comment
-    writer.write_all(header)?;
-    byte_offset += header.len() as u64;
-
-    // Object 1: Catalog
-    xref_offsets[0] = byte_offset;
-    let catalog = b"1 0 obj\n<< /Type /Catalog /Pages 2 0 R >>\nendobj\n";
-    writer.write_all(catalog)?;
-    byte_offset += catalog.len() as u64;
-
-    // Object 2: The root Pages object
-    xref_offsets[1] = byte_offset;
-    let page_refs: String = (0..total_pages).map(|i| format!("{} 0 R", (i * 3) + 3)).collect::<Vec<_>>().join(" ");
-    let pages_root = format!("2 0 obj\n<< /Type /Pages /Kids [ {} ] /Count {} >>\nendobj\n", page_refs, total_pages).into_bytes();
-    writer.write_all(&pages_root)?;
-    byte_offset += pages_root.len() as u64;
-
-    // --- Write all the page chunks : cannot be parallelised ---
-    for (i, chunk) in page_chunks.iter().enumerate() {
-        let page_obj_id_idx = (i * 3) + 2;
-
-        xref_offsets[page_obj_id_idx] = byte_offset;
-        writer.write_all(&chunk.page_object)?;
-        byte_offset += chunk.page_object.len() as u64;
-
-        xref_offsets[page_obj_id_idx + 1] = byte_offset;
-        writer.write_all(&chunk.contents_object)?;
-        byte_offset += chunk.contents_object.len() as u64;
-
-        xref_offsets[page_obj_id_idx + 2] = byte_offset;
-        writer.write_all(&chunk.image_object)?;
-        byte_offset += chunk.image_object.len() as u64;
-    }
-
-    // --- Write Cross-Reference Table and Trailer ---
-    let xref_start_offset = byte_offset;
-    writer.write_all(b"xref\n")?;
-    writer.write_all(format!("0 {}\n", xref_offsets.len() + 1).as_bytes())?;
-    writer.write_all(b"0000000000 65535 f \n")?; // XRef entry for object 0
-    for offset in &xref_offsets {
-        writer.write_all(format!("{:010} 00000 n \n", offset).as_bytes())?;
-    }
-
-    writer.write_all(b"trailer\n")?;
-    writer.write_all(format!("<< /Size {} /Root 1 0 R >>\n", xref_offsets.len() + 1).as_bytes())?;
-    writer.write_all(b"startxref\n")?;
-    writer.write_all(format!("{}\n", xref_start_offset).as_bytes())?;
-    writer.write_all(b"%%EOF\n")?;
+/// A rough size of the PDF of `notebook`: the RGB pixels of all its pages, times `compression_ratio`.
+fn estimate_output_bytes(notebook: &Notebook, compression_ratio: f32) -> u64 {
+    let raw_bytes: f64 = notebook
+        .pages
+        .iter()
+        .map(|page| notebook.page_size(page))
+        .map(|(width, height)| width as f64 * height as f64 * 3.0)
+        .sum();
+    (raw_bytes * compression_ratio as f64) as u64
+}
 
-    writer.flush()?;
+/// Totals of the files converted from one kind of device, for `--stats`.
+#[derive(Default)]
+struct DeviceStats {
+    files: usize,
+    pages: usize,
+    output_bytes: u64,
+    total_ms: u128,
+}
 
-    Ok(())
+/// Prints, for `--stats`, one line per detected device model (A5X or A5X2) with the totals of its converted files, such as
+/// `A5X: 24 files, 312 pages, 1.12 GiB output, avg 4.2s/file`. Failed files are left out.
+fn print_device_stats(stats: &[FileStats]) {
+    let mut devices: BTreeMap<&str, DeviceStats> = BTreeMap::new();
+    for row in stats {
+        let Ok(summary) = &row.result else { continue };
+        let totals = devices.entry(summary.notebook.header.device_model()).or_default();
+        totals.files += 1;
+        totals.pages += summary.pages_written;
+        totals.output_bytes += row.output_bytes;
+        totals.total_ms += row.total_ms;
+    }
+    for (device, totals) in devices {
+        eprintln!(
+            "{}: {} files, {} pages, {} output, avg {:.1}s/file",
+            device,
+            totals.files,
+            totals.pages,
+            HumanBytes(totals.output_bytes),
+            totals.total_ms as f64 / totals.files as f64 / 1000.0
+        );
+    }
 }
 
-fn process_single_file(input_file: &Path, output_file: &Path) -> Result<()> {
-    if input_file.extension().map_or(true, |s| s != "note") {
+fn process_single_file(input_file: &Path, output_file: &Path, run: &Run) -> Result<()> {
+    if input_file.extension().is_none_or(|s| s != "note") {
         bail!("Input file '{}' must have a .note extension.", input_file.display());
     }
     if output_file.is_dir() {
@@ -476,25 +1246,37 @@ fn process_single_file(input_file: &Path, output_file: &Path) -> Result<()> {
             output_file.display()
         );
     }
-    if output_file.extension().map_or(true, |s| s != "pdf") {
+    if output_file.extension().is_none_or(|s| s != "pdf") {
         bail!("Output file '{}' must have a .pdf extension.", output_file.display());
     }
+    if run.on_conflict == OnConflict::Skip && output_exists(output_file) {
+        eprintln!("Skipping '{}': '{}' already exists.", input_file.display(), output_file.display());
+        return Ok(());
+    }
     if output_file.exists() {
         bail!(
             "Output file '{}' already exists. Please remove it or choose a different name.",
             output_file.display()
         );
     }
+    if run.dry_run {
+        print_dry_run(&[(input_file.to_path_buf(), output_file.to_path_buf())], run);
+        return Ok(());
+    }
 
-    println!("Converting single file...");
+    eprintln!("Converting single file...");
     let start = Instant::now();
-    let pb = ProgressBar::new_spinner();
+    // The conversion sets the length once it knows how many pages it renders.
+    let pb = run.progress_bar(None, "{bar:40} {pos}/{len} pages {msg}");
     pb.set_message(format!("Converting {}...", input_file.display()));
 
-    convert_note_to_pdf(input_file, output_file)?;
+    if convert_file(input_file, output_file, run, Some(&pb))?.is_none() {
+        pb.finish_and_clear();
+        return Ok(());
+    }
 
     pb.finish_with_message("Conversion complete!");
-    println!(
+    eprintln!(
         "Successfully converted '{}' to '{}' in {:?}",
         input_file.display(),
         output_file.display(),
@@ -504,7 +1286,7 @@ fn process_single_file(input_file: &Path, output_file: &Path) -> Result<()> {
     Ok(())
 }
 
-fn process_directory(input_dir: &Path, output_dir: &Path) -> Result<()> {
+fn process_directory(input_dir: &Path, output_dir: &Path, run: &Run) -> Result<()> {
     if output_dir.is_file() {
         bail!(
             "Input is a directory, but output '{}' is a file. Please specify an output directory.",
@@ -512,71 +1294,711 @@ fn process_directory(input_dir: &Path, output_dir: &Path) -> Result<()> {
         );
     }
 
-    if output_dir.exists() {
+    // When skipping existing outputs, the output directory is expected to hold PDFs from an earlier run.
+    if output_dir.exists() && run.on_conflict == OnConflict::Error {
         bail!(
             "Output directory '{}' already exists. Please remove it or choose a different directory to prevent data loss.",
             output_dir.display()
         );
     }
 
-    println!("Scanning for .note files in '{}'...", input_dir.display());
-    let jobs: Vec<(PathBuf, PathBuf)> = WalkDir::new(input_dir)
+    eprintln!("Scanning for .note files in '{}'...", input_dir.display());
+    let mut walker = WalkDir::new(input_dir).follow_links(run.follow_symlinks);
+    if let Some(max_depth) = run.max_depth {
+        walker = walker.max_depth(max_depth);
+    }
+    let mut ignore_files = IgnoreFiles::new(input_dir);
+    let jobs: Vec<(PathBuf, PathBuf)> = walker
         .into_iter()
-        .filter_map(Result::ok) // Ignore errors during walk
-        .filter(|e| e.file_type().is_file() && e.path().extension().map_or(false, |s| s == "note"))
+        .filter_entry(|entry| !run.use_ignore_files || !ignore_files.is_ignored(entry.path(), entry.file_type().is_dir()))
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                // Other errors during the walk are ignored, but a symlink loop is worth knowing about.
+                if let (Some(path), Some(ancestor)) = (e.path(), e.loop_ancestor()) {
+                    eprintln!("Warning: skipping '{}', a link back to '{}'.", path.display(), ancestor.display());
+                }
+                None
+            }
+        })
+        .filter(|e| e.file_type().is_file() && e.path().extension().is_some_and(|s| s == "note"))
         .map(|entry| {
             let input_path = entry.into_path();
-            // Create the corresponding output path by mirroring the directory structure
             let relative_path = input_path.strip_prefix(input_dir).expect("Path from WalkDir should have a known prefix");
-            let mut output_path = output_dir.join(relative_path);
+            let mut output_path = match run.dir_structure {
+                // Create the corresponding output path by mirroring the directory structure
+                DirStructure::Mirror => output_dir.join(relative_path),
+                DirStructure::Flat => output_dir.join(flat_file_name(relative_path)),
+            };
             output_path.set_extension("pdf");
             (input_path, output_path)
         })
         .collect();
+    let jobs = if run.dir_structure == DirStructure::Flat {
+        deduplicate_outputs(jobs)
+    } else {
+        jobs
+    };
+
+    if jobs.is_empty() {
+        eprintln!("No .note files found. Exiting.");
+        return Ok(());
+    }
 
+    let (skipped, jobs): (Vec<_>, Vec<_>) = jobs.into_iter().partition(|(input_path, output_path)| {
+        run.on_conflict == OnConflict::Skip && has_existing_output(input_path, output_path, input_dir, output_dir)
+    });
     if jobs.is_empty() {
-        println!("No .note files found. Exiting.");
+        eprintln!("All {} files already have a PDF in '{}'. Exiting.", skipped.len(), output_dir.display());
+        return Ok(());
+    }
+
+    if run.dry_run {
+        if !skipped.is_empty() {
+            eprintln!("Would skip {} files that already have a PDF.", skipped.len());
+        }
+        print_dry_run(&jobs, run);
         return Ok(());
     }
 
     let num_jobs = jobs.len();
-    println!("Found {} files to convert. Starting conversion...", num_jobs);
+    match skipped.len() {
+        0 => eprintln!("Found {} files to convert. Starting conversion...", num_jobs),
+        n => eprintln!(
+            "Found {} files to convert, skipping {} that already have a PDF. Starting conversion...",
+            num_jobs, n
+        ),
+    }
     let start = Instant::now();
 
-    let pb = ProgressBar::new(num_jobs as u64);
+    // The bar's message counts files itself, once there are estimates to show with the count.
+    let pb = run.progress_bar(Some(num_jobs as u64), "{bar:40} {msg}");
+    let estimate = Mutex::new(BatchEstimate::new(num_jobs));
+    let failed = AtomicUsize::new(0);
     jobs.into_par_iter().for_each(|(input_path, output_path)| {
         let file_name = input_path.file_name().unwrap_or_default().to_string_lossy();
-        pb.set_message(format!("Converting {}...", file_name));
+        if run.progress {
+            // Once a file is done, the message shows the estimates instead.
+            if estimate.lock().unwrap().files_done == 0 {
+                pb.set_message(format!("Converting {}...", file_name));
+            }
+        } else {
+            eprintln!("Converting {}...", input_path.display());
+        }
         if let Some(parent) = output_path.parent() {
             fs::create_dir_all(parent).expect("Failed to create output subdirectory");
         }
 
-        if let Err(e) = convert_note_to_pdf(&input_path, &output_path) {
-            pb.println(format!("Failed to convert '{}': {}", input_path.display(), e));
-        }
+        let converted = match convert_file(&input_path, &output_path, run, None) {
+            Ok(converted) => converted,
+            Err(e) => {
+                run.status(&pb, format!("Failed to convert '{}': {}", input_path.display(), e));
+                failed.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        };
+        let mut estimate = estimate.lock().unwrap();
+        estimate.record(converted);
         pb.inc(1);
+        pb.set_message(estimate.message(&file_name));
     });
 
     pb.finish_with_message("All files converted!");
-    println!("Converted {} files in {:?}", num_jobs, start.elapsed());
+    eprintln!(
+        "Converted {} files, skipped {} already-existing outputs, failed {} in {:?}",
+        estimate.into_inner().unwrap().files_converted,
+        skipped.len(),
+        failed.into_inner(),
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+/// Whether a notebook being converted from `input_dir` already has a PDF in `output_dir`: at `output_path`, or, if
+/// the output is laid out differently (as with `--dir-structure flat`), where the mirrored layout would put it.
+fn has_existing_output(input_path: &Path, output_path: &Path, input_dir: &Path, output_dir: &Path) -> bool {
+    let relative_path = input_path.strip_prefix(input_dir).expect("Path from WalkDir should have a known prefix");
+    output_exists(output_path) || output_exists(&output_dir.join(relative_path).with_extension("pdf"))
+}
+
+/// Whether the PDF for `output_path` was written before: the file itself, or the first part it was split into
+/// (`name_001.pdf`, or `name_chapter_01_<title>.pdf` with `--auto-split-pdf`).
+fn output_exists(output_path: &Path) -> bool {
+    let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+    if output_path.exists() || output_path.with_file_name(format!("{}_001.pdf", stem)).exists() {
+        return true;
+    }
+    // Chapter parts are named after titles that aren't known until the notebook is read.
+    let chapter_prefix = format!("{}_chapter_01_", stem);
+    let dir = output_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::read_dir(dir).into_iter().flatten().flatten().any(|entry| {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        name.starts_with(&chapter_prefix) && name.ends_with(".pdf")
+    })
+}
+
+/// Running totals of a directory conversion, for the speed, time left and output size shown by its progress bar.
+struct BatchEstimate {
+    start: Instant,
+    num_jobs: usize,
+    files_done: usize,
+    /// Files that were converted rather than skipped or failed, and the pages and bytes they wrote.
+    files_converted: usize,
+    pages: usize,
+    output_bytes: u64,
+}
+
+impl BatchEstimate {
+    fn new(num_jobs: usize) -> Self {
+        BatchEstimate {
+            start: Instant::now(),
+            num_jobs,
+            files_done: 0,
+            files_converted: 0,
+            pages: 0,
+            output_bytes: 0,
+        }
+    }
+
+    fn record(&mut self, converted: Option<ConvertedFile>) {
+        self.files_done += 1;
+        if let Some(converted) = converted {
+            self.files_converted += 1;
+            self.pages += converted.pages;
+            self.output_bytes += converted.output_bytes;
+        }
+    }
+
+    /// `[3/42] work.note – 12.5 pages/s – ETA 2m 14s – Est. output: 128.00 MiB`, after `file_name` finished.
+    ///
+    /// The time left assumes the remaining files take as long as the mean file so far (counting the parallelism),
+    /// and the output size that they write as much as the mean converted file, since their page counts aren't known
+    /// until they are parsed.
+    fn message(&self, file_name: &str) -> String {
+        let elapsed = self.start.elapsed();
+        let remaining = self.num_jobs - self.files_done;
+        let eta = elapsed.mul_f64(remaining as f64 / self.files_done as f64);
+        let mut message = format!(
+            "[{}/{}] {} – {:.1} pages/s – ETA {}",
+            self.files_done,
+            self.num_jobs,
+            file_name,
+            self.pages as f64 / elapsed.as_secs_f64(),
+            format_eta(eta)
+        );
+        if self.files_converted > 0 {
+            let estimated_bytes = self.output_bytes + self.output_bytes / self.files_converted as u64 * remaining as u64;
+            message.push_str(&format!(" – Est. output: {}", HumanBytes(estimated_bytes)));
+        }
+        message
+    }
+}
+
+/// A time left such as `1h 5m`, `2m 14s` or `9s`.
+fn format_eta(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, _) => format!("{}h {}m", h, m),
+    }
+}
+
+/// Names a file from its path relative to the input directory for `--dir-structure flat`, joining the directories
+/// and file name with underscores: `notebooks/daily/2024/jan.note` becomes `notebooks_daily_2024_jan.note`.
+fn flat_file_name(relative_path: &Path) -> String {
+    relative_path.components().map(|c| c.as_os_str().to_string_lossy()).join("_")
+}
+
+/// Flattened names can collide (`a/b_c.note` and `a_b/c.note` both become `a_b_c.pdf`), so later duplicates get a
+/// numbered suffix instead of overwriting earlier ones: `a_b_c_2.pdf`.
+fn deduplicate_outputs(jobs: Vec<(PathBuf, PathBuf)>) -> Vec<(PathBuf, PathBuf)> {
+    let mut taken: HashSet<PathBuf> = jobs.iter().map(|(_, output_path)| output_path.clone()).collect();
+    let mut seen = HashSet::new();
+    jobs.into_iter()
+        .map(|(input_path, output_path)| {
+            if seen.insert(output_path.clone()) {
+                return (input_path, output_path);
+            }
+            let stem = output_path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+            let renamed = (2..)
+                .map(|n| output_path.with_file_name(format!("{}_{}.pdf", stem, n)))
+                .find(|candidate| !taken.contains(candidate))
+                .expect("some numbered name is free");
+            eprintln!(
+                "Warning: '{}' would also be written to '{}'; writing it to '{}' instead.",
+                input_path.display(),
+                output_path.display(),
+                renamed.display()
+            );
+            taken.insert(renamed.clone());
+            (input_path, renamed)
+        })
+        .collect()
+}
+
+/// Converts a single input path, dispatching on whether it is a file or a directory.
+fn process_input(input: &Path, output: &Path, run: &Run) -> Result<()> {
+    if !input.exists() {
+        bail!("Input path '{}' does not exist.", input.display());
+    }
+
+    if input.is_dir() {
+        process_directory(input, output, run)?;
+    } else if input.is_file() {
+        process_single_file(input, output, run)?;
+    } else {
+        bail!("Input path '{}' is not a regular file or directory.", input.display());
+    }
+
+    Ok(())
+}
+
+/// Writes the side-by-side comparison of two notebooks for `--compare`.
+fn process_comparison(before: &Path, after: &Path, output_file: &Path, options: &ConvertOptions) -> Result<()> {
+    for input_file in [before, after] {
+        if !input_file.is_file() {
+            bail!("Input file '{}' does not exist.", input_file.display());
+        }
+    }
+    if output_file.extension().is_none_or(|s| s != "pdf") {
+        bail!("Output file '{}' must have a .pdf extension.", output_file.display());
+    }
+    if output_file.exists() {
+        bail!(
+            "Output file '{}' already exists. Please remove it or choose a different name.",
+            output_file.display()
+        );
+    }
+
+    eprintln!("Comparing '{}' with '{}'...", before.display(), after.display());
+    let start = Instant::now();
+    let pages = compare_notes_to_pdf(before, after, output_file, options)?;
+    eprintln!("Wrote {} compared pages to '{}' in {:?}", pages, output_file.display(), start.elapsed());
+    Ok(())
+}
+
+/// Writes the merged PDF of several notebooks for `--merge`.
+fn process_merge(input_files: &[PathBuf], output_file: &Path, options: &ConvertOptions) -> Result<()> {
+    for input_file in input_files {
+        if !input_file.is_file() {
+            bail!("Input file '{}' does not exist.", input_file.display());
+        }
+    }
+    if output_file.extension().is_none_or(|s| s != "pdf") {
+        bail!("Output file '{}' must have a .pdf extension.", output_file.display());
+    }
+    if output_file.exists() {
+        bail!(
+            "Output file '{}' already exists. Please remove it or choose a different name.",
+            output_file.display()
+        );
+    }
+
+    eprintln!("Merging {} notebooks...", input_files.len());
+    let start = Instant::now();
+    let manifest = merge_notes_to_pdf(input_files, output_file, options)?;
+    for source in &manifest.sources {
+        match source.pages.len() {
+            0 => eprintln!("  '{}': no pages", source.path.display()),
+            _ => eprintln!("  '{}': pages {}-{}", source.path.display(), source.pages.start + 1, source.pages.end),
+        }
+    }
+    let pages = manifest.sources.last().map_or(0, |source| source.pages.end);
+    eprintln!("Wrote {} merged pages to '{}' in {:?}", pages, output_file.display(), start.elapsed());
+    Ok(())
+}
+
+/// Converts several inputs into one output directory.
+/// Files become `<output_dir>/<stem>.pdf`, directories are mirrored into `<output_dir>/<dir name>/`.
+/// Each input is processed independently, so one failure does not stop the rest.
+fn process_multiple_inputs(inputs: &[PathBuf], output_dir: &Path, run: &Run) -> Result<()> {
+    if output_dir.is_file() {
+        bail!(
+            "Multiple inputs were given, but output '{}' is a file. Please specify an output directory.",
+            output_dir.display()
+        );
+    }
+    if !run.dry_run {
+        fs::create_dir_all(output_dir)?;
+    }
+
+    let mut failures = 0;
+    for input in inputs {
+        let Some(name) = input.file_name() else {
+            eprintln!("Skipping '{}': cannot determine an output name.", input.display());
+            failures += 1;
+            continue;
+        };
+        let mut output = output_dir.join(name);
+        if !input.is_dir() {
+            output.set_extension("pdf");
+        }
+
+        if let Err(e) = process_input(input, &output, run) {
+            eprintln!("Failed to convert '{}': {}", input.display(), e);
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} of {} inputs failed to convert.", failures, inputs.len());
+    }
 
     Ok(())
 }
 
+/// Files one worker thread may have open at once: the notebook it is reading, the PDF it is writing and a cache entry.
+const FDS_PER_THREAD: usize = 3;
+/// Files open however many threads there are: stdin/stdout/stderr, the dedup index, the stats file, and some slack.
+const RESERVED_FDS: usize = 16;
+
+/// The most worker threads that keep the number of open files within `fd_limit`.
+fn threads_for_fd_limit(fd_limit: usize) -> usize {
+    (fd_limit.saturating_sub(RESERVED_FDS) / FDS_PER_THREAD).max(1)
+}
+
+/// Raises the soft limit on open files to what the thread pool may need, as far as the hard limit allows.
+/// Batches still run when that isn't enough, with a warning, since most notebooks don't all open at once.
+fn ensure_fd_limit() {
+    let needed = (rayon::current_num_threads() * FDS_PER_THREAD + RESERVED_FDS) as u64;
+    match rlimit::increase_nofile_limit(needed) {
+        Ok(limit) if limit < needed => eprintln!(
+            "Warning: up to {} files may be open at once, but the limit is {}; raise `ulimit -n` or use --fd-limit to run fewer conversions at once.",
+            needed, limit
+        ),
+        Ok(_) => {}
+        Err(e) => eprintln!("Warning: could not check the open file limit: {}", e),
+    }
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    if !cli.input.exists() {
-        bail!("Input path '{}' does not exist.", cli.input.display());
+    if cli.no_rayon {
+        // The main thread becomes the pool's only worker, so parallel iterators run on it in order.
+        rayon::ThreadPoolBuilder::new().num_threads(1).use_current_thread().build_global()?;
+    } else if cli.deterministic {
+        rayon::ThreadPoolBuilder::new().num_threads(1).build_global()?;
+    } else if let Some(fd_limit) = cli.fd_limit {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads_for_fd_limit(fd_limit.get()))
+            .build_global()?;
+    }
+
+    if let Some(shell) = cli.generate_completion {
+        clap_complete::generate(shell, &mut Cli::command(), "supernote_pdf", &mut std::io::stdout());
+        return Ok(());
+    }
+
+    if cli.generate_man {
+        return print_man_page();
+    }
+
+    if let Some(input_file) = &cli.layer_info {
+        return print_layer_info(input_file, cli.format);
+    }
+    if let Some(input_file) = &cli.device_dump {
+        return print_device_dump(input_file, cli.format);
+    }
+    if let Some(broken_file) = &cli.repair {
+        let output_file = cli.output.as_deref().context("--repair needs --output for the repaired PDF")?;
+        return repair_file(broken_file, output_file);
+    }
+
+    if !cfg!(feature = "fonts") && (cli.cover_page || cli.embed_fonts) {
+        bail!("--cover-page and --embed-fonts are not compiled into this build; reinstall with `cargo install supernote_pdf --features fonts`");
     }
 
-    if cli.input.is_dir() {
-        process_directory(&cli.input, &cli.output)?;
-    } else if cli.input.is_file() {
-        process_single_file(&cli.input, &cli.output)?;
+    let color_map = match &cli.color_map {
+        Some(path) => ColorMap::from_toml_file(path)?,
+        None => ColorMap::default(),
+    };
+    let options = ConvertOptions {
+        skip_blank_pages: cli.skip_blank_pages,
+        color_map,
+        strict: cli.strict,
+        starred_only: cli.starred_only,
+        exclude_starred: cli.exclude_starred,
+        excluded_pages: cli.exclude_pages.unwrap_or_default(),
+        sidecar: cli.sidecar,
+        embed_fonts: cli.embed_fonts,
+        compress_metadata: cli.compress_metadata,
+        thumbnail_width: cli.extract_thumbnails.then_some(cli.thumbnail_size),
+        pdf_layers: cli.pdf_layers,
+        layer_order: cli.layer_order,
+        high_contrast: cli.high_contrast,
+        invert: cli.invert,
+        ink_tint: cli.ink_tint,
+        bg_color: cli.bg_color,
+        scale: cli.scale,
+        max_output_size: cli.max_output_size,
+        pages_per_file: cli.pages_per_file.map(NonZeroUsize::get),
+        auto_split_chapters: cli.auto_split_pdf,
+        crop_to_ink: cli.crop_to_ink,
+        crop_margin: match (cli.crop_margin, cli.crop_margin_pct) {
+            (_, Some(pct)) => CropMargin::Percent(pct),
+            (Some(n), None) => CropMargin::Pixels(n),
+            (None, None) => CropMargin::default(),
+        },
+        dpi: cli.dpi,
+        cache_dir: cli.cache_dir,
+        all_errors: cli.all_errors,
+        page_label_offset: cli.page_label_offset.map(NonZeroUsize::get),
+        page_label_style: cli.page_label_style.map(|style| match style {
+            LabelStyle::Decimal => PageLabelStyle::Decimal,
+            LabelStyle::Roman => PageLabelStyle::LowerRoman,
+        }),
+        viewer: ViewerPreferences {
+            fit_window: cli.viewer_fit_window,
+            two_page: cli.viewer_two_page,
+            full_screen: cli.viewer_fullscreen,
+        },
+        producer: cli.producer,
+        creator: cli.creator,
+        author: cli.author,
+        pdf_ua: cli.pdf_ua,
+        lang: cli.lang.or_else(system_language),
+        page_rotations: cli.rotate_page.unwrap_or_default(),
+        cover_page: cli.cover_page,
+        page_format: match cli.page_format {
+            PageFormatArg::Auto => PageFormat::Auto,
+            PageFormatArg::Jpeg => PageFormat::Fixed(ImageEncoding::Jpeg),
+            PageFormatArg::Flate => PageFormat::Fixed(ImageEncoding::Flate),
+            PageFormatArg::Ccitt => PageFormat::Fixed(ImageEncoding::Ccitt),
+        },
+        zlib_level: cli.zlib_level,
+        pad_to_count: cli.pad_to_count,
+        layer_renderer: None,
+        hook: Some(Arc::new(PrintWarnings)),
+        page_progress: None,
+        print_layers: cli.print_layers,
+    };
+
+    if cli.serve {
+        return server::serve(SocketAddr::from((Ipv4Addr::LOCALHOST, cli.port)), options);
+    }
+
+    if cli.compress_only {
+        // Not a clap conflict: clap stops enforcing `requires = "compress_only"` when a conflicting argument is given.
+        if cli.output.is_some() {
+            bail!("--compress-only doesn't write any files; leave out --output");
+        }
+        return print_compression(&cli.input, cli.compression_method, cli.compression_level, &options);
+    }
+
+    let output = cli.output.as_deref().expect("clap requires --output unless an info mode is used");
+    // Files for an S3 output are written to a local staging directory first, then uploaded one by one.
+    let (s3, output) = if is_s3_url(output) {
+        let (s3, local_output) = S3Output::connect(&output.to_string_lossy())?;
+        (Some(s3), local_output)
     } else {
-        bail!("Input path '{}' is not a regular file or directory.", cli.input.display());
+        (None, output.to_path_buf())
+    };
+
+    if let Some([before, after]) = cli.compare.as_deref() {
+        process_comparison(before, after, &output, &options)?;
+        if let Some(s3) = &s3 {
+            eprintln!("Uploaded {}", s3.upload(&output)?);
+        }
+        return Ok(());
+    }
+    if let Some(input_files) = &cli.merge {
+        process_merge(input_files, &output, &options)?;
+        if let Some(s3) = &s3 {
+            eprintln!("Uploaded {}", s3.upload(&output)?);
+        }
+        return Ok(());
     }
 
-    Ok(())
+    let dedup = if cli.dedup_check {
+        let cache_dir = match &options.cache_dir {
+            Some(dir) => dir.clone(),
+            None => default_cache_dir().context("cannot locate a cache directory for --dedup-check; set --cache-dir")?,
+        };
+        Some(DedupIndex::load(cache_dir.join("hashes.db"))?)
+    } else {
+        None
+    };
+    ensure_fd_limit();
+    let run = Run {
+        options,
+        stats: Mutex::new(Vec::new()),
+        dedup,
+        progress: !cli.no_progress && std::io::stderr().is_terminal(),
+        retries: cli.retry,
+        retry_delay: Duration::from_millis(cli.retry_delay),
+        follow_symlinks: cli.follow_symlinks,
+        max_depth: cli.max_depth,
+        dir_structure: cli.dir_structure,
+        on_conflict: if cli.skip_if_exists { OnConflict::Skip } else { cli.on_conflict },
+        use_ignore_files: !cli.no_ignore,
+        version_check: cli.version_check,
+        verify: cli.verify,
+        preserve_timestamps: cli.preserve_timestamps,
+        dry_run: cli.dry_run,
+        estimated_compression_ratio: cli.estimated_compression_ratio,
+        s3,
+    };
+
+    let result = if let [input] = cli.input.as_slice() {
+        process_input(input, &output, &run)
+    } else {
+        process_multiple_inputs(&cli.input, &output, &run)
+    };
+
+    // Written even when some conversions failed, since that is when the summary is most useful.
+    if let (Some(dedup), false) = (&run.dedup, run.dry_run) {
+        dedup.save()?;
+    }
+    let stats = run.stats.into_inner().unwrap();
+    if cli.stats {
+        print_device_stats(&stats);
+    }
+    if let Some(stats_path) = &cli.stats_output {
+        write_stats_csv(stats_path, &stats)?;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn language_tags_are_parsed() {
+        assert_eq!(parse_language_tag("ja").unwrap(), "ja");
+        assert_eq!(parse_language_tag("en-US").unwrap(), "en-US");
+        assert_eq!(parse_language_tag(" pt_BR ").unwrap(), "pt-BR");
+        assert_eq!(parse_language_tag("zh-Hant-TW").unwrap(), "zh-Hant-TW");
+        for invalid in ["", "-", "en-", "en--US", "en US", "de.UTF-8", "fr@euro"] {
+            assert!(parse_language_tag(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn locale_names_give_their_language() {
+        assert_eq!(locale_language("de_DE.UTF-8@euro").as_deref(), Some("de-DE"));
+        assert_eq!(locale_language("ja_JP.eucJP").as_deref(), Some("ja-JP"));
+        assert_eq!(locale_language("en").as_deref(), Some("en"));
+        assert_eq!(locale_language("C"), None);
+        assert_eq!(locale_language("C.UTF-8"), None);
+        assert_eq!(locale_language("POSIX"), None);
+        assert_eq!(locale_language(""), None);
+    }
+
+    #[test]
+    fn page_rotations_are_parsed() {
+        let rotations = parse_page_rotations("1:90, 3:180,5-7:270,,2:0").unwrap();
+        assert_eq!(rotations, HashMap::from([(1, 90), (2, 0), (3, 180), (5, 270), (6, 270), (7, 270)]));
+        // A later entry overrides an earlier one.
+        assert_eq!(parse_page_rotations("1-3:90,2:180").unwrap(), HashMap::from([(1, 90), (2, 180), (3, 90)]));
+        assert!(parse_page_rotations("").unwrap().is_empty());
+
+        for invalid_angle in ["1:45", "1:360", "1:-90", "1:450", "1:ninety", "1:"] {
+            let error = parse_page_rotations(invalid_angle).unwrap_err();
+            assert!(error.contains("0, 90, 180 or 270"), "{:?} gave {:?}", invalid_angle, error);
+        }
+        for invalid_pages in ["7-5:90", "0:90", "0-2:90", "a:90", "1-:90", ":90", "1", "1-3"] {
+            let error = parse_page_rotations(invalid_pages).unwrap_err();
+            assert!(error.contains("like 3:90"), "{:?} gave {:?}", invalid_pages, error);
+        }
+    }
+
+    #[test]
+    fn page_sets_are_parsed() {
+        assert_eq!(parse_page_set("1,5-7,10").unwrap(), BTreeSet::from([1, 5, 6, 7, 10]));
+        assert_eq!(parse_page_set(" 3 , 3, 2-3 ").unwrap(), BTreeSet::from([2, 3]));
+        assert_eq!(parse_page_set("4-4").unwrap(), BTreeSet::from([4]));
+        assert!(parse_page_set("").unwrap().is_empty());
+        for invalid in ["7-5", "0", "0-3", "-3", "1-", "1-2-3", "a", "1;2", "2.5"] {
+            assert!(parse_page_set(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn sizes_are_parsed() {
+        let cases = [
+            ("500", 500),
+            ("12B", 12),
+            ("500KB", 500 * 1024),
+            ("500k", 500 * 1024),
+            (" 10 MB ", 10 * 1024 * 1024),
+            ("2G", 2 * 1024 * 1024 * 1024),
+        ];
+        for (size, bytes) in cases {
+            assert_eq!(parse_size(size), Ok(bytes), "{:?}", size);
+        }
+        for invalid in ["", "0", "0MB", "MB", "1.5MB", "10TB", "-1", "10 M B", "99999999999GB"] {
+            assert!(parse_size(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn hex_colors_are_parsed() {
+        assert_eq!(parse_hex_color("#1a237e"), Ok(Rgba([0x1a, 0x23, 0x7e, 255])));
+        assert_eq!(parse_hex_color("FFFFFF"), Ok(Rgba([255, 255, 255, 255])));
+        assert_eq!(parse_bg_color("Transparent"), Ok(Rgba([0, 0, 0, 0])));
+        // "ééé" is six bytes long, but not hex.
+        for invalid in ["", "#", "#fff", "#1a237e0", "12345g", "##1a237e", "ééé", "transparent"] {
+            assert!(parse_hex_color(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn scales_and_percentages_are_parsed() {
+        assert_eq!(parse_scale("0.5"), Ok(0.5));
+        assert_eq!(parse_scale("300"), Ok(300.0));
+        for invalid in ["0", "-1", "inf", "NaN", "", "half"] {
+            assert!(parse_scale(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+
+        assert_eq!(parse_percent("0"), Ok(0.0));
+        assert_eq!(parse_percent("12.5%"), Ok(12.5));
+        assert_eq!(parse_percent(" 100% "), Ok(100.0));
+        for invalid in ["-1", "100.5", "150%", "", "%", "ten"] {
+            assert!(parse_percent(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn retry_calls_again_after_a_transient_error() {
+        let mut calls = 0;
+        let result = retry(3, Duration::ZERO, || {
+            calls += 1;
+            if calls < 3 {
+                Err(SupernoteError::IoError(io::Error::from(ErrorKind::Interrupted)))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn retry_gives_up_after_the_last_attempt() {
+        let mut calls = 0;
+        let result: supernote_pdf::Result<()> = retry(2, Duration::ZERO, || {
+            calls += 1;
+            Err(SupernoteError::IoError(io::Error::from(ErrorKind::NotFound)))
+        });
+        assert!(matches!(result, Err(SupernoteError::IoError(e)) if e.kind() == ErrorKind::NotFound));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn retry_returns_a_permanent_error_at_once() {
+        let mut calls = 0;
+        let result: supernote_pdf::Result<()> = retry(3, Duration::ZERO, || {
+            calls += 1;
+            Err(SupernoteError::IoError(io::Error::from(ErrorKind::AlreadyExists)))
+        });
+        assert!(matches!(result, Err(SupernoteError::IoError(e)) if e.kind() == ErrorKind::AlreadyExists));
+        assert_eq!(calls, 1);
+    }
 }
@@ -0,0 +1,625 @@
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Result, SupernoteError};
+
+const A5X_WIDTH: usize = 1404;
+const A5X_HEIGHT: usize = 1872;
+const A5X2_WIDTH: usize = 1920;
+const A5X2_HEIGHT: usize = 2560;
+/// Page dimensions of every supported device, as `(width, height)`.
+pub(crate) const DEVICE_DIMENSIONS: &[(usize, usize)] = &[(A5X_WIDTH, A5X_HEIGHT), (A5X2_WIDTH, A5X2_HEIGHT)];
+
+/// Screen pixel densities, used to give PDF pages the physical size of the device's screen.
+const A5X_DPI: f32 = 226.0;
+const A5X2_DPI: f32 = 300.0;
+
+/// Upper bound for a single metadata block.
+/// Metadata blocks are small text maps: the footer, the largest one, grows by roughly 20 bytes per page,
+/// so even notebooks with thousands of pages stay far below 1 MB. Anything above this limit is treated as a
+/// corrupt length rather than allocated.
+const MAX_METADATA_BLOCK_SIZE: usize = 10_000_000;
+
+/// Where a `.note` file keeps the fields that are read before any metadata block: its signature and the address
+/// of its footer.
+pub(crate) struct FileFormatConfig {
+    /// Byte offset of the signature, after the 4-byte file type (`note`).
+    pub signature_offset: u64,
+    /// Length of the signature in bytes, e.g. 20 for `SN_FILE_VER_20200001`.
+    pub signature_length: usize,
+    /// Size of the little-endian footer address at the very end of the file, in bytes (at most 8).
+    pub footer_addr_size: usize,
+}
+
+/// The layout of every format version in [`KNOWN_SIGNATURES`]. A format that moves these fields gets a config of
+/// its own.
+pub(crate) const CURRENT_FORMAT: FileFormatConfig = FileFormatConfig {
+    signature_offset: 4,
+    signature_length: 20,
+    footer_addr_size: 4,
+};
+
+/// Signatures written by Supernote firmware so far, as read by `get_signature`.
+/// Files start with a 4-byte type (`note`), so on disk these appear as e.g. `noteSN_FILE_VER_20200001`.
+pub const KNOWN_SIGNATURES: &[&str] = &[
+    "SN_FILE_VER_20200001",
+    "SN_FILE_VER_20200005",
+    "SN_FILE_VER_20200006",
+    "SN_FILE_VER_20200007",
+    "SN_FILE_VER_20200008",
+    "SN_FILE_VER_20210009",
+    "SN_FILE_VER_20210010",
+    "SN_FILE_VER_20220011",
+    "SN_FILE_VER_20220013",
+    "SN_FILE_VER_20230014",
+    "SN_FILE_VER_20230015",
+];
+
+/// Signatures whose files are known to convert correctly, with the firmware that writes them.
+/// Signatures that aren't listed may still convert fine; they just haven't been checked.
+pub const KNOWN_GOOD_SIGNATURES: &[(&str, &str)] = &[
+    ("SN_FILE_VER_20200001", "Chauvet ≥2.x"),
+    ("SN_FILE_VER_20200005", "Chauvet ≥2.x"),
+    ("SN_FILE_VER_20200006", "Chauvet ≥2.x"),
+    ("SN_FILE_VER_20200007", "Chauvet ≥2.x"),
+    ("SN_FILE_VER_20200008", "Chauvet ≥2.x"),
+    ("SN_FILE_VER_20210009", "Chauvet ≥2.x"),
+    ("SN_FILE_VER_20210010", "Chauvet ≥2.x"),
+    ("SN_FILE_VER_20220011", "Chauvet ≥3.x"),
+    ("SN_FILE_VER_20220013", "Chauvet ≥3.x"),
+    ("SN_FILE_VER_20230014", "Chauvet ≥3.x"),
+    ("SN_FILE_VER_20230015", "Chauvet ≥3.x"),
+];
+
+#[derive(Debug, Serialize)]
+pub struct Notebook {
+    pub signature: String,
+    /// The file-level metadata from the `FILE_FEATURE` block.
+    pub header: FileHeader,
+    pub pages: Vec<Page>,
+    pub width: usize,
+    pub height: usize,
+    /// The file the notebook was read from, when it was opened with [`Notebook::from_path`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Problems that didn't stop the file from being read, such as metadata blocks that aren't valid UTF-8.
+    /// Conversions pass them to [`ConversionHook::on_warning`](crate::ConversionHook::on_warning).
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+/// Metadata from a notebook's `FILE_FEATURE` header block.
+#[derive(Debug, Default, Serialize)]
+pub struct FileHeader {
+    /// The device the notebook was created for (`APPLY_EQUIPMENT`), e.g. `N5` for the A5X2.
+    pub equipment: Option<String>,
+    /// Version of the software that wrote the file (`APP_VERSION`).
+    pub firmware_version: Option<String>,
+    /// The notebook's title (`FILE_TITLE`), if it has one.
+    pub note_title: Option<String>,
+    /// Every key-value pair in the header, including the ones above.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl FileHeader {
+    /// The device model detected from [`FileHeader::equipment`]: `A5X2` for `N5`, otherwise `A5X`.
+    pub fn device_model(&self) -> &'static str {
+        if self.equipment.as_deref() == Some("N5") { "A5X2" } else { "A5X" }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Page {
+    pub addr: u64,
+    pub layers: Vec<Layer>,
+    /// The page title (`PAGETITLE`), if one was set.
+    pub title: Option<String>,
+    /// Creation time (`CREATETIME`), in milliseconds since the Unix epoch.
+    pub created_at: Option<u64>,
+    /// Last modification time (`MODIFYTIME`), in milliseconds since the Unix epoch.
+    pub modified_at: Option<u64>,
+    /// Whether the page was starred on the device (`ISMARKED`).
+    pub is_starred: bool,
+    /// The page's own bitmap width (`PAGEWIDTH`), for pages sized differently from the rest of the notebook, such
+    /// as imported screenshots. Unset means [`Notebook::width`].
+    pub actual_width: Option<usize>,
+    /// The page's own bitmap height (`PAGEHEIGHT`). Unset means [`Notebook::height`].
+    pub actual_height: Option<usize>,
+    /// The page's template (`PAGESTYLE`): one of the device's own, like `style_white`, or `user_...` for a custom
+    /// template or an imported image.
+    pub template: Option<String>,
+}
+
+impl Page {
+    /// Whether the page's background is a custom template or an imported image rather than one of the device's own
+    /// templates, so that its background layer is content rather than stationery.
+    pub fn has_custom_template(&self) -> bool {
+        self.template.as_deref().is_some_and(|template| template.starts_with("user_"))
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Layer {
+    pub key: String,
+    pub protocol: String,
+    pub bitmap_address: u64,
+    /// Bits per pixel of the bitmap (`LAYERDEPTH`): 8, or 16 for layers from firmware that stores 16-bit pixels.
+    pub depth: u32,
+}
+
+fn get_signature(file: &mut File, config: &FileFormatConfig) -> Result<String> {
+    // The `?` operator is used here. If `File::open` returns an `Err`, the `?`
+    // will immediately stop this function and return that `Err` to the caller.
+    // If it returns `Ok(file)`, it unwraps the value and assigns it to `file`.
+
+    // Seek to the signature's starting position.
+    file.seek(SeekFrom::Start(config.signature_offset))?;
+
+    // Read the signature bytes.
+    let mut signature_bytes = vec![0; config.signature_length];
+    file.read_exact(&mut signature_bytes)?;
+
+    // Convert the bytes into a readable string. Older files may have anything here, including bytes that aren't text.
+    let signature_string = String::from_utf8_lossy(&signature_bytes).into_owned();
+
+    if is_legacy_signature(&signature_string) {
+        return Err(SupernoteError::UnsupportedDevice(format!(
+            "legacy note format (pre-2020, signature '{}') is not yet supported; please re-export from the device",
+            signature_string.escape_debug()
+        )));
+    }
+
+    Ok(signature_string)
+}
+
+/// Reads the signature of a `.note` file without parsing the rest of it.
+pub fn read_signature(path: &Path) -> Result<String> {
+    get_signature(&mut File::open(path)?, &CURRENT_FORMAT)
+}
+
+/// Whether a signature belongs to the format used before `SN_FILE_VER_20200001`, whose layout this crate
+/// doesn't know. Files in that format have no `SN_FILE_VER_` signature at all; current ones end theirs with a
+/// `YYYYNNNN` version, and any dated before 2020 is legacy too.
+fn is_legacy_signature(signature: &str) -> bool {
+    match signature.strip_prefix("SN_FILE_VER_") {
+        Some(version) => version.get(..4).and_then(|year| year.parse::<u32>().ok()).is_some_and(|year| year < 2020),
+        None => true,
+    }
+}
+
+/// Reads a metadata block at a given address and parses it into a HashMap.
+/// Metadata format is `<KEY1:VALUE1><KEY2:VALUE2>...`
+///
+/// Parsing keeps no shared state (there is no regex or other lazily built static behind it), so threads can parse
+/// blocks at the same time without contending, each through its own `File`.
+pub fn parse_metadata_block(file: &mut File, address: u64) -> Result<HashMap<String, String>> {
+    read_metadata_block(file, address, &mut Vec::new())
+}
+
+/// [`parse_metadata_block`], adding a message to `warnings` for a block that had to be decoded lossily.
+fn read_metadata_block(file: &mut File, address: u64, warnings: &mut Vec<String>) -> Result<HashMap<String, String>> {
+    if address == 0 {
+        let empty: HashMap<String, String> = HashMap::new();
+        return Ok(empty);
+    }
+
+    let file_len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(address))?;
+
+    // Read the 4-byte block length.
+    // A u32 always fits in usize on 32- and 64-bit targets, so the cast is lossless; the real risk is a
+    // garbage length asking for a multi-GB allocation, hence the limit.
+    let mut len_bytes = [0u8; 4];
+    file.read_exact(&mut len_bytes)?;
+    let block_len = u32::from_le_bytes(len_bytes) as usize;
+    if block_len > MAX_METADATA_BLOCK_SIZE {
+        return Err(SupernoteError::MalformedMetadata(format!(
+            "metadata block at {:#x} claims a size of {} bytes, more than the {} byte limit; the file is probably corrupt",
+            address, block_len, MAX_METADATA_BLOCK_SIZE
+        )));
+    }
+    check_block_bounds(file_len, address, block_len)?;
+
+    // Read the block content
+    let mut content_bytes = vec![0; block_len];
+    file.read_exact(&mut content_bytes)?;
+    // Older firmware may write Latin-1 text or leave binary junk at the end of a block. Decode lossily, so one odd
+    // byte in a title doesn't make the whole notebook unreadable; the affected characters become U+FFFD.
+    let content = match String::from_utf8(content_bytes) {
+        Ok(content) => content,
+        Err(e) => {
+            warnings.push(format!(
+                "metadata block at {:#x} is not valid UTF-8; invalid bytes were replaced.",
+                address
+            ));
+            String::from_utf8_lossy(e.as_bytes()).into_owned()
+        }
+    };
+
+    Ok(parse_metadata_entries(&content))
+}
+
+/// Parses `<KEY1:VALUE1><KEY2:VALUE2>...` into a map.
+/// Values are free text (e.g. page titles) and may themselves contain `<`, `>` or `:`, so a value only ends at a
+/// `>` that closes its own bracket and is followed by the next `<KEY:` or by the end of the block.
+fn parse_metadata_entries(content: &str) -> HashMap<String, String> {
+    let bytes = content.as_bytes();
+    let mut map = HashMap::new();
+    let mut pos = 0;
+
+    while let Some(offset) = content[pos..].find('<') {
+        let key_start = pos + offset + 1;
+        let Some(key_len) = metadata_key_len(bytes, key_start) else {
+            pos = key_start;
+            continue;
+        };
+        let value_start = key_start + key_len + 1;
+        let Some(value_end) = metadata_value_end(bytes, value_start) else {
+            break;
+        };
+
+        let key = content[key_start..key_start + key_len].to_string();
+        let value = content[value_start..value_end].to_string();
+        map.insert(key, value);
+        pos = value_end + 1;
+    }
+
+    map
+}
+
+/// Length of the `KEY` in a `KEY:` starting at `start`, or `None` if there is no well-formed key there.
+fn metadata_key_len(bytes: &[u8], start: usize) -> Option<usize> {
+    let len = bytes.get(start..)?.iter().position(|&c| matches!(c, b':' | b'<' | b'>'))?;
+    (len > 0 && bytes[start + len] == b':').then_some(len)
+}
+
+/// Whether position `i` is where a metadata entry may end: the end of the block or the start of the next `<KEY:`.
+fn is_entry_boundary(bytes: &[u8], i: usize) -> bool {
+    i == bytes.len() || (bytes[i] == b'<' && metadata_key_len(bytes, i + 1).is_some())
+}
+
+/// Finds the `>` that closes a value starting at `start`.
+fn metadata_value_end(bytes: &[u8], start: usize) -> Option<usize> {
+    // Track bracket depth, so nested `<...>` inside a value don't end it early.
+    let mut depth = 1;
+    for i in start..bytes.len() {
+        match bytes[i] {
+            b'<' => depth += 1,
+            b'>' if depth > 1 => depth -= 1,
+            b'>' if is_entry_boundary(bytes, i + 1) => return Some(i),
+            _ => {}
+        }
+    }
+
+    // The value has unbalanced brackets (e.g. a lone `<` in a title): fall back to the first `>` that is
+    // followed by another entry, or failing that the last `>` in the block.
+    (start..bytes.len())
+        .find(|&i| bytes[i] == b'>' && is_entry_boundary(bytes, i + 1))
+        .or_else(|| bytes.get(start..)?.iter().rposition(|&c| c == b'>').map(|p| start + p))
+}
+
+/// Reads the `FILE_FEATURE` header block and works out the page dimensions from the device it names.
+/// A missing header gives an empty [`FileHeader`] and A5X dimensions.
+fn parse_file_header(file: &mut File, footer_map: &HashMap<String, String>, warnings: &mut Vec<String>) -> Result<(FileHeader, usize, usize)> {
+    let mut header = FileHeader::default();
+    if let Some(header_addr_str) = footer_map.get("FILE_FEATURE")
+        && let Ok(header_addr) = header_addr_str.parse::<u64>()
+    {
+        let header_map = read_metadata_block(file, header_addr, warnings)?;
+        header.equipment = header_map.get("APPLY_EQUIPMENT").cloned();
+        header.firmware_version = header_map.get("APP_VERSION").cloned();
+        header.note_title = header_map.get("FILE_TITLE").filter(|title| !title.is_empty()).cloned();
+        header.fields = header_map.into_iter().collect();
+    }
+
+    if header.device_model() == "A5X2" {
+        Ok((header, A5X2_WIDTH, A5X2_HEIGHT))
+    } else {
+        Ok((header, A5X_WIDTH, A5X_HEIGHT))
+    }
+}
+
+/// The address of the footer metadata block, which the last bytes of the file hold (little-endian).
+pub(crate) fn footer_address(file: &mut File, config: &FileFormatConfig) -> Result<u64> {
+    file.seek(SeekFrom::End(-(config.footer_addr_size as i64)))?;
+    let mut addr_bytes = [0u8; 8];
+    file.read_exact(&mut addr_bytes[..config.footer_addr_size])?;
+    Ok(u64::from_le_bytes(addr_bytes))
+}
+
+pub fn parse_notebook(file: &mut File) -> Result<Notebook> {
+    let file_signature = get_signature(file, &CURRENT_FORMAT)?;
+
+    // Get footer address and map
+    let footer_addr = footer_address(file, &CURRENT_FORMAT)?;
+    let mut warnings = Vec::new();
+    let footer_map = read_metadata_block(file, footer_addr, &mut warnings)?;
+
+    // Read the file header, which also tells us the device and so the page dimensions
+    let (header, width, height) = parse_file_header(file, &footer_map, &mut warnings)?;
+
+    let page_addrs = page_addresses(&footer_map)?;
+
+    // let page_map = parse_metadata_block(&mut file, *page_addrs.get(0).unwrap());
+    // println!("{:?}", page_map);
+
+    let mut pages: Vec<Page> = Vec::new();
+    for (page_idx, addr) in page_addrs.into_iter().enumerate() {
+        let page_num = page_idx + 1;
+        let page_map = read_metadata_block(file, addr, &mut warnings).map_err(|e| e.in_page(page_num))?;
+        let layer_map = resolve_layer_map(file, &page_map, &mut warnings).map_err(|e| e.in_page(page_num))?;
+        let layer_order = layer_map
+            .get("LAYERSEQ")
+            .or_else(|| page_map.get("LAYERSEQ"))
+            .map(|s| s.split(',').map(String::from).collect())
+            .unwrap_or_else(|| {
+                // Default order if LAYERSEQ is missing
+                vec![
+                    "BGLAYER".to_string(),
+                    "MAINLAYER".to_string(),
+                    "LAYER1".to_string(),
+                    "LAYER2".to_string(),
+                    "LAYER3".to_string(),
+                    "HIGHLAYER".to_string(),
+                ]
+            });
+        let mut layers: Vec<Layer> = Vec::new();
+        for layer_key in layer_order.iter() {
+            // if page_map.contains_key(layer_key.as_str()) {
+            if let Some(addr_str) = layer_map.get(layer_key.as_str()) {
+                let layer_addr = parse_address(layer_key, addr_str).map_err(|e| e.in_layer(page_num, layer_key))?;
+                let data = read_metadata_block(file, layer_addr, &mut warnings).map_err(|e| e.in_layer(page_num, layer_key))?;
+                layers.push(Layer {
+                    key: layer_key.to_string(),
+                    protocol: data.get("LAYERPROTOCOL").cloned().unwrap_or_default(),
+                    bitmap_address: data.get("LAYERBITMAP").and_then(|s| s.parse::<u64>().ok()).unwrap_or(0),
+                    depth: data.get("LAYERDEPTH").and_then(|s| s.parse::<u32>().ok()).unwrap_or(8),
+                });
+            }
+        }
+        pages.push(Page {
+            addr,
+            layers,
+            title: page_map.get("PAGETITLE").filter(|t| !t.is_empty()).cloned(),
+            created_at: page_map.get("CREATETIME").and_then(|s| s.parse::<u64>().ok()),
+            modified_at: page_map.get("MODIFYTIME").and_then(|s| s.parse::<u64>().ok()),
+            is_starred: page_map.get("ISMARKED").is_some_and(|s| s == "1" || s == "true"),
+            actual_width: page_map.get("PAGEWIDTH").and_then(|s| s.parse::<usize>().ok()).filter(|&w| w > 0),
+            actual_height: page_map.get("PAGEHEIGHT").and_then(|s| s.parse::<usize>().ok()).filter(|&h| h > 0),
+            template: page_map.get("PAGESTYLE").filter(|t| !t.is_empty()).cloned(),
+        });
+    }
+
+    Ok(Notebook {
+        signature: file_signature,
+        header,
+        pages,
+        width,
+        height,
+        path: None,
+        warnings,
+    })
+}
+
+/// How many `PAGELAYER` references are followed from a page before giving up; real files use one.
+const MAX_PAGELAYER_DEPTH: usize = 8;
+
+/// The metadata listing a page's layers. Usually that is the page's own metadata, but newer firmware may instead
+/// give the page a `PAGELAYER` key pointing at a separate block with the layer keys (which may itself point further).
+fn resolve_layer_map(file: &mut File, page_map: &HashMap<String, String>, warnings: &mut Vec<String>) -> Result<HashMap<String, String>> {
+    let mut layer_map = page_map.clone();
+    for _ in 0..MAX_PAGELAYER_DEPTH {
+        let Some(value) = layer_map.get("PAGELAYER") else {
+            return Ok(layer_map);
+        };
+        let address = parse_address("PAGELAYER", value)?;
+        layer_map = read_metadata_block(file, address, warnings)?;
+    }
+    Err(SupernoteError::MalformedMetadata(format!(
+        "PAGELAYER references are nested more than {} deep; the file is probably corrupt",
+        MAX_PAGELAYER_DEPTH
+    )))
+}
+
+/// Fails if a block of `block_len` bytes, stored after its 4-byte length at `address`, would run past the end of the file.
+/// Checking this before allocating avoids both huge allocations and confusing EOF errors on truncated files.
+fn check_block_bounds(file_len: u64, address: u64, block_len: usize) -> Result<()> {
+    if address.saturating_add(4).saturating_add(block_len as u64) > file_len {
+        return Err(SupernoteError::MalformedMetadata(format!(
+            "block at {:#x} claims a size of {} bytes, which runs past the end of the file ({} bytes); the file is probably truncated or corrupt",
+            address, block_len, file_len
+        )));
+    }
+    Ok(())
+}
+
+/// The addresses of the page metadata blocks listed in the footer, in page order.
+/// Only `PAGE` followed by digits is a page; other footer keys like `PAGETABLE` share the prefix.
+fn page_addresses(footer_map: &HashMap<String, String>) -> Result<Vec<u64>> {
+    footer_map
+        .iter()
+        .filter_map(|(k, v)| page_number(k).map(|n| (n, k, v)))
+        .sorted_by_key(|(n, _k, _v)| *n)
+        .map(|(_n, k, v)| parse_address(k, v))
+        .collect()
+}
+
+/// The page number of a footer key of the form `PAGE<digits>`, e.g. 10 for `PAGE10`.
+fn page_number(key: &str) -> Option<u64> {
+    let digits = key.strip_prefix("PAGE")?;
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// Parses a metadata value holding a block address.
+fn parse_address(key: &str, value: &str) -> Result<u64> {
+    value
+        .parse::<u64>()
+        .map_err(|_| SupernoteError::MalformedMetadata(format!("{} has an invalid address '{}'", key, value)))
+}
+
+impl Notebook {
+    /// Opens and parses a `.note` file, remembering its path so pages can be read again later.
+    pub fn from_path(path: &Path) -> Result<Notebook> {
+        let mut file = File::open(path)?;
+        let mut notebook = parse_notebook(&mut file)?;
+        notebook.path = Some(path.to_path_buf());
+        Ok(notebook)
+    }
+
+    /// Whether the file's signature is one of [`KNOWN_SIGNATURES`].
+    pub fn has_known_signature(&self) -> bool {
+        KNOWN_SIGNATURES.contains(&self.signature.as_str())
+    }
+
+    /// The size of a page's bitmaps in pixels, as `(width, height)`: its own size if it has one, otherwise the
+    /// notebook's.
+    pub fn page_size(&self, page: &Page) -> (usize, usize) {
+        (page.actual_width.unwrap_or(self.width), page.actual_height.unwrap_or(self.height))
+    }
+
+    /// Pixels per inch of the screen the notebook was written on.
+    pub fn screen_dpi(&self) -> f32 {
+        if self.width == A5X2_WIDTH { A5X2_DPI } else { A5X_DPI }
+    }
+}
+
+/// Returns the size in bytes of the bitmap block at a given address, without reading the bitmap itself.
+/// A zero address (a layer without a bitmap) has size 0.
+pub fn bitmap_block_size(file: &mut File, address: u64) -> Result<u32> {
+    if address == 0 {
+        return Ok(0);
+    }
+
+    file.seek(SeekFrom::Start(address))?;
+    let mut len_bytes = [0u8; 4];
+    file.read_exact(&mut len_bytes)?;
+    Ok(u32::from_le_bytes(len_bytes))
+}
+
+/// Reads a length-prefixed data block (4-byte little-endian length, then the bytes) at a given address.
+pub(crate) fn read_block(file: &mut File, address: u64) -> Result<Vec<u8>> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(address))?;
+    let mut len_bytes = [0u8; 4];
+    file.read_exact(&mut len_bytes)?;
+    let block_len = u32::from_le_bytes(len_bytes) as usize;
+    check_block_bounds(file_len, address, block_len)?;
+    let mut data = vec![0; block_len];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn entries(content: &str) -> Vec<(String, String)> {
+        parse_metadata_entries(content).into_iter().sorted().collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn metadata_values_may_contain_brackets() {
+        assert_eq!(entries("<A:1><B:2><C:3>"), pairs(&[("A", "1"), ("B", "2"), ("C", "3")]));
+        assert_eq!(entries("<TITLE:a > b>"), pairs(&[("TITLE", "a > b")]));
+        assert_eq!(entries("<TITLE:a > b><PAGE:3>"), pairs(&[("PAGE", "3"), ("TITLE", "a > b")]));
+        assert_eq!(entries("<TITLE:<x>>"), pairs(&[("TITLE", "<x>")]));
+        assert_eq!(entries("<TITLE:<x>><PAGE:3>"), pairs(&[("PAGE", "3"), ("TITLE", "<x>")]));
+        assert_eq!(entries("<TITLE:1 < 2><PAGE:3>"), pairs(&[("PAGE", "3"), ("TITLE", "1 < 2")]));
+        assert_eq!(entries("<TITLE:a:b><PAGE:3>"), pairs(&[("PAGE", "3"), ("TITLE", "a:b")]));
+    }
+
+    #[test]
+    fn empty_and_unterminated_metadata_values() {
+        assert_eq!(entries("<TITLE:><PAGE:3>"), pairs(&[("PAGE", "3"), ("TITLE", "")]));
+        assert_eq!(entries("<PAGE:3><TITLE:>"), pairs(&[("PAGE", "3"), ("TITLE", "")]));
+        // An unterminated last value is dropped, and the entries before it are kept.
+        assert_eq!(entries("<PAGE:3><TITLE:abc"), pairs(&[("PAGE", "3")]));
+        assert_eq!(entries("<TITLE:abc"), pairs(&[]));
+        assert_eq!(entries(""), pairs(&[]));
+    }
+
+    /// A file that starts like a `.note` file with `signature`, followed by a footer that lists no pages.
+    fn note_with_signature(signature: &[u8]) -> tempfile::NamedTempFile {
+        let mut note = tempfile::NamedTempFile::new().unwrap();
+        let footer = b"<FILE_FEATURE:0>";
+        note.write_all(b"note").unwrap();
+        note.write_all(signature).unwrap();
+        note.write_all(&(footer.len() as u32).to_le_bytes()).unwrap();
+        note.write_all(footer).unwrap();
+        note.write_all(&(4 + signature.len() as u32).to_le_bytes()).unwrap();
+        note
+    }
+
+    #[test]
+    fn legacy_files_are_rejected_by_signature() {
+        for signature in [
+            &b"SN_FILE_VER_20190003"[..],
+            b"SN_FILE_ASA_20190003",
+            b"\x00\x01\x02\x03\xfe\xff\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+        ] {
+            let note = note_with_signature(signature);
+            match Notebook::from_path(note.path()) {
+                Err(SupernoteError::UnsupportedDevice(msg)) => {
+                    assert!(msg.starts_with("legacy note format (pre-2020"), "{}", msg);
+                    assert!(msg.ends_with("please re-export from the device"), "{}", msg);
+                }
+                other => panic!("{:?} was not rejected as legacy: {:?}", String::from_utf8_lossy(signature), other),
+            }
+        }
+    }
+
+    #[test]
+    fn current_and_newer_signatures_are_not_legacy() {
+        for signature in ["SN_FILE_VER_20200001", "SN_FILE_VER_20230015", "SN_FILE_VER_20990001"] {
+            let note = note_with_signature(signature.as_bytes());
+            assert_eq!(read_signature(note.path()).unwrap(), signature);
+            assert!(Notebook::from_path(note.path()).is_ok());
+        }
+    }
+
+    #[test]
+    fn page_addresses_are_in_numeric_order() {
+        let footer_map: HashMap<String, String> = [("PAGE10", "1000"), ("PAGETABLE", "7"), ("PAGE2", "200"), ("PAGE1", "100")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(page_addresses(&footer_map).unwrap(), vec![100, 200, 1000]);
+    }
+
+    #[test]
+    fn metadata_blocks_parse_on_many_threads_at_once() {
+        let content = "<MODULE_LABEL:SNFILE_FEATURE><FILE_TITLE:Meeting Notes><APPLY_EQUIPMENT:A5X>";
+        let mut note = tempfile::NamedTempFile::new().unwrap();
+        note.write_all(b"note").unwrap();
+        note.write_all(&(content.len() as u32).to_le_bytes()).unwrap();
+        note.write_all(content.as_bytes()).unwrap();
+        let expected = parse_metadata_block(&mut File::open(note.path()).unwrap(), 4).unwrap();
+        assert_eq!(expected.get("FILE_TITLE").map(String::as_str), Some("Meeting Notes"));
+
+        // All threads start parsing together, each through its own `File`.
+        let start = std::sync::Barrier::new(100);
+        std::thread::scope(|scope| {
+            for _ in 0..100 {
+                scope.spawn(|| {
+                    let mut file = File::open(note.path()).unwrap();
+                    start.wait();
+                    for _ in 0..100 {
+                        assert_eq!(parse_metadata_block(&mut file, 4).unwrap(), expected);
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,2341 @@
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{Pixel, Rgba, RgbaImage, imageops};
+use indicatif::ProgressBar;
+use itertools::{EitherOrBoth, Itertools};
+use rayon::prelude::*;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::iter;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::cache::PageCache;
+#[cfg(feature = "fonts")]
+use crate::cover::render_cover;
+use crate::decode::{
+    ColorMap, decode_lz4, decode_lzma, decode_rle_16bit_reporting, decode_rle_reporting, is_blank_layer, pixels_to_image, pixels16_to_image,
+    write_pixels, write_pixels16,
+};
+use crate::error::{Result, SupernoteError};
+#[cfg(feature = "fonts")]
+use crate::font::{TEXT_FONT, TEXT_FONT_NAME};
+use crate::hook::ConversionHook;
+use crate::notebook::{CURRENT_FORMAT, FileHeader, Layer, Notebook, Page, footer_address, read_block};
+use crate::pipeline::{self, PageSource, PageTransform, pipeline};
+use crate::postprocess::{high_contrast, highlight, ink_bounds, invert_ink, is_ink_pixel, luminance, resample, tint_ink};
+use crate::renderer::LayerRenderer;
+use crate::sidecar::write_sidecar;
+
+/// Options that change how a notebook is converted, shared by every file in a run.
+#[derive(Debug, Default, Clone)]
+pub struct ConvertOptions {
+    /// Leave pages without any ink out of the PDF. The template a page is drawn on doesn't count as ink, unless it is a
+    /// custom template or an imported image (see [`Page::has_custom_template`]).
+    pub skip_blank_pages: bool,
+    /// Mapping from Supernote color codes to the RGBA pixels drawn in the PDF.
+    pub color_map: ColorMap,
+    /// Fail on files whose signature isn't a known Supernote signature, instead of warning and converting anyway.
+    pub strict: bool,
+    /// Only convert pages that were starred on the device.
+    pub starred_only: bool,
+    /// Leave out pages that were starred on the device.
+    pub exclude_starred: bool,
+    /// Leave out these pages, by 1-based page number. The remaining pages are numbered from 1 in the PDF.
+    pub excluded_pages: BTreeSet<usize>,
+    /// Also write a JSON file with the notebook's metadata next to the PDF (same name, `.json` extension).
+    pub sidecar: bool,
+    /// Embed a font (printable ASCII from DejaVu Sans) as `/F1` in every page's resources, so a text layer, such
+    /// as OCR output, can be added to the PDF later without changing its structure. No text is drawn with it yet.
+    /// Needs the `fonts` feature, which is on by default.
+    pub embed_fonts: bool,
+    /// Flate-compress the page content streams and the XMP metadata of [`ConvertOptions::pdf_ua`] output, which are
+    /// otherwise stored as plain text. Saves a little space per page at the cost of readability.
+    pub compress_metadata: bool,
+    /// Also write a PNG thumbnail of each page, this many pixels wide, next to the PDF: `<stem>_thumb_001.png`, ...
+    pub thumbnail_width: Option<u32>,
+    /// Embed each layer as its own image in a PDF optional content group, so viewers can toggle layers,
+    /// instead of one pre-composited image per page.
+    pub pdf_layers: bool,
+    /// Draw only these layers, bottom to top, e.g. `["MAINLAYER", "LAYER1"]`, instead of every layer in the page's
+    /// `LAYERSEQ` order. Layers a page doesn't have are ignored.
+    pub layer_order: Option<Vec<String>>,
+    /// Render every visible pixel pure black or pure white, for maximum legibility.
+    pub high_contrast: bool,
+    /// Draw light ink on dark paper. Photo-like (colorful) pixels keep their colors.
+    pub invert: bool,
+    /// Draw ink in this color instead of black; grays become lighter shades of it.
+    pub ink_tint: Option<Rgba<u8>>,
+    /// Paper color pages are drawn on; white when unset. A fully transparent color leaves the paper out,
+    /// so pages are embedded with an alpha channel.
+    pub bg_color: Option<Rgba<u8>>,
+    /// Resample page images by this factor before encoding, e.g. `1.5` for 150%. The page size stays the same,
+    /// so this trades file size for sharpness at high zoom. Unset means the device resolution.
+    pub scale: Option<f32>,
+    /// Split the output into several PDFs (`name_001.pdf`, `name_002.pdf`, ...) so that none is larger than
+    /// this many bytes. A single page that is larger on its own still gets a file to itself.
+    pub max_output_size: Option<u64>,
+    /// Split the output into several PDFs (`name_001.pdf`, `name_002.pdf`, ...) of at most this many pages each.
+    pub pages_per_file: Option<usize>,
+    /// Split the output into one PDF per chapter (`name_chapter_01_<title>.pdf`, ...). A chapter starts at each titled
+    /// page that follows an untitled one, and pages before the first chapter form a chapter of their own.
+    /// Takes the place of [`ConvertOptions::max_output_size`] and [`ConvertOptions::pages_per_file`].
+    pub auto_split_chapters: bool,
+    /// Crop each page to the area that has ink on it, plus [`ConvertOptions::crop_margin`]. Blank pages are not cropped.
+    pub crop_to_ink: bool,
+    /// Space kept around the ink when cropping.
+    pub crop_margin: CropMargin,
+    /// Pixels per inch used to size PDF pages. Unset means the device's own screen density, so pages come out
+    /// at the physical size of the screen, whichever device a notebook was written on.
+    pub dpi: Option<f32>,
+    /// Directory where composited pages are cached as PNG files, so converting an unchanged notebook again
+    /// skips decoding. Not used with [`ConvertOptions::pdf_layers`].
+    pub cache_dir: Option<PathBuf>,
+    /// Render every page even after one fails, and report all failures together instead of only the first.
+    pub all_errors: bool,
+    /// Number shown by PDF viewers for the first exported page, instead of 1.
+    pub page_label_offset: Option<usize>,
+    /// Numbering style shown by PDF viewers for page numbers. Unset leaves page labels out unless an offset is given.
+    pub page_label_style: Option<PageLabelStyle>,
+    /// How PDF viewers should show the document when it is opened.
+    pub viewer: ViewerPreferences,
+    /// `/Producer` in the PDF's document information. Unset means `supernote_pdf <version>`.
+    pub producer: Option<String>,
+    /// `/Creator` in the PDF's document information. Unset means `supernote_pdf <version>`.
+    pub creator: Option<String>,
+    /// `/Author` in the PDF's document information. Unset leaves it out, except in merged PDFs, where it lists the
+    /// source files.
+    pub author: Option<String>,
+    /// Write a tagged PDF following PDF/UA-1: each page is a `Figure` with alt text in a structure tree.
+    /// Without OCR the figures hold no text, so this only gives screen readers the document's structure.
+    pub pdf_ua: bool,
+    /// Language of the document (`/Lang` in the catalog) as a BCP 47 tag, e.g. `en-US` or `ja`.
+    /// Tagged output without a language is marked as English.
+    pub lang: Option<String>,
+    /// Clockwise rotation, in degrees (90, 180 or 270), of specific pages by 1-based page number.
+    /// Pages that aren't listed keep their orientation.
+    pub page_rotations: HashMap<usize, u32>,
+    /// How composited page images are compressed. Pages with transparency and layers in layered output are always
+    /// Flate-compressed, since JPEG and CCITT images can't carry an alpha channel.
+    pub page_format: PageFormat,
+    /// zlib level (0-9) of Flate-compressed page images: 0 stores them uncompressed, 1 is fastest and 9 smallest.
+    /// Unset means 6.
+    pub zlib_level: Option<u8>,
+    /// Append blank pages so the PDF has at least this many pages, counting a cover page. Longer notebooks are
+    /// not truncated.
+    pub pad_to_count: Option<usize>,
+    /// Start the PDF with a generated title page showing the notebook's title, device, page count and creation date.
+    /// Needs the `fonts` feature, which is on by default.
+    pub cover_page: bool,
+    /// Draws layers in place of the built-in decoding and compositing. Pages drawn by a custom renderer are never
+    /// cached in [`ConvertOptions::cache_dir`].
+    pub layer_renderer: Option<Arc<dyn LayerRenderer>>,
+    /// Called as each page is rendered, and with warnings about the notebook. Without a hook, warnings are dropped.
+    pub hook: Option<Arc<dyn ConversionHook>>,
+    /// A progress bar to count rendered pages on: its length is set to the number of pages to render, and it
+    /// advances as each one is done.
+    pub page_progress: Option<ProgressBar>,
+    /// Print a table of each page's layers to stderr as the page is rendered: key, protocol, bitmap address,
+    /// compressed and decoded size, and how long decoding and compositing took.
+    pub print_layers: bool,
+}
+
+/// Initial view settings stored in the PDF catalog. Viewers may ignore them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ViewerPreferences {
+    /// Resize the viewer window to fit the first page.
+    pub fit_window: bool,
+    /// Show two pages side by side, odd pages on the left.
+    pub two_page: bool,
+    /// Open in full-screen mode.
+    pub full_screen: bool,
+}
+
+/// How PDF viewers display page numbers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PageLabelStyle {
+    /// 1, 2, 3, ...
+    #[default]
+    Decimal,
+    /// i, ii, iii, ...
+    LowerRoman,
+}
+
+/// How a page image is compressed in the PDF.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageEncoding {
+    /// Lossless Deflate compression; good for ink, and the default.
+    #[default]
+    Flate,
+    /// Lossy JPEG compression; smallest for pages with photos.
+    Jpeg,
+    /// CCITT Group 4 fax compression of a 1-bit image; smallest for pure black-and-white ink, but grays are
+    /// rounded to black or white.
+    Ccitt,
+}
+
+/// How [`ConvertOptions::page_format`] picks each page's [`ImageEncoding`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFormat {
+    /// Every page uses the same encoding.
+    Fixed(ImageEncoding),
+    /// Each page's encoding is picked from its content by [`detect_optimal_encoding`].
+    Auto,
+}
+
+impl Default for PageFormat {
+    fn default() -> Self {
+        PageFormat::Fixed(ImageEncoding::Flate)
+    }
+}
+
+/// How much space [`ConvertOptions::crop_to_ink`] keeps around the ink on each side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropMargin {
+    /// A fixed number of device pixels.
+    Pixels(u32),
+    /// A percentage of the page width (left and right) and height (top and bottom).
+    Percent(f32),
+}
+
+impl Default for CropMargin {
+    fn default() -> Self {
+        CropMargin::Pixels(50)
+    }
+}
+
+impl ConvertOptions {
+    /// Passes a warning to the [`ConvertOptions::hook`], if there is one.
+    fn warn(&self, message: &str) {
+        if let Some(hook) = &self.hook {
+            hook.on_warning(message);
+        }
+    }
+
+    /// Whether a page should be converted at all, based on its 1-based number and its metadata alone.
+    fn keeps_page(&self, page_num: usize, page: &Page) -> bool {
+        if self.excluded_pages.contains(&page_num) {
+            return false;
+        }
+        if page.is_starred { !self.exclude_starred } else { !self.starred_only }
+    }
+
+    /// Applies the requested post-processing passes to a composited page or a single layer image.
+    fn adjust_image(&self, image: &mut RgbaImage) {
+        if self.high_contrast {
+            high_contrast(image, &self.color_map);
+        }
+        if self.invert {
+            invert_ink(image);
+        }
+        if let Some(tint) = self.ink_tint {
+            tint_ink(image, tint);
+        }
+    }
+
+    /// The part of a composited page to keep, as `(x, y, width, height)`, or `None` to keep all of it.
+    fn crop_rect(&self, canvas: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+        if !self.crop_to_ink {
+            return None;
+        }
+        let (x, y, width, height) = ink_bounds(canvas, self.background())?;
+        let (margin_x, margin_y) = match self.crop_margin {
+            CropMargin::Pixels(n) => (n, n),
+            CropMargin::Percent(pct) => (
+                (canvas.width() as f32 * pct / 100.0) as u32,
+                (canvas.height() as f32 * pct / 100.0) as u32,
+            ),
+        };
+        let left = x.saturating_sub(margin_x);
+        let top = y.saturating_sub(margin_y);
+        let right = (x + width).saturating_add(margin_x).min(canvas.width());
+        let bottom = (y + height).saturating_add(margin_y).min(canvas.height());
+        Some((left, top, right - left, bottom - top))
+    }
+
+    /// Size of a notebook pixel in PDF units (points, 1/72 inch), before any scaling.
+    fn points_per_pixel(&self, notebook: &Notebook) -> f32 {
+        72.0 / self.dpi.unwrap_or_else(|| notebook.screen_dpi())
+    }
+
+    /// Crops, post-processes and resamples a composited page or a single layer image, in that order.
+    fn finish_image(&self, mut image: RgbaImage, crop: Option<(u32, u32, u32, u32)>) -> RgbaImage {
+        if let Some((x, y, width, height)) = crop {
+            image = imageops::crop_imm(&image, x, y, width, height).to_image();
+        }
+        self.adjust_image(&mut image);
+        self.scale_image(image)
+    }
+
+    /// Resamples a finished page or layer image by [`ConvertOptions::scale`].
+    fn scale_image(&self, image: RgbaImage) -> RgbaImage {
+        match self.scale {
+            Some(scale) if scale != 1.0 => resample(&image, scale),
+            _ => image,
+        }
+    }
+
+    /// The color each page canvas starts out with, before any layer is drawn.
+    fn background(&self) -> Rgba<u8> {
+        self.bg_color.unwrap_or(Rgba([255, 255, 255, 255]))
+    }
+
+    /// The layers of `page` to draw, bottom to top.
+    fn page_layers<'a>(&self, page: &'a Page) -> Vec<&'a Layer> {
+        match &self.layer_order {
+            Some(keys) => keys.iter().filter_map(|key| page.layers.iter().find(|layer| &layer.key == key)).collect(),
+            None => page.layers.iter().collect(),
+        }
+    }
+
+    /// The compression of Flate-compressed page images.
+    fn zlib_compression(&self) -> Compression {
+        self.zlib_level.map_or(Compression::default(), |level| Compression::new(level as u32))
+    }
+
+    /// The paper color after post-processing. Layered output draws it behind the transparent layers.
+    fn paper_color(&self) -> Rgba<u8> {
+        let mut paper = RgbaImage::from_pixel(1, 1, self.background());
+        self.adjust_image(&mut paper);
+        *paper.get_pixel(0, 0)
+    }
+}
+
+/// A page after rendering: the composited image, plus each layer's own image when layers are exported separately.
+struct RenderedPage<'a> {
+    page: &'a Page,
+    canvas: RgbaImage,
+    layers: Vec<(String, RgbaImage)>,
+    /// Width and height of the PDF page, in points.
+    page_size: (f32, f32),
+}
+
+/// The compressed pixel data of an image XObject, and of its alpha channel if it has one.
+#[derive(Debug)]
+struct EncodedImage {
+    width: u32,
+    height: u32,
+    color_space: &'static str,
+    filter: ImageFilter,
+    data: Vec<u8>,
+    smask: Option<Box<EncodedImage>>,
+}
+
+/// The PDF filter an [`EncodedImage`]'s data is compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImageFilter {
+    Flate,
+    /// JPEG (`/DCTDecode`).
+    Dct,
+    /// CCITT Group 4 of a 1-bit image, black where bits are 0.
+    CcittGroup4,
+}
+
+impl EncodedImage {
+    /// Approximate number of bytes the image takes up in the PDF, including its soft mask.
+    fn encoded_len(&self) -> u64 {
+        let smask_len = self.smask.as_ref().map_or(0, |smask| smask.encoded_len());
+        self.data.len() as u64 + 256 + smask_len
+    }
+}
+
+/// A page's images, bottom to top, each with the optional content group (layer name) it belongs to, if any.
+#[derive(Debug)]
+struct PdfPageChunk {
+    images: Vec<(Option<String>, EncodedImage)>,
+    /// Width and height of the page, in points.
+    page_size: (f32, f32),
+}
+
+impl PdfPageChunk {
+    /// Approximate number of bytes the page takes up in the PDF: its images plus the page and content stream objects.
+    fn encoded_len(&self) -> u64 {
+        self.images.iter().map(|(_, image)| image.encoded_len()).sum::<u64>() + 512
+    }
+}
+
+/// What a successful conversion produced.
+#[derive(Debug)]
+pub struct ConversionSummary {
+    /// The parsed notebook, including pages that were left out of the PDF.
+    pub notebook: Notebook,
+    /// Number of pages in the written PDFs.
+    pub pages_written: usize,
+    /// The PDFs that were written: just the requested output path, unless the output was split.
+    pub output_files: Vec<PathBuf>,
+    /// The page thumbnails that were written, for [`ConvertOptions::thumbnail_width`].
+    pub thumbnail_files: Vec<PathBuf>,
+}
+
+/// Converts a `.note` file into a PDF with one embedded image per page.
+///
+/// This is a [`pipeline`](crate::pipeline): the source composites each page the options keep, the transforms crop,
+/// post-process, resample and rotate it, and the sink writes the pages out along with the extra files the options
+/// ask for.
+pub fn convert_note_to_pdf(input_path: &Path, output_path: &Path, options: &ConvertOptions) -> Result<ConversionSummary> {
+    let notebook = Notebook::from_path(input_path)?;
+    check_notebook(&notebook, input_path, options)?;
+
+    let transforms = page_transforms(&notebook, options);
+    let mut sink = PdfSink {
+        notebook: &notebook,
+        input_path,
+        output_path,
+        options,
+        rendered_pages: Vec::new(),
+    };
+    pipeline(
+        composite_pages(&notebook, input_path, options)?,
+        &transforms.iter().map(|transform| transform.as_ref()).collect_vec(),
+        |_, rendered| sink.push(rendered),
+    )?;
+    let (pages_written, output_files, thumbnail_files) = sink.finish()?;
+    // The transforms borrow the notebook, which goes into the summary.
+    drop(transforms);
+
+    Ok(ConversionSummary {
+        notebook,
+        pages_written,
+        output_files,
+        thumbnail_files,
+    })
+}
+
+/// The sink of [`convert_note_to_pdf`]: collects the finished pages, then writes the PDF (or its parts) along with
+/// the sidecar and thumbnails.
+struct PdfSink<'a> {
+    notebook: &'a Notebook,
+    input_path: &'a Path,
+    output_path: &'a Path,
+    options: &'a ConvertOptions,
+    rendered_pages: Vec<RenderedPage<'a>>,
+}
+
+impl<'a> PdfSink<'a> {
+    fn push(&mut self, rendered: RenderedPage<'a>) -> Result<()> {
+        self.rendered_pages.push(rendered);
+        Ok(())
+    }
+
+    /// Writes everything out, returning the number of PDF pages, the PDFs and the thumbnails that were written.
+    ///
+    /// If anything fails, the files written so far are removed again: a retry or a re-run of the conversion would
+    /// otherwise find parts of this attempt in its way, and refuse to write over them.
+    fn finish(self) -> Result<(usize, Vec<PathBuf>, Vec<PathBuf>)> {
+        let mut written = Vec::new();
+        let result = self.write(&mut written);
+        if result.is_err() {
+            for path in &written {
+                let _ = fs::remove_file(path);
+            }
+        }
+        result
+    }
+
+    /// Does the work of [`PdfSink::finish`], adding every file it creates to `written` before writing to it.
+    fn write(self, written: &mut Vec<PathBuf>) -> Result<(usize, Vec<PathBuf>, Vec<PathBuf>)> {
+        let PdfSink {
+            notebook,
+            input_path,
+            output_path,
+            options,
+            mut rendered_pages,
+        } = self;
+
+        if options.sidecar {
+            let canvases: Vec<&RgbaImage> = rendered_pages.iter().map(|rendered| &rendered.canvas).collect();
+            let sidecar_path = output_path.with_extension("json");
+            written.push(sidecar_path.clone());
+            write_sidecar(notebook, &canvases, options.paper_color(), &sidecar_path)?;
+        }
+        let thumbnail_files = match options.thumbnail_width {
+            Some(width) => {
+                written.extend((0..rendered_pages.len()).map(|page_idx| thumbnail_path(output_path, page_idx)));
+                write_thumbnails(&rendered_pages, width, output_path)?
+            }
+            None => Vec::new(),
+        };
+        let cover_page = Page::default();
+        if options.cover_page {
+            rendered_pages.insert(0, render_cover_page(notebook, input_path, &cover_page, rendered_pages.len(), options)?);
+        }
+        let padding_page = Page::default();
+        pad_pages(&mut rendered_pages, notebook, &padding_page, options);
+
+        let (pages, page_chunks) = encode_pages(rendered_pages, options);
+
+        // Write everything to a file sequentially
+        let parts = if options.auto_split_chapters {
+            split_into_chapters(&pages)
+        } else {
+            split_into_parts(&page_chunks, options)
+        };
+        let part_paths: Vec<PathBuf> = parts
+            .iter()
+            .enumerate()
+            .map(|(part_idx, range)| {
+                if options.auto_split_chapters {
+                    chapter_path(output_path, part_idx + 1, pages[range.start].title.as_deref())
+                } else if parts.len() == 1 {
+                    output_path.to_path_buf()
+                } else {
+                    numbered_path(output_path, part_idx + 1)
+                }
+            })
+            .collect();
+        // The caller vouches for `output_path`, but the names of split parts are only known here. Check them all before
+        // writing any, so a re-run doesn't overwrite earlier parts or leave a mix of old and new ones.
+        let split_paths = || part_paths.iter().filter(|&part_path| part_path != output_path);
+        if let Some(existing) = split_paths().find(|part_path| part_path.exists()) {
+            return Err(SupernoteError::IoError(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("output file '{}' already exists", existing.display()),
+            )));
+        }
+        let mut output_files = Vec::with_capacity(parts.len());
+        for (range, part_path) in parts.iter().zip(part_paths) {
+            let out_file = if part_path == output_path {
+                File::create(&part_path)?
+            } else {
+                File::create_new(&part_path)?
+            };
+            written.push(part_path.clone());
+            write_pdf(
+                BufWriter::new(out_file),
+                &pages[range.clone()],
+                &page_chunks[range.clone()],
+                range.start,
+                &notebook.header,
+                options,
+            )?;
+            output_files.push(part_path);
+        }
+
+        Ok((pages.len(), output_files, thumbnail_files))
+    }
+}
+
+/// Writes a `width` pixels wide PNG of each page next to `output_path`, named `<stem>_thumb_001.png`, ...
+fn write_thumbnails(rendered_pages: &[RenderedPage], width: u32, output_path: &Path) -> Result<Vec<PathBuf>> {
+    rendered_pages
+        .par_iter()
+        .enumerate()
+        .map(|(i, rendered)| {
+            let canvas = &rendered.canvas;
+            let height = ((canvas.height() as u64 * width as u64 / canvas.width() as u64) as u32).max(1);
+            let thumbnail = imageops::resize(canvas, width, height, FilterType::Lanczos3);
+            let path = thumbnail_path(output_path, i);
+            thumbnail.save(&path)?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Where [`write_thumbnails`] puts the thumbnail of the `index`-th (0-based) rendered page.
+fn thumbnail_path(output_path: &Path, index: usize) -> PathBuf {
+    let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+    output_path.with_file_name(format!("{}_thumb_{:03}.png", stem, index + 1))
+}
+
+/// Passes on the [`Notebook::warnings`] from reading a notebook, and warns about an unrecognized signature, or fails
+/// in [`ConvertOptions::strict`] mode.
+fn check_notebook(notebook: &Notebook, input_path: &Path, options: &ConvertOptions) -> Result<()> {
+    for warning in &notebook.warnings {
+        options.warn(&format!("'{}': {}", input_path.display(), warning));
+    }
+    if !notebook.has_known_signature() {
+        let msg = format!(
+            "'{}' has an unrecognized signature '{}'; it was probably written by newer firmware",
+            input_path.display(),
+            notebook.signature.escape_debug()
+        );
+        if options.strict {
+            return Err(SupernoteError::UnsupportedDevice(msg));
+        }
+        options.warn(&msg);
+    }
+    Ok(())
+}
+
+/// Renders the pages of a notebook read from `input_path` that the options keep, in page order, finished for the PDF.
+/// Blank pages are left out when [`ConvertOptions::skip_blank_pages`] is set.
+fn render_pages<'a>(notebook: &'a Notebook, input_path: &Path, options: &ConvertOptions) -> Result<Vec<RenderedPage<'a>>> {
+    let transforms = page_transforms(notebook, options);
+    let mut rendered_pages = Vec::new();
+    pipeline(
+        composite_pages(notebook, input_path, options)?,
+        &transforms.iter().map(|transform| transform.as_ref()).collect_vec(),
+        |_, rendered| {
+            rendered_pages.push(rendered);
+            Ok(())
+        },
+    )?;
+    Ok(rendered_pages)
+}
+
+/// The source of the conversion pipeline: composites the pages of a notebook read from `input_path` that the options
+/// keep, in page order, as drawn on the device. See [`render_pages`].
+fn composite_pages<'a>(notebook: &'a Notebook, input_path: &Path, options: &ConvertOptions) -> Result<impl PageSource<RenderedPage<'a>>> {
+    // Only composited pages are cached; layered output needs every layer image.
+    let page_cache = options
+        .cache_dir
+        .as_deref()
+        .filter(|_| !options.pdf_layers && options.layer_renderer.is_none())
+        .and_then(|dir| PageCache::new(dir, input_path, &|message| options.warn(message)));
+
+    if let Some(pb) = &options.page_progress {
+        let page_count = notebook
+            .pages
+            .iter()
+            .enumerate()
+            .filter(|(page_idx, page)| options.keeps_page(page_idx + 1, page))
+            .count();
+        pb.set_length(page_count as u64);
+    }
+
+    // Pages are rendered in parallel, but `collect` on an indexed parallel iterator keeps them in page order,
+    // so the output is the same on any number of threads.
+    let page_results = notebook
+        .pages
+        .par_iter()
+        .enumerate()
+        .filter(|(page_idx, page)| options.keeps_page(page_idx + 1, page))
+        .map(|(page_idx, _)| {
+            if let Some(hook) = &options.hook {
+                hook.on_page_start(page_idx, notebook.pages.len());
+            }
+            let rendered = render_page(notebook, page_idx, input_path, page_cache.as_ref(), options);
+            if let Some(pb) = &options.page_progress {
+                pb.inc(1);
+            }
+            if let (Some(hook), Err(e)) = (&options.hook, &rendered) {
+                hook.on_error(page_idx, e);
+            }
+            rendered
+        });
+    let rendered: Vec<RenderedPage> = if options.all_errors {
+        let (rendered, errors): (Vec<_>, Vec<_>) = page_results.collect::<Vec<_>>().into_iter().partition_result();
+        if !errors.is_empty() {
+            return Err(SupernoteError::Multiple(errors));
+        }
+        rendered.into_iter().flatten().collect()
+    } else {
+        // Stops at the first failing page.
+        page_results.collect::<Result<Vec<_>>>()?.into_iter().flatten().collect()
+    };
+    Ok(rendered.into_iter().map(Ok))
+}
+
+/// The transforms of the conversion pipeline, which finish a composited page for the PDF: cropping, post-processing,
+/// resampling and rotation, in that order, and then the [`ConversionHook::on_page_done`] callback. Layer images of
+/// layered output go through the same steps as the page. Steps the options don't ask for are left out.
+fn page_transforms<'a: 'o, 'o>(notebook: &'a Notebook, options: &'o ConvertOptions) -> Vec<Box<dyn PageTransform<RenderedPage<'a>> + 'o>> {
+    let mut transforms: Vec<Box<dyn PageTransform<RenderedPage<'a>> + 'o>> = Vec::new();
+    if options.crop_to_ink {
+        // A cropped page keeps the scale of a full one, so its ink is printed at the same size.
+        let points_per_pixel = options.points_per_pixel(notebook);
+        transforms.push(Box::new(move |mut rendered: RenderedPage<'a>| {
+            if let Some((x, y, width, height)) = options.crop_rect(&rendered.canvas) {
+                let crop = |image: &RgbaImage| imageops::crop_imm(image, x, y, width, height).to_image();
+                rendered.canvas = crop(&rendered.canvas);
+                for (_, layer_image) in rendered.layers.iter_mut() {
+                    *layer_image = crop(layer_image);
+                }
+                rendered.page_size = (width as f32 * points_per_pixel, height as f32 * points_per_pixel);
+            }
+            Ok(rendered)
+        }));
+    }
+    if options.high_contrast {
+        transforms.push(Box::new(each_image(pipeline::high_contrast(options.color_map.clone()))));
+    }
+    if options.invert {
+        transforms.push(Box::new(each_image(pipeline::invert())));
+    }
+    if let Some(color) = options.ink_tint {
+        transforms.push(Box::new(each_image(pipeline::tint(color))));
+    }
+    if let Some(factor) = options.scale.filter(|&factor| factor != 1.0) {
+        transforms.push(Box::new(each_image(pipeline::scale(factor))));
+    }
+    if !options.page_rotations.is_empty() {
+        transforms.push(Box::new(move |mut rendered: RenderedPage<'a>| {
+            let page_num = page_index(notebook, rendered.page).map_or(0, |page_idx| page_idx + 1);
+            let rotation = options.page_rotations.get(&page_num).copied().unwrap_or(0);
+            if rotation % 180 == 90 {
+                rendered.page_size = (rendered.page_size.1, rendered.page_size.0);
+            }
+            rendered.canvas = rotate_image(rendered.canvas, rotation);
+            rendered.layers = rendered
+                .layers
+                .into_iter()
+                .map(|(key, layer_image)| (key, rotate_image(layer_image, rotation)))
+                .collect();
+            Ok(rendered)
+        }));
+    }
+    if let Some(hook) = &options.hook {
+        transforms.push(Box::new(move |rendered: RenderedPage<'a>| {
+            if let Some(page_idx) = page_index(notebook, rendered.page) {
+                hook.on_page_done(page_idx, &rendered.canvas);
+            }
+            Ok(rendered)
+        }));
+    }
+    transforms
+}
+
+/// Applies an image transform to a rendered page and to each of its layer images.
+fn each_image<'a>(transform: impl PageTransform) -> impl PageTransform<RenderedPage<'a>> {
+    move |mut rendered: RenderedPage<'a>| {
+        rendered.canvas = transform(rendered.canvas)?;
+        rendered.layers = rendered
+            .layers
+            .into_iter()
+            .map(|(key, layer_image)| Ok((key, transform(layer_image)?)))
+            .collect::<Result<_>>()?;
+        Ok(rendered)
+    }
+}
+
+/// The 0-based index of `page` in `notebook`, or `None` for a page that isn't one of its own, like a cover page.
+fn page_index(notebook: &Notebook, page: &Page) -> Option<usize> {
+    notebook.pages.iter().position(|own_page| std::ptr::eq(own_page, page))
+}
+
+/// Composites the page at `page_idx` of a notebook read from `input_path`, or returns `None` for a blank page when
+/// [`ConvertOptions::skip_blank_pages`] is set. The page still has to be finished by [`page_transforms`].
+fn render_page<'a>(
+    notebook: &'a Notebook,
+    page_idx: usize,
+    input_path: &Path,
+    page_cache: Option<&PageCache>,
+    options: &ConvertOptions,
+) -> Result<Option<RenderedPage<'a>>> {
+    let page = &notebook.pages[page_idx];
+    let (width, height) = notebook.page_size(page);
+    let points_per_pixel = options.points_per_pixel(notebook);
+    let page_num = page_idx + 1;
+    let mut file = File::open(input_path).map_err(|e| SupernoteError::from(e).in_page(page_num))?;
+
+    // Read every layer's bitmap block up front, so blank pages can be detected before any decoding happens.
+    let mut layer_blocks = Vec::new();
+    for layer in options.page_layers(page) {
+        if layer.bitmap_address == 0 {
+            continue;
+        }
+        let data = read_block(&mut file, layer.bitmap_address).map_err(|e| e.in_layer(page_num, &layer.key))?;
+        layer_blocks.push((layer, data));
+    }
+    // Decoding may pick up other pages on this thread; don't keep a file open for each of them.
+    drop(file);
+
+    if options.skip_blank_pages && layer_blocks.iter().all(|(layer, data)| is_blank_layer(page, layer, data)) {
+        return Ok(None);
+    }
+
+    let cached = page_cache.and_then(|cache| cache.load(page, options.layer_order.as_deref(), &options.color_map, options.background()));
+    let is_cached = cached.is_some();
+    // One entry per layer block, `None` for layers that were skipped, for `print_layers`.
+    let mut timings: Vec<Option<LayerTiming>> = Vec::new();
+    let mut tiles_time = None;
+    let (base_canvas, layers) = match cached {
+        Some(canvas) => (canvas, Vec::new()),
+        None => {
+            let (base_canvas, layers) = if let Some(renderer) = &options.layer_renderer {
+                // The renderer's work isn't split into decoding and compositing, so there are no timings to report.
+                timings = layer_blocks.iter().map(|_| None).collect();
+                let mut base_canvas = RgbaImage::from_pixel(width as u32, height as u32, options.background());
+                let mut layers = Vec::new();
+                for (layer, data) in layer_blocks.iter() {
+                    let render = |canvas: &mut RgbaImage| {
+                        renderer
+                            .render_layer(canvas, layer, data, width, height)
+                            .map_err(|e| e.in_layer(page_num, &layer.key))
+                    };
+                    if options.pdf_layers {
+                        let mut layer_image = RgbaImage::new(width as u32, height as u32);
+                        render(&mut layer_image)?;
+                        imageops::overlay(&mut base_canvas, &layer_image, 0, 0);
+                        if layer_image.pixels().any(|p| p.0[3] != 0) {
+                            layers.push((layer.key.clone(), layer_image));
+                        }
+                    } else {
+                        render(&mut base_canvas)?;
+                    }
+                }
+                (base_canvas, layers)
+            } else if !options.pdf_layers && is_single_rle_layer(&layer_blocks) {
+                let (layer, data) = &layer_blocks[0];
+                let (pixel_data, decode_time) = timed(|| decode_rle_reporting(data, width, height, &layer_warning(page_num, layer, options)));
+                let pixel_data = pixel_data.map_err(|e| e.in_layer(page_num, &layer.key))?;
+                let (base_canvas, composite_time) = timed(|| {
+                    let layer_image = pixels_to_image(&pixel_data, width, height, &options.color_map);
+                    // An opaque layer hides the paper entirely, so it already is the page.
+                    if layer_image.pixels().all(|p| p.0[3] == 255) {
+                        layer_image
+                    } else {
+                        let mut base_canvas = RgbaImage::from_pixel(width as u32, height as u32, options.background());
+                        imageops::overlay(&mut base_canvas, &layer_image, 0, 0);
+                        base_canvas
+                    }
+                });
+                timings.push(Some(LayerTiming {
+                    decoded_len: pixel_data.len(),
+                    decode: decode_time,
+                    composite: Some(composite_time),
+                }));
+                (base_canvas, Vec::new())
+            } else if !options.pdf_layers && width * height >= TILED_RENDER_MIN_PIXELS {
+                let decoded_layers = decode_layers_timed(&layer_blocks, page_num, |layer, data| {
+                    decode_layer_pixels(layer, data, width, height, &options.color_map, &layer_warning(page_num, layer, options))
+                })?;
+                timings = decoded_layers
+                    .iter()
+                    .map(|(pixels, decode)| pixels.as_ref().map(|pixels| LayerTiming::new(pixels.decoded_len(), *decode)))
+                    .collect();
+                let layer_pixels: Vec<LayerPixels> = decoded_layers.into_iter().filter_map(|(pixels, _)| pixels).collect();
+                let (base_canvas, composite_time) = timed(|| render_tiled(&layer_pixels, width, height, &options.color_map, options.background()));
+                tiles_time = Some(composite_time);
+                (base_canvas, Vec::new())
+            } else if !options.pdf_layers {
+                let decoded_layers = decode_layers_timed(&layer_blocks, page_num, |layer, data| {
+                    decode_layer_pixels(layer, data, width, height, &options.color_map, &layer_warning(page_num, layer, options))
+                })?;
+
+                // Each layer is drawn into the same scratch image before being stacked, instead of one image per layer.
+                let mut base_canvas = RgbaImage::from_pixel(width as u32, height as u32, options.background());
+                let mut scratch = RgbaImage::new(width as u32, height as u32);
+                for (layer_pixels, decode_time) in decoded_layers.iter() {
+                    timings.push(layer_pixels.as_ref().map(|layer_pixels| {
+                        let ((), composite_time) = timed(|| layer_pixels.overlay_onto(&mut base_canvas, &mut scratch, &options.color_map));
+                        LayerTiming {
+                            composite: Some(composite_time),
+                            ..LayerTiming::new(layer_pixels.decoded_len(), *decode_time)
+                        }
+                    }));
+                }
+                (base_canvas, Vec::new())
+            } else {
+                let mut base_canvas = RgbaImage::from_pixel(width as u32, height as u32, options.background());
+                let mut layers = Vec::new();
+
+                // Layers are independent until they are stacked, so decode them in parallel and composite in LAYERSEQ order.
+                let decoded_layers = decode_layers_timed(&layer_blocks, page_num, |layer, data| {
+                    decode_layer(layer, data, width, height, &options.color_map, &layer_warning(page_num, layer, options))
+                })?;
+
+                for ((layer, _), (layer_image, decode_time)) in layer_blocks.iter().zip(decoded_layers) {
+                    let Some(layer_image) = layer_image else {
+                        timings.push(None);
+                        continue;
+                    };
+                    let ((), composite_time) = timed(|| imageops::overlay(&mut base_canvas, &layer_image, 0, 0));
+                    timings.push(Some(LayerTiming {
+                        composite: Some(composite_time),
+                        ..LayerTiming::new(layer_image.as_raw().len(), decode_time)
+                    }));
+
+                    // Layers without any visible pixel don't get their own image in layered output.
+                    if layer_image.pixels().any(|p| p.0[3] != 0) {
+                        layers.push((layer.key.clone(), layer_image));
+                    }
+                }
+                (base_canvas, layers)
+            };
+
+            if let Some(cache) = page_cache {
+                cache.store(
+                    page,
+                    options.layer_order.as_deref(),
+                    &options.color_map,
+                    options.background(),
+                    &base_canvas,
+                    &|message| options.warn(message),
+                );
+            }
+            (base_canvas, layers)
+        }
+    };
+
+    if options.print_layers {
+        eprint!("{}", layer_report(page_num, &layer_blocks, &timings, tiles_time, is_cached));
+    }
+
+    Ok(Some(RenderedPage {
+        page,
+        canvas: base_canvas,
+        layers,
+        page_size: (width as f32 * points_per_pixel, height as f32 * points_per_pixel),
+    }))
+}
+
+/// Draws the title page that [`ConvertOptions::cover_page`] puts in front of a notebook's `page_count` rendered pages.
+/// It is the size of an uncropped page, and `cover` (a page without layers or a title) stands in for its metadata.
+#[cfg(feature = "fonts")]
+fn render_cover_page<'a>(
+    notebook: &Notebook,
+    input_path: &Path,
+    cover: &'a Page,
+    page_count: usize,
+    options: &ConvertOptions,
+) -> Result<RenderedPage<'a>> {
+    let fallback_title = input_path.file_stem().unwrap_or_default().to_string_lossy();
+    let (width, height) = (notebook.width as u32, notebook.height as u32);
+    let image = render_cover(notebook, &fallback_title, page_count, width, height, options.background());
+    let points_per_pixel = options.points_per_pixel(notebook);
+    Ok(RenderedPage {
+        page: cover,
+        canvas: options.finish_image(image, None),
+        layers: Vec::new(),
+        page_size: (width as f32 * points_per_pixel, height as f32 * points_per_pixel),
+    })
+}
+
+/// Stand-in for builds without the `fonts` feature.
+#[cfg(not(feature = "fonts"))]
+fn render_cover_page<'a>(_: &Notebook, _: &Path, _: &'a Page, _: usize, _: &ConvertOptions) -> Result<RenderedPage<'a>> {
+    Err(font_left_out("cover pages"))
+}
+
+/// The error for options that need the bundled font in builds without the `fonts` feature.
+#[cfg(not(feature = "fonts"))]
+fn font_left_out(what: &str) -> SupernoteError {
+    SupernoteError::IoError(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} need the bundled font, which this build leaves out; rebuild with the `fonts` feature",
+            what
+        ),
+    ))
+}
+
+/// Appends blank pages for [`ConvertOptions::pad_to_count`], or warns when there are already more pages than that.
+fn pad_pages<'a>(rendered_pages: &mut Vec<RenderedPage<'a>>, notebook: &Notebook, blank: &'a Page, options: &ConvertOptions) {
+    let Some(target) = options.pad_to_count else {
+        return;
+    };
+    if rendered_pages.len() > target {
+        options.warn(&format!(
+            "the PDF has {} pages, more than the {} it was to be padded to; no pages were removed.",
+            rendered_pages.len(),
+            target
+        ));
+        return;
+    }
+    let (width, height) = (notebook.width as u32, notebook.height as u32);
+    let points_per_pixel = options.points_per_pixel(notebook);
+    let canvas = options.finish_image(RgbaImage::from_pixel(width, height, options.background()), None);
+    let padding = (rendered_pages.len()..target).map(|_| RenderedPage {
+        page: blank,
+        canvas: canvas.clone(),
+        layers: Vec::new(),
+        page_size: (width as f32 * points_per_pixel, height as f32 * points_per_pixel),
+    });
+    rendered_pages.extend(padding);
+}
+
+/// Compresses the images of rendered pages in parallel, returning the pages alongside their PDF chunks.
+fn encode_pages<'a>(rendered_pages: Vec<RenderedPage<'a>>, options: &ConvertOptions) -> (Vec<&'a Page>, Vec<PdfPageChunk>) {
+    let pages: Vec<&Page> = rendered_pages.iter().map(|rendered| rendered.page).collect();
+    let page_chunks: Vec<PdfPageChunk> = rendered_pages
+        .into_par_iter()
+        .map(|rendered| {
+            // Pages without layers of their own, like a cover page, are embedded composited even in layered output.
+            let images = if options.pdf_layers && !rendered.layers.is_empty() {
+                rendered
+                    .layers
+                    .into_iter()
+                    .map(|(key, layer_image)| (Some(key), encode_rgba_image(layer_image, options.zlib_compression())))
+                    .collect()
+            } else if options.background().0[3] != 255 {
+                vec![(None, encode_rgba_image(rendered.canvas, options.zlib_compression()))]
+            } else {
+                let encoding = match options.page_format {
+                    PageFormat::Fixed(encoding) => encoding,
+                    PageFormat::Auto => detect_optimal_encoding(&rendered.canvas),
+                };
+                let image = match encoding {
+                    ImageEncoding::Flate => encode_rgb_image(rendered.canvas, options.zlib_compression()),
+                    ImageEncoding::Jpeg => encode_jpeg_image(rendered.canvas, JPEG_QUALITY),
+                    ImageEncoding::Ccitt => encode_ccitt_image(rendered.canvas),
+                };
+                vec![(None, image)]
+            };
+            PdfPageChunk {
+                images,
+                page_size: rendered.page_size,
+            }
+        })
+        .collect();
+    (pages, page_chunks)
+}
+
+impl Notebook {
+    /// Renders the notebook into a single PDF in memory, with the default [`ConvertOptions`].
+    /// `source` is the `.note` file the notebook was parsed from, which page bitmaps are read from.
+    pub fn render_to_pdf(&self, source: &Path) -> Result<Vec<u8>> {
+        self.render_to_pdf_with_options(source, &ConvertOptions::default())
+    }
+
+    /// Renders one page (1-based, as numbered on the device) to an image, as it would appear in the PDF.
+    /// Options that choose which pages are converted, like [`ConvertOptions::skip_blank_pages`], don't apply.
+    pub fn render_page(&self, source: &Path, page_num: usize, options: &ConvertOptions) -> Result<RgbaImage> {
+        if page_num == 0 || page_num > self.pages.len() {
+            return Err(SupernoteError::PageNotFound {
+                page: page_num,
+                page_count: self.pages.len(),
+            });
+        }
+        let options = ConvertOptions {
+            skip_blank_pages: false,
+            ..options.clone()
+        };
+        let rendered = render_page(self, page_num - 1, source, None, &options)?.expect("pages are only skipped when blank pages are");
+        let transforms = page_transforms(self, &options);
+        let mut image = None;
+        pipeline(
+            iter::once(Ok(rendered)),
+            &transforms.iter().map(|transform| transform.as_ref()).collect_vec(),
+            |_, rendered| {
+                image = Some(rendered.canvas);
+                Ok(())
+            },
+        )?;
+        Ok(image.expect("the one page reaches the sink"))
+    }
+
+    /// Same as [`Notebook::render_to_pdf`], with options. Options that produce extra files, like
+    /// [`ConvertOptions::sidecar`], or split the output, like [`ConvertOptions::max_output_size`], are ignored.
+    /// The notebook's [`Notebook::warnings`] go to the [`ConvertOptions::hook`] along with those from rendering.
+    pub fn render_to_pdf_with_options(&self, source: &Path, options: &ConvertOptions) -> Result<Vec<u8>> {
+        for warning in &self.warnings {
+            options.warn(warning);
+        }
+        let mut rendered_pages = render_pages(self, source, options)?;
+        let cover_page = Page::default();
+        if options.cover_page {
+            rendered_pages.insert(0, render_cover_page(self, source, &cover_page, rendered_pages.len(), options)?);
+        }
+        let padding_page = Page::default();
+        pad_pages(&mut rendered_pages, self, &padding_page, options);
+        let (pages, page_chunks) = encode_pages(rendered_pages, options);
+        let mut pdf = Vec::new();
+        write_pdf(&mut pdf, &pages, &page_chunks, 0, &self.header, options)?;
+        Ok(pdf)
+    }
+}
+
+/// How well one page compressed, from [`measure_compression`].
+#[derive(Debug, Clone)]
+pub struct PageCompression {
+    /// 1-based page number in the notebook.
+    pub page: usize,
+    /// Size in bytes of the page's uncompressed RGB pixels.
+    pub raw_len: usize,
+    /// Size in bytes of the compressed image data.
+    pub compressed_len: usize,
+    /// How long compressing took.
+    pub time: Duration,
+}
+
+/// Renders each page of a notebook as it would be converted and compresses its image with `encoding`, without
+/// writing a PDF, for comparing compression settings. `level` is the zlib level (0-9) for
+/// [`ImageEncoding::Flate`] and the quality (1-100) for [`ImageEncoding::Jpeg`]; CCITT has no settings.
+///
+/// Pages are compressed one at a time so the timings aren't skewed by other work. Pages left out by the options,
+/// like blank pages with [`ConvertOptions::skip_blank_pages`], are not measured.
+pub fn measure_compression(input_path: &Path, encoding: ImageEncoding, level: u8, options: &ConvertOptions) -> Result<Vec<PageCompression>> {
+    let notebook = Notebook::from_path(input_path)?;
+    check_notebook(&notebook, input_path, options)?;
+    let options = &ConvertOptions {
+        pdf_layers: false,
+        ..options.clone()
+    };
+    let rendered_pages = render_pages(&notebook, input_path, options)?;
+    let mut results = Vec::new();
+    for rendered in rendered_pages {
+        let page_idx = page_index(&notebook, rendered.page).expect("rendered pages belong to the notebook");
+        let canvas = rendered.canvas;
+        let raw_len = canvas.width() as usize * canvas.height() as usize * 3;
+        let (compressed_len, time) = timed(|| match encoding {
+            ImageEncoding::Flate => {
+                let rgb = image::DynamicImage::ImageRgba8(canvas).to_rgb8().into_raw();
+                flate_with_level(&rgb, Compression::new(level as u32)).len()
+            }
+            ImageEncoding::Jpeg => encode_jpeg_image(canvas, level).data.len(),
+            ImageEncoding::Ccitt => encode_ccitt_image(canvas).data.len(),
+        });
+        results.push(PageCompression {
+            page: page_idx + 1,
+            raw_len,
+            compressed_len,
+            time,
+        });
+    }
+    Ok(results)
+}
+
+/// Width, in pixels, of the line between the two sides of a comparison page.
+const COMPARE_SEPARATOR_WIDTH: u32 = 4;
+const COMPARE_SEPARATOR_COLOR: Rgba<u8> = Rgba([0x9d, 0x9d, 0x9d, 255]);
+
+/// Renders two versions of a notebook into one PDF for review: each PDF page shows a page of `before_path` on the left
+/// and the same page of `after_path` on the right, split by a thin line. When one notebook has more pages, the other
+/// side of its extra pages is left blank. Returns the number of PDF pages written.
+///
+/// Pages are always composited, so [`ConvertOptions::pdf_layers`] is ignored, as are options that split the output
+/// or write a sidecar.
+pub fn compare_notes_to_pdf(before_path: &Path, after_path: &Path, output_path: &Path, options: &ConvertOptions) -> Result<usize> {
+    let options = &ConvertOptions {
+        pdf_layers: false,
+        ..options.clone()
+    };
+    let before = Notebook::from_path(before_path)?;
+    check_notebook(&before, before_path, options)?;
+    let after = Notebook::from_path(after_path)?;
+    check_notebook(&after, after_path, options)?;
+
+    let (before_pages, after_pages) = rayon::join(
+        || render_pages(&before, before_path, options),
+        || render_pages(&after, after_path, options),
+    );
+    let paper = options.paper_color();
+    let compared: Vec<RenderedPage> = before_pages?
+        .into_iter()
+        .zip_longest(after_pages?)
+        .map(|pair| side_by_side(pair, paper))
+        .collect();
+
+    let (pages, page_chunks) = encode_pages(compared, options);
+    write_pdf(
+        BufWriter::new(File::create(output_path)?),
+        &pages,
+        &page_chunks,
+        0,
+        &after.header,
+        options,
+    )?;
+    Ok(pages.len())
+}
+
+/// Where the pages of one notebook ended up in a merged PDF.
+#[derive(Debug, Clone)]
+pub struct MergeSource {
+    pub path: PathBuf,
+    /// The notebook's pages in the merged PDF, 0-based.
+    pub pages: Range<usize>,
+    /// Address of the notebook's footer metadata block, which lists its pages and header, in its `.note` file.
+    pub metadata_address: u64,
+}
+
+/// What [`merge_notes_to_pdf`] put where, one entry per source notebook in merge order.
+#[derive(Debug, Clone, Default)]
+pub struct MergeManifest {
+    pub sources: Vec<MergeSource>,
+}
+
+/// Converts several `.note` files into one PDF, one after the other.
+///
+/// Each page title becomes a bookmark prefixed with its notebook's file name, e.g. `work: Agenda` for a page of
+/// `work.note`, and the PDF's `/Author` lists the source files (unless [`ConvertOptions::author`] is set). Pages
+/// are rendered as for [`convert_note_to_pdf`], except that the PDF is never split and gets no cover page, padding
+/// or sidecar.
+pub fn merge_notes_to_pdf(input_paths: &[PathBuf], output_path: &Path, options: &ConvertOptions) -> Result<MergeManifest> {
+    let mut notebooks = Vec::with_capacity(input_paths.len());
+    let mut manifest = MergeManifest::default();
+    for input_path in input_paths {
+        let mut notebook = Notebook::from_path(input_path)?;
+        check_notebook(&notebook, input_path, options)?;
+        let stem = input_path.file_stem().unwrap_or_default().to_string_lossy();
+        for page in notebook.pages.iter_mut() {
+            page.title = page.title.take().map(|title| format!("{}: {}", stem, title));
+        }
+        let metadata_address = footer_address(&mut File::open(input_path)?, &CURRENT_FORMAT)?;
+        notebooks.push((notebook, metadata_address));
+    }
+
+    let mut rendered_pages = Vec::new();
+    for ((notebook, metadata_address), input_path) in notebooks.iter().zip(input_paths) {
+        let start = rendered_pages.len();
+        rendered_pages.extend(render_pages(notebook, input_path, options)?);
+        manifest.sources.push(MergeSource {
+            path: input_path.clone(),
+            pages: start..rendered_pages.len(),
+            metadata_address: *metadata_address,
+        });
+    }
+
+    let sources = input_paths
+        .iter()
+        .map(|path| path.file_name().unwrap_or_default().to_string_lossy())
+        .join(", ");
+    let options = &ConvertOptions {
+        author: options.author.clone().or(Some(sources)),
+        ..options.clone()
+    };
+    let (pages, page_chunks) = encode_pages(rendered_pages, options);
+    write_pdf(
+        BufWriter::new(File::create(output_path)?),
+        &pages,
+        &page_chunks,
+        0,
+        &FileHeader::default(),
+        options,
+    )?;
+    Ok(manifest)
+}
+
+/// Joins a page and the page it is compared with into one, left and right. A missing page is left as blank paper
+/// the size of the other one. The result keeps the right-hand page's title, for bookmarks.
+fn side_by_side<'a>(pair: EitherOrBoth<RenderedPage<'a>, RenderedPage<'a>>, paper: Rgba<u8>) -> RenderedPage<'a> {
+    let (left, right) = match pair {
+        EitherOrBoth::Both(left, right) => (Some(left), Some(right)),
+        EitherOrBoth::Left(left) => (Some(left), None),
+        EitherOrBoth::Right(right) => (None, Some(right)),
+    };
+    let reference = right.as_ref().or(left.as_ref()).expect("a comparison pair has at least one page");
+    let page = reference.page;
+    let reference_size = reference.canvas.dimensions();
+    // Both sides were rendered with the same options, so they share one scale.
+    let points_per_pixel = reference.page_size.0 / reference_size.0 as f32;
+
+    let (left_width, left_height) = left.as_ref().map_or(reference_size, |left| left.canvas.dimensions());
+    let (right_width, right_height) = right.as_ref().map_or(reference_size, |right| right.canvas.dimensions());
+    let width = left_width + COMPARE_SEPARATOR_WIDTH + right_width;
+    let height = left_height.max(right_height);
+
+    let mut canvas = RgbaImage::from_pixel(width, height, paper);
+    if let Some(left) = &left {
+        imageops::replace(&mut canvas, &left.canvas, 0, 0);
+    }
+    if let Some(right) = &right {
+        imageops::replace(&mut canvas, &right.canvas, (left_width + COMPARE_SEPARATOR_WIDTH) as i64, 0);
+    }
+    for x in left_width..left_width + COMPARE_SEPARATOR_WIDTH {
+        for y in 0..height {
+            canvas.put_pixel(x, y, COMPARE_SEPARATOR_COLOR);
+        }
+    }
+
+    RenderedPage {
+        page,
+        canvas,
+        layers: Vec::new(),
+        page_size: (width as f32 * points_per_pixel, height as f32 * points_per_pixel),
+    }
+}
+
+/// Room left in every split PDF for the catalog, page tree, bookmarks and cross-reference table.
+const PDF_OVERHEAD_BYTES: u64 = 16 * 1024;
+
+/// Groups consecutive pages into the PDFs they will be written to, so that each stays under
+/// [`ConvertOptions::max_output_size`] and [`ConvertOptions::pages_per_file`]. Without limits everything goes into one PDF.
+fn split_into_parts(page_chunks: &[PdfPageChunk], options: &ConvertOptions) -> Vec<Range<usize>> {
+    let max_size = options.max_output_size.unwrap_or(u64::MAX);
+    let max_pages = options.pages_per_file.unwrap_or(usize::MAX);
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut size = PDF_OVERHEAD_BYTES;
+    for (idx, chunk) in page_chunks.iter().enumerate() {
+        let chunk_size = chunk.encoded_len();
+        if idx > start && (size.saturating_add(chunk_size) > max_size || idx - start == max_pages) {
+            parts.push(start..idx);
+            start = idx;
+            size = PDF_OVERHEAD_BYTES;
+        }
+        size += chunk_size;
+    }
+    parts.push(start..page_chunks.len());
+    parts
+}
+
+/// Groups consecutive pages into chapters for [`ConvertOptions::auto_split_chapters`]: a titled page whose previous page
+/// is untitled starts a new one.
+fn split_into_chapters(pages: &[&Page]) -> Vec<Range<usize>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for idx in 1..pages.len() {
+        if pages[idx].title.is_some() && pages[idx - 1].title.is_none() {
+            parts.push(start..idx);
+            start = idx;
+        }
+    }
+    parts.push(start..pages.len());
+    parts
+}
+
+/// `dir/name.pdf` becomes `dir/name_chapter_01_intro.pdf` for a first chapter titled "Intro".
+fn chapter_path(output_path: &Path, chapter_num: usize, title: Option<&str>) -> PathBuf {
+    let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+    let slug = title.map(slugify).filter(|slug| !slug.is_empty());
+    output_path.with_file_name(format!(
+        "{}_chapter_{:02}_{}.pdf",
+        stem,
+        chapter_num,
+        slug.as_deref().unwrap_or("untitled")
+    ))
+}
+
+/// Longest slug, in characters, used in a file name.
+const MAX_SLUG_LEN: usize = 48;
+
+/// Turns a title into something safe to use in a file name on any filesystem: lowercase letters and digits,
+/// with every other run of characters replaced by a single `-`.
+fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.chars().take(MAX_SLUG_LEN).collect::<String>().trim_end_matches('-').to_string()
+}
+
+/// `dir/name.pdf` becomes `dir/name_001.pdf` for part 1.
+fn numbered_path(output_path: &Path, part_num: usize) -> PathBuf {
+    let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+    output_path.with_file_name(format!("{}_{:03}.pdf", stem, part_num))
+}
+
+/// A decoded layer bitmap: one color code per pixel that still has to go through the color map,
+/// or ready-made pixels for protocols that store an image.
+enum LayerPixels {
+    Codes(Vec<u8>),
+    Codes16(Vec<u16>),
+    Image(RgbaImage),
+}
+
+impl LayerPixels {
+    /// The pixel at position `i` of a row-major `width`-wide page. Pixels outside a stored image are transparent.
+    fn pixel(&self, i: usize, width: usize, color_map: &ColorMap) -> Rgba<u8> {
+        match self {
+            LayerPixels::Codes(codes) => color_map.get(codes[i]),
+            LayerPixels::Codes16(codes) => color_map.get_16bit(codes[i]),
+            LayerPixels::Image(image) => image
+                .get_pixel_checked((i % width) as u32, (i / width) as u32)
+                .copied()
+                .unwrap_or(Rgba([0, 0, 0, 0])),
+        }
+    }
+
+    /// Stacks the layer onto `canvas`. Color codes are first mapped into `scratch`, a page-sized image that is
+    /// reused across layers; every one of its pixels is overwritten, so it doesn't need clearing in between.
+    fn overlay_onto(&self, canvas: &mut RgbaImage, scratch: &mut RgbaImage, color_map: &ColorMap) {
+        match self {
+            LayerPixels::Codes(codes) => {
+                write_pixels(scratch, codes, color_map);
+                imageops::overlay(canvas, scratch, 0, 0);
+            }
+            LayerPixels::Codes16(codes) => {
+                write_pixels16(scratch, codes, color_map);
+                imageops::overlay(canvas, scratch, 0, 0);
+            }
+            LayerPixels::Image(image) => imageops::overlay(canvas, image, 0, 0),
+        }
+    }
+
+    /// Size in bytes of the decoded pixels.
+    fn decoded_len(&self) -> usize {
+        match self {
+            LayerPixels::Codes(codes) => codes.len(),
+            LayerPixels::Codes16(codes) => codes.len() * 2,
+            LayerPixels::Image(image) => image.as_raw().len(),
+        }
+    }
+
+    fn into_image(self, width: usize, height: usize, color_map: &ColorMap) -> RgbaImage {
+        match self {
+            LayerPixels::Codes(codes) => pixels_to_image(&codes, width, height, color_map),
+            LayerPixels::Codes16(codes) => pixels16_to_image(&codes, width, height, color_map),
+            LayerPixels::Image(image) => image,
+        }
+    }
+}
+
+/// Decodes one layer's bitmap block. Layers with an unknown protocol are skipped (`None`). Bitmaps that had to be
+/// padded are reported to `warn`.
+fn decode_layer_pixels(
+    layer: &Layer,
+    data: &[u8],
+    width: usize,
+    height: usize,
+    color_map: &ColorMap,
+    warn: &dyn Fn(&str),
+) -> Result<Option<LayerPixels>> {
+    let pixels = match layer.protocol.as_str() {
+        "RATTA_RLE" if layer.depth == 16 => LayerPixels::Codes16(decode_rle_16bit_reporting(data, width, height, warn)?),
+        "RATTA_RLE" => LayerPixels::Codes(decode_rle_reporting(data, width, height, warn)?),
+        "LZMA" => LayerPixels::Codes(decode_lzma(data, width, height)?),
+        "LZ4" => LayerPixels::Codes(decode_lz4(data, width, height)?),
+        "PNG" => LayerPixels::Image(
+            image::load_from_memory(data)
+                .map_err(|e| SupernoteError::Decode(format!("invalid PNG data: {}", e)))?
+                .to_rgba8(),
+        ),
+        _ => return Ok(None),
+    };
+    if is_highlight_layer(layer) {
+        let mut image = pixels.into_image(width, height, color_map);
+        highlight(&mut image);
+        return Ok(Some(LayerPixels::Image(image)));
+    }
+    Ok(Some(pixels))
+}
+
+/// Decodes one layer's bitmap block into an image. Layers with an unknown protocol are skipped (`None`).
+pub(crate) fn decode_layer(
+    layer: &Layer,
+    data: &[u8],
+    width: usize,
+    height: usize,
+    color_map: &ColorMap,
+    warn: &dyn Fn(&str),
+) -> Result<Option<RgbaImage>> {
+    Ok(decode_layer_pixels(layer, data, width, height, color_map, warn)?.map(|pixels| pixels.into_image(width, height, color_map)))
+}
+
+/// Passes a warning about decoding a layer on page `page_num` (1-based) to the [`ConvertOptions::hook`], naming
+/// the page and layer the way errors do.
+fn layer_warning(page_num: usize, layer: &Layer, options: &ConvertOptions) -> impl Fn(&str) {
+    move |message| options.warn(&format!("page {}, layer {}: {}", page_num, layer.key, message))
+}
+
+/// Decodes a page's layer blocks in parallel with `decode`, timing each one. Layers `decode` skips are `None`.
+fn decode_layers_timed<T: Send>(
+    layer_blocks: &[(&Layer, Vec<u8>)],
+    page_num: usize,
+    decode: impl Fn(&Layer, &[u8]) -> Result<Option<T>> + Sync,
+) -> Result<Vec<(Option<T>, Duration)>> {
+    layer_blocks
+        .par_iter()
+        .map(|(layer, data)| {
+            let (decoded, decode_time) = timed(|| decode(layer, data));
+            decoded
+                .map(|decoded| (decoded, decode_time))
+                .map_err(|e| e.in_layer(page_num, &layer.key))
+        })
+        .collect()
+}
+
+/// Runs `f`, returning its result and how long it took.
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+/// How one layer of a page was decoded and composited, for [`ConvertOptions::print_layers`].
+struct LayerTiming {
+    /// Size in bytes of the decoded pixels.
+    decoded_len: usize,
+    decode: Duration,
+    /// `None` when the page's layers are composited together, tile by tile.
+    composite: Option<Duration>,
+}
+
+impl LayerTiming {
+    fn new(decoded_len: usize, decode: Duration) -> Self {
+        LayerTiming {
+            decoded_len,
+            decode,
+            composite: None,
+        }
+    }
+}
+
+/// The [`ConvertOptions::print_layers`] table for one page: each layer's key, protocol, bitmap address,
+/// compressed and decoded size, and decode and compositing times.
+fn layer_report(
+    page_num: usize,
+    layer_blocks: &[(&Layer, Vec<u8>)],
+    timings: &[Option<LayerTiming>],
+    tiles_time: Option<Duration>,
+    is_cached: bool,
+) -> String {
+    let mut report = format!("Page {}:\n", page_num);
+    if is_cached {
+        report.push_str("  (loaded from the page cache)\n");
+        return report;
+    }
+    for ((layer, data), timing) in layer_blocks.iter().zip(timings) {
+        let (decoded, decode, composite) = match timing {
+            Some(timing) => (
+                format_size(timing.decoded_len),
+                format_ms(timing.decode),
+                timing.composite.map_or_else(|| "-".to_string(), format_ms),
+            ),
+            // Unknown protocols aren't decoded at all, and custom renderers aren't timed.
+            None => ("-".to_string(), "-".to_string(), "-".to_string()),
+        };
+        report.push_str(&format!(
+            "  {:<12} {:<10} 0x{:08X}  {:>7} → {:<7}  {:>6}  {:>6}\n",
+            layer.key,
+            layer.protocol,
+            layer.bitmap_address,
+            format_size(data.len()),
+            decoded,
+            decode,
+            composite
+        ));
+    }
+    if let Some(tiles_time) = tiles_time {
+        report.push_str(&format!("  composited together in tiles: {}\n", format_ms(tiles_time)));
+    }
+    report
+}
+
+/// A byte count in the largest unit that keeps it at least 1, e.g. `45KB` or `2.6MB`.
+fn format_size(bytes: usize) -> String {
+    const KB: f64 = 1024.0;
+    let bytes_f = bytes as f64;
+    if bytes_f >= KB * KB {
+        format!("{:.1}MB", bytes_f / (KB * KB))
+    } else if bytes_f >= KB {
+        format!("{:.0}KB", bytes_f / KB)
+    } else {
+        format!("{}B", bytes)
+    }
+}
+
+fn format_ms(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+/// Key of the layer that highlighter strokes are stored in.
+const HIGHLIGHT_LAYER_KEY: &str = "HIGHLAYER";
+
+/// Whether a layer holds highlighter strokes, which are drawn as a translucent tint rather than as ink.
+fn is_highlight_layer(layer: &Layer) -> bool {
+    layer.key == HIGHLIGHT_LAYER_KEY
+}
+
+/// Whether a page's only bitmap is an 8-bit RATTA_RLE ink layer, which can be mapped straight into the page image.
+fn is_single_rle_layer(layer_blocks: &[(&Layer, Vec<u8>)]) -> bool {
+    matches!(layer_blocks, [(layer, _)] if layer.protocol == "RATTA_RLE" && layer.depth != 16 && !is_highlight_layer(layer))
+}
+
+/// Pages with at least this many pixels (the A5X2's, but not the A5X's) are composited tile by tile.
+const TILED_RENDER_MIN_PIXELS: usize = 4_000_000;
+/// Width and height of one tile in [`render_tiled`]; tiles on the right and bottom edges may be smaller.
+const TILE_SIZE: usize = 256;
+
+/// Composites decoded layers, in order, onto a `width x height` page of `background`.
+///
+/// The page is split into tiles that are rendered in parallel, each reading only its own part of every layer, and then
+/// copied into place. Unlike decoding every layer into a full-page image first, this allocates a single page of pixels.
+fn render_tiled(layers: &[LayerPixels], width: usize, height: usize, color_map: &ColorMap, background: Rgba<u8>) -> RgbaImage {
+    let tile_origins: Vec<(usize, usize)> = (0..height).step_by(TILE_SIZE).cartesian_product((0..width).step_by(TILE_SIZE)).collect();
+
+    let tiles: Vec<RgbaImage> = tile_origins
+        .par_iter()
+        .map(|&(tile_y, tile_x)| {
+            let tile_width = TILE_SIZE.min(width - tile_x);
+            let tile_height = TILE_SIZE.min(height - tile_y);
+            let mut tile = RgbaImage::from_pixel(tile_width as u32, tile_height as u32, background);
+            for layer in layers {
+                for (x, y, pixel) in tile.enumerate_pixels_mut() {
+                    let i = (tile_y + y as usize) * width + tile_x + x as usize;
+                    pixel.blend(&layer.pixel(i, width, color_map));
+                }
+            }
+            tile
+        })
+        .collect();
+
+    let mut canvas = RgbaImage::new(width as u32, height as u32);
+    for (&(tile_y, tile_x), tile) in tile_origins.iter().zip(tiles) {
+        imageops::replace(&mut canvas, &tile, tile_x as i64, tile_y as i64);
+    }
+    canvas
+}
+
+/// Rotates a finished page or layer image clockwise by a multiple of 90 degrees.
+fn rotate_image(image: RgbaImage, degrees: u32) -> RgbaImage {
+    match degrees % 360 {
+        90 => imageops::rotate90(&image),
+        180 => imageops::rotate180(&image),
+        270 => imageops::rotate270(&image),
+        _ => image,
+    }
+}
+
+/// Compresses an image's RGB channels, dropping alpha. Used for composited pages on opaque paper.
+fn encode_rgb_image(canvas: RgbaImage, level: Compression) -> EncodedImage {
+    let (width, height) = canvas.dimensions();
+    let dynamic_image = image::DynamicImage::ImageRgba8(canvas);
+    let raw_pixels = dynamic_image.to_rgb8().into_raw();
+
+    EncodedImage {
+        width,
+        height,
+        color_space: "/DeviceRGB",
+        filter: ImageFilter::Flate,
+        data: flate_with_level(&raw_pixels, level),
+        smask: None,
+    }
+}
+
+/// Compresses an image's RGB channels, with its alpha channel as a soft mask so lower layers show through.
+fn encode_rgba_image(layer_image: RgbaImage, level: Compression) -> EncodedImage {
+    let alpha: Vec<u8> = layer_image.pixels().map(|p| p.0[3]).collect();
+    let mut encoded = encode_rgb_image(layer_image, level);
+    encoded.smask = Some(Box::new(EncodedImage {
+        width: encoded.width,
+        height: encoded.height,
+        color_space: "/DeviceGray",
+        filter: ImageFilter::Flate,
+        data: flate_with_level(&alpha, level),
+        smask: None,
+    }));
+    encoded
+}
+
+/// JPEG quality (1-100) of [`ImageEncoding::Jpeg`] pages. High enough that pen strokes stay free of visible ringing.
+const JPEG_QUALITY: u8 = 85;
+
+/// Compresses an image's RGB channels as a JPEG of the given quality (1-100), dropping alpha.
+fn encode_jpeg_image(canvas: RgbaImage, quality: u8) -> EncodedImage {
+    let (width, height) = canvas.dimensions();
+    let rgb = image::DynamicImage::ImageRgba8(canvas).to_rgb8();
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, quality)
+        .encode_image(&rgb)
+        .expect("encoding an RGB image to memory cannot fail");
+    EncodedImage {
+        width,
+        height,
+        color_space: "/DeviceRGB",
+        filter: ImageFilter::Dct,
+        data,
+        smask: None,
+    }
+}
+
+/// Compresses an image as a 1-bit CCITT Group 4 image: pixels darker than mid-gray become black, the rest white.
+fn encode_ccitt_image(canvas: RgbaImage) -> EncodedImage {
+    let (width, height) = canvas.dimensions();
+    let mut encoder = fax::encoder::Encoder::new(fax::VecWriter::new());
+    for row in canvas.rows() {
+        let pels = row.map(|p| if luminance(*p) < 128 { fax::Color::Black } else { fax::Color::White });
+        encoder.encode_line(pels, width).expect("writing to a Vec cannot fail");
+    }
+    let data = encoder.finish().expect("writing to a Vec cannot fail").finish();
+    EncodedImage {
+        width,
+        height,
+        color_space: "/DeviceGray",
+        filter: ImageFilter::CcittGroup4,
+        data,
+        smask: None,
+    }
+}
+
+/// Pixels at most this far from pure black or white count as black-and-white for [`detect_optimal_encoding`].
+const BINARY_PIXEL_TOLERANCE: u8 = 32;
+/// Share of pixels that must be black-and-white for a page to be encoded as CCITT.
+const CCITT_MIN_BINARY_FRACTION: f64 = 0.995;
+/// Share of photo pixels from which a page is encoded as JPEG.
+const JPEG_MIN_PHOTO_FRACTION: f64 = 0.1;
+/// Pen ink, paper and the gray pens only take a handful of gray levels; pixels at any other level are photo content.
+const INK_GRAY_LEVELS: usize = 8;
+
+/// Picks the encoding for a composited page from the spread of its pixel values: pages that are almost entirely
+/// black and white get [`ImageEncoding::Ccitt`], pages with large photo regions get [`ImageEncoding::Jpeg`], and
+/// everything else, including pages drawn with the gray pens, gets [`ImageEncoding::Flate`].
+///
+/// Photo pixels are those that are colorful, or gray but at a level outside the few most common ones (ink, paper and
+/// the gray pens).
+pub fn detect_optimal_encoding(img: &RgbaImage) -> ImageEncoding {
+    let total = img.pixels().len();
+    if total == 0 {
+        return ImageEncoding::Flate;
+    }
+    let mut gray_histogram = [0usize; 256];
+    let mut colorful = 0;
+    let mut binary = 0;
+    for &p in img.pixels() {
+        if !is_ink_pixel(p) {
+            colorful += 1;
+            continue;
+        }
+        let lum = luminance(p);
+        gray_histogram[lum as usize] += 1;
+        if lum <= BINARY_PIXEL_TOLERANCE || lum >= 255 - BINARY_PIXEL_TOLERANCE {
+            binary += 1;
+        }
+    }
+    if binary as f64 >= total as f64 * CCITT_MIN_BINARY_FRACTION {
+        return ImageEncoding::Ccitt;
+    }
+    gray_histogram.sort_unstable_by(|a, b| b.cmp(a));
+    let ink_levels: usize = gray_histogram[..INK_GRAY_LEVELS].iter().sum();
+    let photo = colorful + (total - colorful - ink_levels);
+    if photo as f64 >= total as f64 * JPEG_MIN_PHOTO_FRACTION {
+        ImageEncoding::Jpeg
+    } else {
+        ImageEncoding::Flate
+    }
+}
+
+fn flate(data: &[u8]) -> Vec<u8> {
+    flate_with_level(data, Compression::default())
+}
+
+fn flate_with_level(data: &[u8], level: Compression) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), level);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Writes PDF objects one after another, recording each object's byte offset for the cross-reference table.
+struct PdfWriter<W: Write> {
+    writer: W,
+    byte_offset: u64,
+    xref_offsets: Vec<u64>,
+}
+
+impl<W: Write> PdfWriter<W> {
+    fn new(mut writer: W) -> Result<Self> {
+        let header = b"%PDF-1.7\n%\xE2\xE3\xCF\xD3\n"; // Header + binary comment
+        writer.write_all(header)?;
+        Ok(PdfWriter {
+            writer,
+            byte_offset: header.len() as u64,
+            xref_offsets: Vec::new(),
+        })
+    }
+
+    /// Reserves the next object id. Objects may be written in any order once their id is reserved.
+    fn alloc_id(&mut self) -> usize {
+        self.xref_offsets.push(0);
+        self.xref_offsets.len()
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        self.byte_offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Writes `id 0 obj << dict >> endobj`.
+    fn write_object(&mut self, id: usize, dict: &str) -> Result<()> {
+        self.xref_offsets[id - 1] = self.byte_offset;
+        self.write_raw(format!("{} 0 obj\n<< {} >>\nendobj\n", id, dict).as_bytes())
+    }
+
+    /// Writes a stream object; `/Length` is added to the dictionary automatically.
+    fn write_stream(&mut self, id: usize, dict: &str, data: &[u8]) -> Result<()> {
+        self.xref_offsets[id - 1] = self.byte_offset;
+        let dict = if dict.is_empty() {
+            format!("/Length {}", data.len())
+        } else {
+            format!("{} /Length {}", dict, data.len())
+        };
+        self.write_raw(format!("{} 0 obj\n<< {} >>\nstream\n", id, dict).as_bytes())?;
+        self.write_raw(data)?;
+        self.write_raw(b"\nendstream\nendobj\n")
+    }
+
+    /// Writes a stream of text, such as a content stream, as is or, if `compress` is set, Flate-compressed.
+    fn write_text_stream(&mut self, id: usize, dict: &str, text: &str, compress: bool) -> Result<()> {
+        if !compress {
+            return self.write_stream(id, dict, text.as_bytes());
+        }
+        let dict = if dict.is_empty() {
+            "/Filter /FlateDecode".to_string()
+        } else {
+            format!("{} /Filter /FlateDecode", dict)
+        };
+        self.write_stream(id, &dict, &flate(text.as_bytes()))
+    }
+
+    /// Writes an image XObject, and its soft mask if it has one.
+    fn write_image(&mut self, id: usize, image: &EncodedImage) -> Result<()> {
+        let smask = match &image.smask {
+            Some(smask) => {
+                let smask_id = self.alloc_id();
+                self.write_image(smask_id, smask)?;
+                format!(" /SMask {} 0 R", smask_id)
+            }
+            None => String::new(),
+        };
+        let filter = match image.filter {
+            ImageFilter::Flate => "/BitsPerComponent 8 /Filter /FlateDecode".to_string(),
+            ImageFilter::Dct => "/BitsPerComponent 8 /Filter /DCTDecode".to_string(),
+            ImageFilter::CcittGroup4 => format!(
+                "/BitsPerComponent 1 /Filter /CCITTFaxDecode /DecodeParms << /K -1 /Columns {} /Rows {} >>",
+                image.width, image.height
+            ),
+        };
+        let dict = format!(
+            "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} {}{}",
+            image.width, image.height, image.color_space, filter, smask
+        );
+        self.write_stream(id, &dict, &image.data)
+    }
+
+    /// Writes the cross-reference table and trailer, and flushes.
+    fn finish(mut self, root_id: usize, info_id: usize) -> Result<()> {
+        let xref_start_offset = self.byte_offset;
+        let mut xref = format!("xref\n0 {}\n", self.xref_offsets.len() + 1);
+        xref.push_str("0000000000 65535 f \n"); // XRef entry for object 0
+        for offset in &self.xref_offsets {
+            xref.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        xref.push_str(&format!(
+            "trailer\n<< /Size {} /Root {} 0 R /Info {} 0 R >>\n",
+            self.xref_offsets.len() + 1,
+            root_id,
+            info_id
+        ));
+        xref.push_str(&format!("startxref\n{}\n%%EOF\n", xref_start_offset));
+        self.write_raw(xref.as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Assembles the PDF: one page per chunk, bookmarks for titled pages and optional content groups for layers.
+/// `first_page_index` is the position of the first page among all exported pages, which matters when the output
+/// is split into several PDFs. The notebook's title, if it has one, becomes the document's `/Title`.
+fn write_pdf<W: Write>(
+    writer: W,
+    pages: &[&Page],
+    page_chunks: &[PdfPageChunk],
+    first_page_index: usize,
+    header: &FileHeader,
+    options: &ConvertOptions,
+) -> Result<()> {
+    let paper = options.paper_color();
+    let mut pdf = PdfWriter::new(writer)?;
+    let catalog_id = pdf.alloc_id();
+    let pages_root_id = pdf.alloc_id();
+
+    // One optional content group per distinct layer name, in order of first appearance.
+    let mut ocg_names: Vec<&str> = Vec::new();
+    for chunk in page_chunks.iter() {
+        for (ocg, _) in chunk.images.iter() {
+            if let Some(name) = ocg.as_deref()
+                && !ocg_names.contains(&name)
+            {
+                ocg_names.push(name);
+            }
+        }
+    }
+    let ocg_ids: Vec<usize> = ocg_names.iter().map(|_| pdf.alloc_id()).collect();
+    for (&id, name) in ocg_ids.iter().zip(ocg_names.iter()) {
+        pdf.write_object(id, &format!("/Type /OCG /Name {}", pdf_text_string(name)))?;
+    }
+
+    // Every page shares the one font.
+    let fonts = if options.embed_fonts {
+        format!(" /Font << /F1 {} 0 R >>", write_text_font(&mut pdf)?)
+    } else {
+        String::new()
+    };
+
+    // --- Write all the pages : cannot be parallelised ---
+    let mut page_ids = Vec::with_capacity(page_chunks.len());
+    let mut figure_ids = Vec::new();
+    for (page_idx, chunk) in page_chunks.iter().enumerate() {
+        let (page_width, page_height) = (pdf_number(chunk.page_size.0), pdf_number(chunk.page_size.1));
+        let page_id = pdf.alloc_id();
+        let contents_id = pdf.alloc_id();
+        let image_ids: Vec<usize> = chunk.images.iter().map(|_| pdf.alloc_id()).collect();
+        page_ids.push(page_id);
+
+        let mut xobjects = String::new();
+        let mut properties = String::new();
+        let mut contents = String::new();
+        if paper.0[3] != 0 && chunk.images.iter().any(|(ocg, _)| ocg.is_some()) {
+            // Layers are transparent, so paint the paper first.
+            let [r, g, b, _] = paper.0.map(|c| c as f32 / 255.0);
+            let fill = format!("q\n{} {} {} rg\n0 0 {} {} re\nf\nQ\n", r, g, b, page_width, page_height);
+            if options.pdf_ua {
+                // Tagged PDFs must mark content that isn't part of the document's structure as an artifact.
+                contents.push_str(&format!("/Artifact BMC\n{}EMC\n", fill));
+            } else {
+                contents.push_str(&fill);
+            }
+        }
+        if options.pdf_ua {
+            // All of the page's images make up its one figure, marked content 0 of the page.
+            contents.push_str("/Figure << /MCID 0 >> BDC\n");
+        }
+        for (i, ((ocg, _), image_id)) in chunk.images.iter().zip(image_ids.iter()).enumerate() {
+            xobjects.push_str(&format!(" /Im{} {} 0 R", i + 1, image_id));
+            let draw = format!("q\n{} 0 0 {} 0 0 cm\n/Im{} Do\nQ\n", page_width, page_height, i + 1);
+            match ocg.as_deref() {
+                Some(name) => {
+                    let ocg_idx = ocg_names.iter().position(|n| *n == name).unwrap();
+                    properties.push_str(&format!(" /OC{} {} 0 R", ocg_idx, ocg_ids[ocg_idx]));
+                    contents.push_str(&format!("/OC /OC{} BDC\n{}EMC\n", ocg_idx, draw));
+                }
+                None => contents.push_str(&draw),
+            }
+        }
+        let properties = if properties.is_empty() {
+            String::new()
+        } else {
+            format!(" /Properties <<{} >>", properties)
+        };
+        let mut structure = String::new();
+        if options.pdf_ua {
+            contents.push_str("EMC\n");
+            figure_ids.push(pdf.alloc_id());
+            // The page's entry in the structure tree's parent tree is its index.
+            structure.push_str(&format!(" /StructParents {} /Tabs /S", page_idx));
+        }
+
+        pdf.write_object(
+            page_id,
+            &format!(
+                "/Type /Page /Parent {} 0 R /MediaBox [0 0 {} {}] /Contents {} 0 R /Resources << /XObject <<{} >>{}{} >>{}",
+                pages_root_id, page_width, page_height, contents_id, xobjects, fonts, properties, structure
+            ),
+        )?;
+        pdf.write_text_stream(contents_id, "", &contents, options.compress_metadata)?;
+        for ((_, image), &image_id) in chunk.images.iter().zip(image_ids.iter()) {
+            pdf.write_image(image_id, image)?;
+        }
+    }
+
+    // --- Write the bookmarks: titled pages get one ---
+    let titled_pages: Vec<(usize, &str)> = pages
+        .iter()
+        .zip(page_ids.iter())
+        .filter_map(|(page, &page_id)| page.title.as_deref().map(|title| (page_id, title)))
+        .collect();
+    let mut catalog_extra = String::new();
+    let mut page_mode = None;
+    if !titled_pages.is_empty() {
+        let outline_root_id = write_outlines(&mut pdf, &titled_pages)?;
+        catalog_extra.push_str(&format!(" /Outlines {} 0 R", outline_root_id));
+        page_mode = Some("/UseOutlines");
+    }
+
+    // --- How the document opens ---
+    let mut viewer_preferences = String::new();
+    if options.viewer.full_screen {
+        // Leaving full screen goes back to whatever mode the document would otherwise open in.
+        if let Some(mode) = page_mode {
+            viewer_preferences.push_str(&format!(" /NonFullScreenPageMode {}", mode));
+        }
+        page_mode = Some("/FullScreen");
+    }
+    if options.viewer.fit_window {
+        viewer_preferences.push_str(" /FitWindow true");
+    }
+    if options.pdf_ua {
+        viewer_preferences.push_str(" /DisplayDocTitle true");
+    }
+    if let Some(mode) = page_mode {
+        catalog_extra.push_str(&format!(" /PageMode {}", mode));
+    }
+    if options.viewer.two_page {
+        catalog_extra.push_str(" /PageLayout /TwoPageLeft");
+    }
+    if !viewer_preferences.is_empty() {
+        catalog_extra.push_str(&format!(" /ViewerPreferences <<{} >>", viewer_preferences));
+    }
+    if !ocg_ids.is_empty() {
+        let refs = ocg_ids.iter().map(|id| format!("{} 0 R", id)).join(" ");
+        catalog_extra.push_str(&format!(
+            " /OCProperties << /OCGs [{}] /D << /Order [{}] /ON [{}] >> >>",
+            refs, refs, refs
+        ));
+    }
+
+    if options.page_label_offset.is_some() || options.page_label_style.is_some() {
+        let first_label = options.page_label_offset.unwrap_or(1) + first_page_index;
+        let style = match options.page_label_style.unwrap_or_default() {
+            PageLabelStyle::Decimal => "/D",
+            PageLabelStyle::LowerRoman => "/r",
+        };
+        catalog_extra.push_str(&format!(" /PageLabels << /Nums [0 << /S {} /St {} >>] >>", style, first_label));
+    }
+
+    // --- Write the structure tree for tagged output ---
+    if options.pdf_ua {
+        let title = header.note_title.as_deref().unwrap_or(DEFAULT_UA_TITLE);
+        let struct_root_id = write_structure_tree(&mut pdf, &page_ids, &figure_ids, first_page_index)?;
+        let metadata_id = pdf.alloc_id();
+        pdf.write_text_stream(
+            metadata_id,
+            "/Type /Metadata /Subtype /XML",
+            &pdf_ua_metadata(title, options.lang.as_deref().unwrap_or(DEFAULT_UA_LANG)),
+            options.compress_metadata,
+        )?;
+        catalog_extra.push_str(&format!(
+            " /MarkInfo << /Marked true >> /StructTreeRoot {} 0 R /Metadata {} 0 R",
+            struct_root_id, metadata_id
+        ));
+    }
+    if let Some(lang) = options.lang.as_deref().or(options.pdf_ua.then_some(DEFAULT_UA_LANG)) {
+        catalog_extra.push_str(&format!(" /Lang {}", pdf_text_string(lang)));
+    }
+
+    // --- Write the catalog and the root Pages object, now that every id they refer to is known ---
+    pdf.write_object(catalog_id, &format!("/Type /Catalog /Pages {} 0 R{}", pages_root_id, catalog_extra))?;
+    let page_refs = page_ids.iter().map(|id| format!("{} 0 R", id)).join(" ");
+    pdf.write_object(pages_root_id, &format!("/Type /Pages /Kids [ {} ] /Count {}", page_refs, page_ids.len()))?;
+
+    // --- Write the document information dictionary ---
+    let default_producer = format!("supernote_pdf {}", env!("CARGO_PKG_VERSION"));
+    let mut info = format!(
+        "/Producer {} /Creator {}",
+        pdf_text_string(options.producer.as_deref().unwrap_or(&default_producer)),
+        pdf_text_string(options.creator.as_deref().unwrap_or(&default_producer))
+    );
+    if let Some(title) = header.note_title.as_deref().or(options.pdf_ua.then_some(DEFAULT_UA_TITLE)) {
+        info.push_str(&format!(" /Title {}", pdf_text_string(title)));
+    }
+    if let Some(author) = options.author.as_deref() {
+        info.push_str(&format!(" /Author {}", pdf_text_string(author)));
+    }
+    let info_id = pdf.alloc_id();
+    pdf.write_object(info_id, &info)?;
+
+    pdf.finish(catalog_id, info_id)
+}
+
+/// Writes [`TEXT_FONT`] as a Type 0 font with Identity-H encoding, so text is drawn with 2-byte glyph ids, and a
+/// `/ToUnicode` map so text drawn with it can be copied and searched. Returns the id of the font.
+#[cfg(feature = "fonts")]
+fn write_text_font<W: Write>(pdf: &mut PdfWriter<W>) -> Result<usize> {
+    let font = &*TEXT_FONT;
+    let font_id = pdf.alloc_id();
+    let cid_font_id = pdf.alloc_id();
+    let descriptor_id = pdf.alloc_id();
+    let file_id = pdf.alloc_id();
+    let to_unicode_id = pdf.alloc_id();
+
+    pdf.write_object(
+        font_id,
+        &format!(
+            "/Type /Font /Subtype /Type0 /BaseFont /{} /Encoding /Identity-H /DescendantFonts [{} 0 R] /ToUnicode {} 0 R",
+            TEXT_FONT_NAME, cid_font_id, to_unicode_id
+        ),
+    )?;
+    pdf.write_object(
+        cid_font_id,
+        &format!(
+            "/Type /Font /Subtype /CIDFontType2 /BaseFont /{} /CIDSystemInfo << /Registry (Adobe) /Ordering (Identity) /Supplement 0 >> /FontDescriptor {} 0 R /W [1 [{}]] /CIDToGIDMap /Identity",
+            TEXT_FONT_NAME,
+            descriptor_id,
+            font.widths.iter().join(" ")
+        ),
+    )?;
+    // Flags 32: nonsymbolic, i.e. the font's characters are in the standard Latin character set.
+    pdf.write_object(
+        descriptor_id,
+        &format!(
+            "/Type /FontDescriptor /FontName /{} /Flags 32 /FontBBox [{}] /ItalicAngle 0 /Ascent {} /Descent {} /CapHeight {} /StemV 80 /FontFile2 {} 0 R",
+            TEXT_FONT_NAME,
+            font.bbox.iter().join(" "),
+            font.ascent,
+            font.descent,
+            font.cap_height,
+            file_id
+        ),
+    )?;
+    pdf.write_stream(file_id, &format!("/Length1 {} /Filter /FlateDecode", font.data.len()), &flate(&font.data))?;
+
+    let mappings: String = font
+        .chars
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| format!("<{:04X}> <{:04X}>\n", i + 1, c as u32))
+        .collect();
+    let cmap = format!(
+        concat!(
+            "/CIDInit /ProcSet findresource begin\n12 dict begin\nbegincmap\n",
+            "/CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n",
+            "/CMapName /Adobe-Identity-UCS def\n/CMapType 2 def\n",
+            "1 begincodespacerange\n<0000> <FFFF>\nendcodespacerange\n",
+            "{} beginbfchar\n{}endbfchar\n",
+            "endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend\n"
+        ),
+        font.chars.len(),
+        mappings
+    );
+    pdf.write_stream(to_unicode_id, "/Filter /FlateDecode", &flate(cmap.as_bytes()))?;
+    Ok(font_id)
+}
+
+/// Stand-in for builds without the `fonts` feature.
+#[cfg(not(feature = "fonts"))]
+fn write_text_font<W: Write>(_: &mut PdfWriter<W>) -> Result<usize> {
+    Err(font_left_out("embedded fonts"))
+}
+
+/// Title of tagged PDFs whose notebook has none, since PDF/UA requires one.
+const DEFAULT_UA_TITLE: &str = "Handwritten notes";
+/// Language of tagged PDFs when none is given, since PDF/UA requires one.
+const DEFAULT_UA_LANG: &str = "en";
+
+/// Writes the structure tree of a tagged PDF: a `Document` element holding one `Figure` per page, whose ids were
+/// allocated while writing the pages, and the parent tree that maps each page's marked content back to its figure.
+/// Returns the id of the structure tree root.
+fn write_structure_tree<W: Write>(pdf: &mut PdfWriter<W>, page_ids: &[usize], figure_ids: &[usize], first_page_index: usize) -> Result<usize> {
+    let root_id = pdf.alloc_id();
+    let document_id = pdf.alloc_id();
+
+    for (i, (&figure_id, &page_id)) in figure_ids.iter().zip(page_ids).enumerate() {
+        let alt = format!("Handwritten note page {}", first_page_index + i + 1);
+        pdf.write_object(
+            figure_id,
+            &format!(
+                "/Type /StructElem /S /Figure /P {} 0 R /Pg {} 0 R /K 0 /Alt {}",
+                document_id,
+                page_id,
+                pdf_text_string(&alt)
+            ),
+        )?;
+    }
+
+    let figure_refs = figure_ids.iter().map(|id| format!("{} 0 R", id)).join(" ");
+    pdf.write_object(
+        document_id,
+        &format!("/Type /StructElem /S /Document /P {} 0 R /K [{}]", root_id, figure_refs),
+    )?;
+    let parent_tree = figure_ids.iter().enumerate().map(|(i, id)| format!("{} [{} 0 R]", i, id)).join(" ");
+    pdf.write_object(
+        root_id,
+        &format!(
+            "/Type /StructTreeRoot /K [{} 0 R] /ParentTree << /Nums [{}] >> /ParentTreeNextKey {}",
+            document_id,
+            parent_tree,
+            figure_ids.len()
+        ),
+    )?;
+    Ok(root_id)
+}
+
+/// XMP metadata for a tagged PDF: the PDF/UA part it claims to follow, the title viewers show and the language.
+fn pdf_ua_metadata(title: &str, lang: &str) -> String {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+            "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+            "<rdf:Description rdf:about=\"\" xmlns:pdfuaid=\"http://www.aiim.org/pdfua/ns/id/\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n",
+            "<pdfuaid:part>1</pdfuaid:part>\n",
+            "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>\n",
+            "<dc:language><rdf:Bag><rdf:li>{}</rdf:li></rdf:Bag></dc:language>\n",
+            "</rdf:Description>\n",
+            "</rdf:RDF>\n",
+            "</x:xmpmeta>\n",
+            "<?xpacket end=\"w\"?>"
+        ),
+        escape(title),
+        escape(lang)
+    )
+}
+
+/// Writes the document outline: an `/Outlines` root with one bookmark per `(page id, title)` entry,
+/// each jumping to its page. Returns the id of the outline root.
+fn write_outlines<W: Write>(pdf: &mut PdfWriter<W>, entries: &[(usize, &str)]) -> Result<usize> {
+    let root_id = pdf.alloc_id();
+    let ids: Vec<usize> = entries.iter().map(|_| pdf.alloc_id()).collect();
+
+    pdf.write_object(
+        root_id,
+        &format!(
+            "/Type /Outlines /First {} 0 R /Last {} 0 R /Count {}",
+            ids[0],
+            ids[ids.len() - 1],
+            entries.len()
+        ),
+    )?;
+
+    for (k, (page_id, title)) in entries.iter().enumerate() {
+        let mut siblings = String::new();
+        if k > 0 {
+            siblings.push_str(&format!(" /Prev {} 0 R", ids[k - 1]));
+        }
+        if k + 1 < ids.len() {
+            siblings.push_str(&format!(" /Next {} 0 R", ids[k + 1]));
+        }
+        pdf.write_object(
+            ids[k],
+            &format!(
+                "/Title {} /Parent {} 0 R{} /Dest [{} 0 R /Fit]",
+                pdf_text_string(title),
+                root_id,
+                siblings,
+                page_id
+            ),
+        )?;
+    }
+
+    Ok(root_id)
+}
+
+/// Formats a real number for PDF content, with at most two decimals and no trailing zeros.
+fn pdf_number(value: f32) -> String {
+    let formatted = format!("{:.2}", value);
+    formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Encodes text as a PDF text string: UTF-16BE with a byte order mark, written in hex so nothing needs escaping.
+fn pdf_text_string(text: &str) -> String {
+    let mut hex = String::from("<FEFF");
+    for unit in text.encode_utf16() {
+        hex.push_str(&format!("{:04X}", unit));
+    }
+    hex.push('>');
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic, well-mixed values for test pixels.
+    fn scatter(i: usize) -> usize {
+        i.wrapping_mul(2654435761) >> 7
+    }
+
+    /// Composites `layers` the way pages below [`TILED_RENDER_MIN_PIXELS`] are: one full-page layer at a time.
+    fn render_untiled(layers: &[LayerPixels], width: usize, height: usize, color_map: &ColorMap, background: Rgba<u8>) -> RgbaImage {
+        let mut canvas = RgbaImage::from_pixel(width as u32, height as u32, background);
+        let mut scratch = RgbaImage::new(width as u32, height as u32);
+        for layer in layers {
+            layer.overlay_onto(&mut canvas, &mut scratch, color_map);
+        }
+        canvas
+    }
+
+    #[test]
+    fn titles_are_slugified() {
+        let long_words = format!("{} b", "a".repeat(MAX_SLUG_LEN - 1));
+        let cases = [
+            ("Meeting Notes", "meeting-notes".to_string()),
+            ("  Q3: Plan / Budget!! ", "q3-plan-budget".to_string()),
+            ("Über Straße", "über-straße".to_string()),
+            ("日本語のノート", "日本語のノート".to_string()),
+            ("🚀 Launch — Day 2", "launch-day-2".to_string()),
+            ("!!! ??? ...", String::new()),
+            ("", String::new()),
+            // Cut at MAX_SLUG_LEN characters, right after a separator, which is then dropped.
+            (&long_words, "a".repeat(MAX_SLUG_LEN - 1)),
+            // Characters, not bytes, are counted.
+            (&"é".repeat(60), "é".repeat(MAX_SLUG_LEN)),
+        ];
+        for (title, slug) in cases {
+            assert_eq!(slugify(title), slug, "slug of {:?}", title);
+        }
+    }
+
+    #[test]
+    fn chapters_are_named_after_their_titles() {
+        let output_path = Path::new("out/notes.pdf");
+        let cases = [
+            (1, Some("Intro"), "out/notes_chapter_01_intro.pdf"),
+            (2, Some("Über uns"), "out/notes_chapter_02_über-uns.pdf"),
+            (3, Some("???"), "out/notes_chapter_03_untitled.pdf"),
+            (12, None, "out/notes_chapter_12_untitled.pdf"),
+            (100, Some("Last"), "out/notes_chapter_100_last.pdf"),
+        ];
+        for (chapter_num, title, path) in cases {
+            assert_eq!(
+                chapter_path(output_path, chapter_num, title),
+                Path::new(path),
+                "chapter {} {:?}",
+                chapter_num,
+                title
+            );
+        }
+    }
+
+    /// A page whose [`PdfPageChunk::encoded_len`] is `len` bytes.
+    fn chunk(len: u64) -> PdfPageChunk {
+        let image = EncodedImage {
+            width: 1,
+            height: 1,
+            color_space: "/DeviceRGB",
+            filter: ImageFilter::Flate,
+            data: vec![0; len as usize - 768],
+            smask: None,
+        };
+        PdfPageChunk {
+            images: vec![(None, image)],
+            page_size: (1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn pages_are_split_into_parts_within_the_limits() {
+        // Two 2,000-byte pages fit next to the overhead, three don't.
+        let max_two = Some(PDF_OVERHEAD_BYTES + 4_500);
+        // Page sizes, max_output_size, pages_per_file and the number of pages in each part.
+        let cases = [
+            (vec![2_000; 3], None, None, vec![3]),
+            (vec![2_000; 5], None, Some(2), vec![2, 2, 1]),
+            (vec![2_000; 5], max_two, None, vec![2, 2, 1]),
+            (vec![2_000; 5], max_two, Some(1), vec![1, 1, 1, 1, 1]),
+            // A page larger than the limit still goes into a PDF, on its own.
+            (vec![2_000, 100_000, 2_000], max_two, None, vec![1, 1, 1]),
+            (vec![100_000], max_two, None, vec![1]),
+            (vec![100_000, 2_000, 2_000], max_two, None, vec![1, 2]),
+        ];
+        for (sizes, max_output_size, pages_per_file, part_lens) in cases {
+            let chunks = sizes.iter().map(|&size| chunk(size)).collect_vec();
+            let options = ConvertOptions {
+                max_output_size,
+                pages_per_file,
+                ..ConvertOptions::default()
+            };
+            let parts = split_into_parts(&chunks, &options);
+            assert_eq!(parts.iter().map(|part| part.len()).collect_vec(), part_lens, "pages of {:?}", sizes);
+            assert!(parts.iter().tuple_windows().all(|(a, b)| a.end == b.start), "parts {:?} have gaps", parts);
+        }
+    }
+
+    #[test]
+    fn tiled_and_untiled_rendering_give_the_same_pixels() {
+        // The A5X2's width, 7.5 tiles, and a height that leaves a partial row of tiles at the bottom too.
+        let (width, height) = (1920, 600);
+        let color_map = ColorMap::default();
+        let codes = [0x61, 0x62, 0x63, 0x64, 0x65, 0x9d, 0x9e, 0xc9, 0xca];
+
+        let main_layer = LayerPixels::Codes((0..width * height).map(|i| codes[scatter(i) % codes.len()]).collect());
+        let codes16 = LayerPixels::Codes16((0..width * height).map(|i| (scatter(i + 1) % 0x300) as u16).collect());
+        // A PNG layer stored smaller than the page: the rest of the page is transparent.
+        let png = LayerPixels::Image(RgbaImage::from_fn(1000, 333, |x, y| {
+            let n = scatter((y * 1000 + x) as usize);
+            Rgba([n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8])
+        }));
+        let mut highlights = pixels_to_image(
+            &(0..width * height)
+                .map(|i| if scatter(i + 2).is_multiple_of(4) { 0x61 } else { 0x62 })
+                .collect_vec(),
+            width,
+            height,
+            &color_map,
+        );
+        highlight(&mut highlights);
+        let layers = [main_layer, codes16, png, LayerPixels::Image(highlights)];
+
+        for background in [Rgba([255, 255, 255, 255]), Rgba([0, 0, 0, 0]), Rgba([250, 240, 200, 128])] {
+            let tiled = render_tiled(&layers, width, height, &color_map, background);
+            let untiled = render_untiled(&layers, width, height, &color_map, background);
+            assert!(tiled == untiled, "tiled and untiled pages differ on {:?}", background);
+        }
+    }
+}
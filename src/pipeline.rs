@@ -0,0 +1,149 @@
+//! Conversion as a pipeline of three stages: a [`PageSource`] produces pages, [`PageTransform`]s change them one after
+//! another, and a [`PageSink`] consumes the results.
+//!
+//! [`convert_note_to_pdf`](crate::convert_note_to_pdf) is such a pipeline: its source composites each page's layers,
+//! its transforms crop, post-process, resample and rotate, and its sink writes the PDF. The same stages work on plain
+//! images for custom conversions. For example, with `notebook` parsed from `path`,
+//!
+//! ```no_run
+//! # use supernote_pdf::{ConvertOptions, Notebook};
+//! # use supernote_pdf::pipeline::{invert, pipeline, png_sink, scale};
+//! # let path = std::path::Path::new("notes.note");
+//! # let notebook = Notebook::from_path(path)?;
+//! let options = ConvertOptions::default();
+//! let pages = (1..=notebook.pages.len()).map(|page_num| notebook.render_page(path, page_num, &options));
+//! pipeline(pages, &[&invert(), &scale(0.5)], png_sink("out".as_ref()))?;
+//! # Ok::<(), supernote_pdf::SupernoteError>(())
+//! ```
+//!
+//! writes each page to `out/page_001.png`, ..., inverted and at half size.
+
+use image::{ImageFormat, Rgba, RgbaImage};
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::decode::ColorMap;
+use crate::error::Result;
+use crate::postprocess::{high_contrast as make_high_contrast, invert_ink, resample, tint_ink};
+
+/// Produces pages in order. Any iterator of `Result<P>` is a source.
+pub trait PageSource<P = RgbaImage>: Iterator<Item = Result<P>> {}
+
+impl<P, T: Iterator<Item = Result<P>>> PageSource<P> for T {}
+
+/// Changes a page. Any `Fn(P) -> Result<P>` that can be shared between threads is a transform.
+pub trait PageTransform<P = RgbaImage>: Fn(P) -> Result<P> + Sync {}
+
+impl<P, T: Fn(P) -> Result<P> + Sync> PageTransform<P> for T {}
+
+/// Consumes finished pages, with their 0-based position in the output. Any `FnMut(usize, P) -> Result<()>` is a sink.
+pub trait PageSink<P = RgbaImage>: FnMut(usize, P) -> Result<()> {}
+
+impl<P, T: FnMut(usize, P) -> Result<()>> PageSink<P> for T {}
+
+/// Passes every page from `source` through `transforms`, in order, and then into `sink`. Returns the number of pages
+/// that reached the sink.
+///
+/// The source is read to the end first. Transforms then run on several pages at once, but the sink gets the pages one
+/// at a time and in source order, so the result doesn't depend on the number of threads. Stops at the first error from
+/// any stage; the sink doesn't get any page if the source or a transform fails.
+pub fn pipeline<P: Send>(source: impl PageSource<P>, transforms: &[&dyn PageTransform<P>], mut sink: impl PageSink<P>) -> Result<usize> {
+    let pages = source.collect::<Result<Vec<P>>>()?;
+    let pages = pages
+        .into_par_iter()
+        .map(|page| transforms.iter().try_fold(page, |page, transform| transform(page)))
+        .collect::<Result<Vec<P>>>()?;
+    let count = pages.len();
+    for (index, page) in pages.into_iter().enumerate() {
+        sink(index, page)?;
+    }
+    Ok(count)
+}
+
+/// Swaps ink and paper, keeping photos in their colors. See [`ConvertOptions::invert`](crate::ConvertOptions::invert).
+pub fn invert() -> impl PageTransform {
+    |mut page: RgbaImage| {
+        invert_ink(&mut page);
+        Ok(page)
+    }
+}
+
+/// Makes every pixel pure black or white. See [`ConvertOptions::high_contrast`](crate::ConvertOptions::high_contrast).
+pub fn high_contrast(color_map: ColorMap) -> impl PageTransform {
+    move |mut page: RgbaImage| {
+        make_high_contrast(&mut page, &color_map);
+        Ok(page)
+    }
+}
+
+/// Recolors ink with `color`. See [`ConvertOptions::ink_tint`](crate::ConvertOptions::ink_tint).
+pub fn tint(color: Rgba<u8>) -> impl PageTransform {
+    move |mut page: RgbaImage| {
+        tint_ink(&mut page, color);
+        Ok(page)
+    }
+}
+
+/// Resamples pages by `factor`, e.g. `0.5` for half the width and height.
+///
+/// # Panics
+///
+/// If `factor` is not a positive number.
+pub fn scale(factor: f32) -> impl PageTransform {
+    assert!(factor.is_finite() && factor > 0.0, "cannot scale pages by {}", factor);
+    move |page: RgbaImage| Ok(resample(&page, factor))
+}
+
+/// Writes each page to `dir` as `page_001.png`, `page_002.png`, ... The directory must exist.
+pub fn png_sink(dir: &Path) -> impl PageSink {
+    image_file_sink(dir.to_path_buf(), ImageFormat::Png, "png")
+}
+
+/// Writes each page to `dir` as `page_001.tiff`, `page_002.tiff`, ... The directory must exist.
+pub fn tiff_sink(dir: &Path) -> impl PageSink {
+    image_file_sink(dir.to_path_buf(), ImageFormat::Tiff, "tiff")
+}
+
+fn image_file_sink(dir: PathBuf, format: ImageFormat, extension: &'static str) -> impl PageSink {
+    move |index: usize, page: RgbaImage| {
+        let path = dir.join(format!("page_{:03}.{}", index + 1, extension));
+        Ok(page.save_with_format(&path, format)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SupernoteError;
+
+    #[test]
+    fn transforms_run_in_order_and_the_sink_gets_pages_in_source_order() {
+        let pages = (1..=50).map(Ok);
+        let mut sunk = Vec::new();
+        let count = pipeline(pages, &[&|page: i32| Ok(page * 10), &|page: i32| Ok(page + 1)], |index, page| {
+            sunk.push((index, page));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 50);
+        assert_eq!(sunk, (1..=50).enumerate().map(|(index, page)| (index, page * 10 + 1)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_failing_transform_keeps_every_page_from_the_sink() {
+        let fail_on_three = |page: i32| {
+            if page == 3 {
+                Err(SupernoteError::Decode("three".to_string()))
+            } else {
+                Ok(page)
+            }
+        };
+        let mut sunk = Vec::new();
+        let result = pipeline((1..=5).map(Ok), &[&fail_on_three], |_, page| {
+            sunk.push(page);
+            Ok(())
+        });
+        assert!(matches!(result, Err(SupernoteError::Decode(msg)) if msg == "three"));
+        assert!(sunk.is_empty());
+    }
+}
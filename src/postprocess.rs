@@ -0,0 +1,100 @@
+use image::imageops::FilterType;
+use image::{Rgba, RgbaImage, imageops};
+
+use crate::decode::ColorMap;
+
+/// Perceived brightness of a pixel (ITU-R BT.601 weights), from 0 (black) to 255 (white).
+pub(crate) fn luminance(p: Rgba<u8>) -> u8 {
+    let [r, g, b, _] = p.0;
+    ((299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000) as u8
+}
+
+/// Makes every visible pixel pure black or pure white, for readers who need maximum contrast.
+/// Pen strokes, including the gray pens, become black; anti-aliasing pixels become black below mid-gray and white above.
+/// Fully transparent pixels are left alone, so this works on single layers as well as composited pages.
+pub(crate) fn high_contrast(image: &mut RgbaImage, color_map: &ColorMap) {
+    let gray_pens = [color_map.get(0x63), color_map.get(0x64)];
+    for p in image.pixels_mut() {
+        if p.0[3] == 0 {
+            continue;
+        }
+        let is_ink = gray_pens.iter().any(|pen| pen.0[..3] == p.0[..3]) || luminance(*p) < 128;
+        *p = if is_ink { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) };
+    }
+}
+
+/// Color of highlighter strokes: a yellow translucent enough for the ink it is drawn over to show through.
+const HIGHLIGHT_COLOR: Rgba<u8> = Rgba([255, 235, 59, 100]);
+
+/// Turns every visible pixel of a highlighter layer into [`HIGHLIGHT_COLOR`], dropping the pen's own gray level and
+/// opacity, so the layer tints what is below it instead of covering it.
+pub(crate) fn highlight(image: &mut RgbaImage) {
+    for p in image.pixels_mut() {
+        if p.0[3] != 0 {
+            *p = HIGHLIGHT_COLOR;
+        }
+    }
+}
+
+/// Whether a pixel looks like pen ink or paper (a shade of gray) rather than part of an embedded photo.
+/// Pixels whose channels differ by more than 30 are colorful enough to be photo content.
+pub(crate) fn is_ink_pixel(p: Rgba<u8>) -> bool {
+    let [r, g, b, _] = p.0;
+    r.max(g).max(b) - r.min(g).min(b) <= 30
+}
+
+/// Inverts ink and paper (white on black) while leaving photo pixels, such as images embedded in the background
+/// layer, in their original colors.
+pub(crate) fn invert_ink(image: &mut RgbaImage) {
+    for p in image.pixels_mut() {
+        if p.0[3] != 0 && is_ink_pixel(*p) {
+            let [r, g, b, a] = p.0;
+            *p = Rgba([255 - r, 255 - g, 255 - b, a]);
+        }
+    }
+}
+
+/// Pixels at least this bright count as paper and are not tinted.
+const TINT_PAPER_LUMINANCE: u8 = 250;
+
+/// Recolors ink with `tint`: black becomes exactly `tint`, and lighter grays are blended between `tint` and white
+/// in proportion to their brightness. Paper and photo pixels are left as they are.
+pub(crate) fn tint_ink(image: &mut RgbaImage, tint: Rgba<u8>) {
+    for p in image.pixels_mut() {
+        if p.0[3] == 0 || !is_ink_pixel(*p) {
+            continue;
+        }
+        let lum = luminance(*p);
+        if lum >= TINT_PAPER_LUMINANCE {
+            continue;
+        }
+        let darkness = 255 - lum as u32;
+        for c in 0..3 {
+            p.0[c] = ((tint.0[c] as u32 * darkness + 255 * lum as u32) / 255) as u8;
+        }
+    }
+}
+
+/// The smallest rectangle `(x, y, width, height)` containing every pixel that differs from `paper`,
+/// or `None` if the image is blank.
+pub(crate) fn ink_bounds(image: &RgbaImage, paper: Rgba<u8>) -> Option<(u32, u32, u32, u32)> {
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, p) in image.enumerate_pixels() {
+        if *p == paper {
+            continue;
+        }
+        let (min_x, min_y, max_x, max_y) = bounds.get_or_insert((x, y, x, y));
+        *min_x = (*min_x).min(x);
+        *min_y = (*min_y).min(y);
+        *max_x = (*max_x).max(x);
+        *max_y = (*max_y).max(y);
+    }
+    bounds.map(|(min_x, min_y, max_x, max_y)| (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+}
+
+/// Resamples an image by `factor` in both directions, keeping at least one pixel.
+pub(crate) fn resample(image: &RgbaImage, factor: f32) -> RgbaImage {
+    let width = ((image.width() as f32 * factor) as u32).max(1);
+    let height = ((image.height() as f32 * factor) as u32).max(1);
+    imageops::resize(image, width, height, FilterType::Lanczos3)
+}
@@ -0,0 +1,151 @@
+//! Python bindings (`import supernote_pdf`), enabled by the `python` feature and built with maturin.
+
+use image::ImageFormat;
+use pyo3::exceptions::{PyIndexError, PyOSError, PyTypeError, PyUserWarning, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use std::ffi::CString;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::error::SupernoteError;
+use crate::hook::ConversionHook;
+use crate::notebook::Notebook;
+use crate::pdf::{ConvertOptions, ImageEncoding, PageFormat, convert_note_to_pdf};
+
+impl From<SupernoteError> for PyErr {
+    fn from(e: SupernoteError) -> Self {
+        match e {
+            SupernoteError::IoError(e) => e.into(),
+            SupernoteError::PageNotFound { .. } => PyIndexError::new_err(e.to_string()),
+            other => PyValueError::new_err(other.to_string()),
+        }
+    }
+}
+
+/// Issues conversion warnings as Python `UserWarning`s.
+#[derive(Debug)]
+struct PythonWarnings;
+
+impl ConversionHook for PythonWarnings {
+    fn on_warning(&self, message: &str) {
+        Python::attach(|py| {
+            let message = CString::new(message.replace('\0', "")).expect("NUL bytes were removed");
+            // With an "error" warnings filter this fails, but a warning shouldn't stop the conversion.
+            let _ = PyErr::warn(py, &py.get_type::<PyUserWarning>(), &message, 1);
+        });
+    }
+}
+
+/// Builds conversion options from `convert_file`'s keyword arguments, named like the fields of [`ConvertOptions`].
+fn options_from_kwargs(kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<ConvertOptions> {
+    let mut options = ConvertOptions {
+        hook: Some(Arc::new(PythonWarnings)),
+        ..ConvertOptions::default()
+    };
+    let Some(kwargs) = kwargs else {
+        return Ok(options);
+    };
+    for (key, value) in kwargs.iter() {
+        let key: String = key.extract()?;
+        match key.as_str() {
+            "skip_blank_pages" => options.skip_blank_pages = value.extract()?,
+            "strict" => options.strict = value.extract()?,
+            "starred_only" => options.starred_only = value.extract()?,
+            "exclude_starred" => options.exclude_starred = value.extract()?,
+            "excluded_pages" => options.excluded_pages = value.extract()?,
+            "sidecar" => options.sidecar = value.extract()?,
+            "embed_fonts" => options.embed_fonts = value.extract()?,
+            "compress_metadata" => options.compress_metadata = value.extract()?,
+            "thumbnail_width" => options.thumbnail_width = value.extract()?,
+            "pdf_layers" => options.pdf_layers = value.extract()?,
+            "layer_order" => options.layer_order = value.extract()?,
+            "high_contrast" => options.high_contrast = value.extract()?,
+            "invert" => options.invert = value.extract()?,
+            "scale" => options.scale = value.extract()?,
+            "max_output_size" => options.max_output_size = value.extract()?,
+            "pages_per_file" => options.pages_per_file = value.extract()?,
+            "auto_split_chapters" => options.auto_split_chapters = value.extract()?,
+            "crop_to_ink" => options.crop_to_ink = value.extract()?,
+            "dpi" => options.dpi = value.extract()?,
+            "cache_dir" => options.cache_dir = value.extract()?,
+            "all_errors" => options.all_errors = value.extract()?,
+            "producer" => options.producer = value.extract()?,
+            "author" => options.author = value.extract()?,
+            "zlib_level" => {
+                options.zlib_level = match value.extract::<Option<u8>>()? {
+                    Some(level @ 10..) => return Err(PyValueError::new_err(format!("zlib_level must be 0 to 9, not {}", level))),
+                    level => level,
+                }
+            }
+            "creator" => options.creator = value.extract()?,
+            "pdf_ua" => options.pdf_ua = value.extract()?,
+            "lang" => options.lang = value.extract()?,
+            "cover_page" => options.cover_page = value.extract()?,
+            "pad_to_count" => options.pad_to_count = value.extract()?,
+            "page_format" => {
+                options.page_format = match value.extract::<String>()?.as_str() {
+                    "auto" => PageFormat::Auto,
+                    "flate" => PageFormat::Fixed(ImageEncoding::Flate),
+                    "jpeg" => PageFormat::Fixed(ImageEncoding::Jpeg),
+                    "ccitt" => PageFormat::Fixed(ImageEncoding::Ccitt),
+                    other => {
+                        return Err(PyValueError::new_err(format!(
+                            "page_format must be 'auto', 'flate', 'jpeg' or 'ccitt', not '{}'",
+                            other
+                        )));
+                    }
+                }
+            }
+            other => {
+                return Err(PyTypeError::new_err(format!(
+                    "convert_file() got an unexpected keyword argument '{}'",
+                    other
+                )));
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// Converts a .note file to a PDF. Keyword arguments set conversion options, e.g. `dpi=300` or `skip_blank_pages=True`.
+#[pyfunction]
+#[pyo3(signature = (input, output, **kwargs))]
+fn convert_file(py: Python<'_>, input: PathBuf, output: PathBuf, kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<()> {
+    let options = options_from_kwargs(kwargs)?;
+    // Converting doesn't touch Python objects, so other Python threads can run meanwhile.
+    py.detach(|| convert_note_to_pdf(&input, &output, &options))?;
+    Ok(())
+}
+
+/// Reads a .note file's metadata (header, pages, layers) into a dict, without decoding any page.
+#[pyfunction]
+fn parse_notebook<'py>(py: Python<'py>, path: PathBuf) -> PyResult<Bound<'py, PyAny>> {
+    let notebook = py.detach(|| Notebook::from_path(&path))?;
+    let json = serde_json::to_string(&notebook).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    py.import("json")?.call_method1("loads", (json,))
+}
+
+/// Renders one page of a .note file (1-based, as numbered on the device) and returns it as PNG bytes.
+#[pyfunction]
+fn render_page<'py>(py: Python<'py>, path: PathBuf, page: usize) -> PyResult<Bound<'py, PyBytes>> {
+    let png = py.detach(|| -> PyResult<Vec<u8>> {
+        let notebook = Notebook::from_path(&path)?;
+        let image = notebook.render_page(&path, page, &options_from_kwargs(None)?)?;
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .map_err(|e| PyOSError::new_err(e.to_string()))?;
+        Ok(png)
+    })?;
+    Ok(PyBytes::new(py, &png))
+}
+
+#[pymodule]
+fn supernote_pdf(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(convert_file, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_notebook, m)?)?;
+    m.add_function(wrap_pyfunction!(render_page, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use image::{RgbaImage, imageops};
+use std::fmt;
+
+use crate::decode::ColorMap;
+use crate::error::Result;
+use crate::notebook::Layer;
+use crate::pdf::decode_layer;
+
+/// Draws layers onto pages, for replacing the built-in compositing through [`ConvertOptions::layer_renderer`]
+/// (e.g. with stroke smoothing or color calibration).
+///
+/// Layers are drawn bottom to top in `LAYERSEQ` order, each onto the page drawn so far. Pages start out as the
+/// paper color, or fully transparent for layered output, where each layer is drawn onto a page of its own.
+/// Post-processing such as inverting or scaling happens afterwards, on the finished page.
+///
+/// [`ConvertOptions::layer_renderer`]: crate::ConvertOptions::layer_renderer
+pub trait LayerRenderer: fmt::Debug + Send + Sync {
+    /// Draws one layer, whose bitmap block (still compressed with `layer.protocol`) is `layer_data`, onto a
+    /// `width` by `height` canvas. Layers with a protocol the renderer doesn't know can be left undrawn.
+    fn render_layer(&self, canvas: &mut RgbaImage, layer: &Layer, layer_data: &[u8], width: usize, height: usize) -> Result<()>;
+}
+
+/// The built-in renderer: decodes every protocol this crate knows, maps color codes with a [`ColorMap`], and
+/// alpha-blends the layer over the canvas. Layers with an unknown protocol are skipped.
+#[derive(Debug, Default, Clone)]
+pub struct DefaultLayerRenderer {
+    pub color_map: ColorMap,
+}
+
+impl LayerRenderer for DefaultLayerRenderer {
+    fn render_layer(&self, canvas: &mut RgbaImage, layer: &Layer, layer_data: &[u8], width: usize, height: usize) -> Result<()> {
+        if let Some(layer_image) = decode_layer(layer, layer_data, width, height, &self.color_map, &|_| {})? {
+            imageops::overlay(canvas, &layer_image, 0, 0);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use itertools::Itertools;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::error::{Result, SupernoteError};
+use crate::verify::{dict_end, reference, type_is, value};
+
+/// What [`repair_pdf`] recovered.
+#[derive(Debug)]
+pub struct RepairSummary {
+    /// Complete objects found and kept.
+    pub objects: usize,
+    /// Pages in the repaired PDF.
+    pub pages: usize,
+    /// Whether the catalog and page tree were missing, and were rebuilt from the pages that were found.
+    pub rebuilt_page_tree: bool,
+    /// Bytes at the end of the file that were not part of a complete object, such as a half-written object or
+    /// the old cross-reference table.
+    pub bytes_dropped: u64,
+}
+
+/// A complete object found in the damaged file.
+struct Salvaged {
+    offset: usize,
+    /// The object's dictionary, without the `<<` and `>>`.
+    dict: String,
+}
+
+/// Salvages a PDF whose writing was interrupted, such as by a crash or a full disk, and writes the result to
+/// `output_path`.
+///
+/// The file is read from the start, object by object, skipping stream data by its `/Length`. Everything up to the
+/// end of the last complete object is copied unchanged, and a new cross-reference table and trailer are written
+/// after it. The catalog and root page tree are written last, so they are usually missing: they are then rebuilt
+/// from the pages whose contents and images were written in full. Bookmarks and other objects written after the
+/// pages are lost.
+///
+/// Like [`verify_pdf`](crate::verify_pdf), this understands the PDFs this crate writes rather than PDFs in general.
+pub fn repair_pdf(input_path: &Path, output_path: &Path) -> Result<RepairSummary> {
+    let data = fs::read(input_path)?;
+    if !data.starts_with(b"%PDF-") {
+        return Err(SupernoteError::MalformedMetadata(format!("'{}' is not a PDF", input_path.display())));
+    }
+
+    // Later objects with the same number replace earlier ones, as in an incremental update.
+    let mut objects: BTreeMap<usize, Salvaged> = BTreeMap::new();
+    let mut salvaged_end = 0;
+    let mut pos = 0;
+    while let Some(start) = find_object_start(&data, pos) {
+        match parse_object(&data, start) {
+            Some((id, dict, end)) => {
+                objects.insert(id, Salvaged { offset: start, dict });
+                salvaged_end = end;
+                pos = end;
+            }
+            None => pos = start + 1,
+        }
+    }
+    if objects.is_empty() {
+        return Err(SupernoteError::MalformedMetadata(format!(
+            "'{}' has no complete objects to salvage",
+            input_path.display()
+        )));
+    }
+
+    // New objects get numbers that nothing refers to yet, not even objects that were cut off.
+    let mut next_id = objects
+        .iter()
+        .flat_map(|(&id, object)| references(&object.dict).chain([id]))
+        .max()
+        .unwrap()
+        + 1;
+    let mut extra_objects = Vec::new();
+    // The catalog is written just before the root of the page tree, so it can be there without it.
+    let catalog = objects
+        .iter()
+        .find(|(_, object)| type_is(&object.dict, "/Catalog") && reference(&object.dict, "/Pages").is_some_and(|id| objects.contains_key(&id)))
+        .map(|(&id, _)| id);
+    let (root_id, pages, rebuilt_page_tree) = match catalog {
+        Some(catalog_id) => {
+            let pages = objects.values().filter(|object| type_is(&object.dict, "/Page")).count();
+            (catalog_id, pages, false)
+        }
+        None => {
+            // Pages whose contents or images were cut off are left out.
+            let page_ids: Vec<usize> = objects
+                .iter()
+                .filter(|(_, object)| type_is(&object.dict, "/Page"))
+                .filter(|(_, object)| references(&object.dict).all(|id| objects.contains_key(&id)))
+                .map(|(&id, _)| id)
+                .collect();
+            // Pages name their parent before it is written, so the page tree can take the id they expect.
+            let pages_root_id = objects
+                .values()
+                .filter(|object| type_is(&object.dict, "/Page"))
+                .filter_map(|object| reference(&object.dict, "/Parent"))
+                .filter(|id| !objects.contains_key(id))
+                .counts()
+                .into_iter()
+                .max_by_key(|&(id, count)| (count, id))
+                .map_or_else(
+                    || {
+                        next_id += 1;
+                        next_id - 1
+                    },
+                    |(id, _)| id,
+                );
+            let kids = page_ids.iter().map(|id| format!("{} 0 R", id)).join(" ");
+            extra_objects.push((pages_root_id, format!("/Type /Pages /Kids [ {} ] /Count {}", kids, page_ids.len())));
+            let catalog_id = next_id;
+            extra_objects.push((catalog_id, format!("/Type /Catalog /Pages {} 0 R", pages_root_id)));
+            (catalog_id, page_ids.len(), true)
+        }
+    };
+    let info_id = objects
+        .iter()
+        .find(|(_, object)| value(&object.dict, "/Producer").is_some())
+        .map(|(&id, _)| id);
+
+    let mut writer = BufWriter::new(File::create(output_path)?);
+    let mut offsets: BTreeMap<usize, usize> = objects.iter().map(|(&id, object)| (id, object.offset)).collect();
+    let mut byte_offset = salvaged_end;
+    writer.write_all(&data[..salvaged_end])?;
+    if !data[..salvaged_end].ends_with(b"\n") {
+        writer.write_all(b"\n")?;
+        byte_offset += 1;
+    }
+    for (id, dict) in &extra_objects {
+        let object = format!("{} 0 obj\n<< {} >>\nendobj\n", id, dict);
+        writer.write_all(object.as_bytes())?;
+        offsets.insert(*id, byte_offset);
+        byte_offset += object.len();
+    }
+
+    // Object numbers that were never found are marked free.
+    let size = offsets.keys().last().unwrap() + 1;
+    let mut xref = format!("xref\n0 {}\n", size);
+    for id in 0..size {
+        match offsets.get(&id) {
+            Some(offset) => xref.push_str(&format!("{:010} 00000 n \n", offset)),
+            None => xref.push_str("0000000000 65535 f \n"),
+        }
+    }
+    let info = info_id.map(|id| format!(" /Info {} 0 R", id)).unwrap_or_default();
+    xref.push_str(&format!("trailer\n<< /Size {} /Root {} 0 R{} >>\n", size, root_id, info));
+    xref.push_str(&format!("startxref\n{}\n%%EOF\n", byte_offset));
+    writer.write_all(xref.as_bytes())?;
+    writer.flush()?;
+
+    Ok(RepairSummary {
+        objects: objects.len(),
+        pages,
+        rebuilt_page_tree,
+        bytes_dropped: (data.len() - salvaged_end) as u64,
+    })
+}
+
+/// The offset of the next `N 0 obj` at the start of a line, at or after `pos`.
+fn find_object_start(data: &[u8], pos: usize) -> Option<usize> {
+    let mut search_from = pos;
+    loop {
+        let keyword = search_from + data.get(search_from..)?.windows(6).position(|window| window == b" 0 obj")?;
+        let digits_start = data[..keyword].iter().rposition(|b| !b.is_ascii_digit()).map_or(0, |i| i + 1);
+        let at_line_start = digits_start == 0 || matches!(data[digits_start - 1], b'\n' | b'\r');
+        if digits_start < keyword && digits_start >= pos && at_line_start {
+            return Some(digits_start);
+        }
+        search_from = keyword + 1;
+    }
+}
+
+/// Parses the object at `start`: `N 0 obj << ... >>`, an optional stream, and `endobj`. Returns its number, its
+/// dictionary and the offset just past it, or `None` if it is incomplete or not a dictionary.
+fn parse_object(data: &[u8], start: usize) -> Option<(usize, String, usize)> {
+    let keyword = start + data[start..].iter().position(|&b| b == b' ')?;
+    let id: usize = std::str::from_utf8(&data[start..keyword]).ok()?.parse().ok()?;
+    let dict_start = keyword + b" 0 obj".len();
+    let dict_end = dict_start + dict_end(&data[dict_start..])?;
+    let dict = String::from_utf8_lossy(&data[dict_start..dict_end]);
+    let dict = dict.trim().trim_start_matches("<<").trim_end_matches(">>").trim().to_string();
+
+    let mut pos = dict_end + whitespace_len(&data[dict_end..]);
+    if data[pos..].starts_with(b"stream") {
+        pos += b"stream".len();
+        pos += if data[pos..].starts_with(b"\r\n") { 2 } else { 1 };
+        pos += value(&dict, "/Length")?.parse::<usize>().ok()?;
+        pos += whitespace_len(data.get(pos..)?);
+        if !data.get(pos..)?.starts_with(b"endstream") {
+            return None;
+        }
+        pos += b"endstream".len();
+        pos += whitespace_len(&data[pos..]);
+    }
+    if !data[pos..].starts_with(b"endobj") {
+        return None;
+    }
+    pos += b"endobj".len();
+    // Keep the line break after `endobj` with the object.
+    pos += data[pos..].iter().take_while(|&&b| b == b'\n' || b == b'\r').count();
+    Some((id, dict, pos))
+}
+
+fn whitespace_len(bytes: &[u8]) -> usize {
+    bytes.len() - bytes.trim_ascii_start().len()
+}
+
+/// The objects a dictionary refers to with `N 0 R`, other than its `/Parent`.
+fn references(dict: &str) -> impl Iterator<Item = usize> + '_ {
+    let parent = reference(dict, "/Parent");
+    dict.split_whitespace()
+        .tuple_windows()
+        .filter(|&(_, generation, keyword)| generation == "0" && keyword.starts_with('R'))
+        .filter_map(|(id, _, _)| id.trim_start_matches(['[', '<']).parse().ok())
+        .filter(move |&id| Some(id) != parent)
+}
@@ -0,0 +1,194 @@
+//! Uploading converted PDFs to an S3-compatible object store, for `--output s3://bucket/prefix/`.
+//!
+//! Conversions write into a local staging directory laid out like the bucket, and each finished file is uploaded to
+//! the key matching its path there, then deleted. Without the `s3` feature, S3 outputs are rejected.
+
+use anyhow::{Result, bail};
+use std::path::Path;
+
+/// Whether an output path is an `s3://` URL rather than a local path.
+pub fn is_s3_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| path.starts_with("s3://"))
+}
+
+/// Splits `s3://bucket/key` into the bucket and the key, which may be empty.
+fn parse_s3_url(url: &str) -> Result<(&str, &str)> {
+    let rest = url.strip_prefix("s3://").unwrap_or(url);
+    let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        bail!("S3 output '{}' has no bucket name; use s3://bucket/prefix/", url);
+    }
+    Ok((bucket, key))
+}
+
+#[cfg(feature = "s3")]
+pub use upload::S3Output;
+
+#[cfg(feature = "s3")]
+mod upload {
+    use anyhow::{Context, Result};
+    use aws_sdk_s3::Client;
+    use aws_sdk_s3::primitives::ByteStream;
+    use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+    use itertools::Itertools;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use tempfile::TempDir;
+    use tokio::runtime::Runtime;
+
+    use super::parse_s3_url;
+
+    /// Size of each part of a multipart upload. S3 requires at least 5 MiB for every part but the last.
+    const PART_SIZE: usize = 8 * 1024 * 1024;
+
+    /// A bucket that converted files are uploaded to, and the local directory they are staged in until then.
+    pub struct S3Output {
+        client: Client,
+        runtime: Runtime,
+        bucket: String,
+        /// Stands in for the bucket: a file at `<root>/a/b.pdf` is uploaded as key `a/b.pdf`.
+        root: PathBuf,
+        // Removed, with anything left in it, when the output is dropped.
+        _staging: TempDir,
+    }
+
+    impl S3Output {
+        /// Connects to the bucket of an `s3://bucket/key` URL with the credentials and region from the environment
+        /// (set `AWS_ENDPOINT_URL` for stores other than AWS). Returns it with the local path conversions should
+        /// write to in place of the URL.
+        pub fn connect(url: &str) -> Result<(Self, PathBuf)> {
+            let (bucket, key) = parse_s3_url(url)?;
+            let runtime = Runtime::new()?;
+            let config = runtime.block_on(aws_config::load_from_env());
+            let staging = tempfile::Builder::new().prefix("supernote_pdf-s3-").tempdir()?;
+            let root = staging.path().join(bucket);
+            let local_output = root.join(key);
+            if let Some(parent) = local_output.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let output = S3Output {
+                client: Client::new(&config),
+                runtime,
+                bucket: bucket.to_string(),
+                root,
+                _staging: staging,
+            };
+            Ok((output, local_output))
+        }
+
+        /// Uploads a file written under the staging directory to its key, deletes the local copy, and returns the
+        /// file's `s3://` URL.
+        pub fn upload(&self, local_path: &Path) -> Result<String> {
+            let relative = local_path
+                .strip_prefix(&self.root)
+                .with_context(|| format!("'{}' was not written to the S3 staging directory", local_path.display()))?;
+            let key = relative.components().map(|c| c.as_os_str().to_string_lossy()).join("/");
+            let url = format!("s3://{}/{}", self.bucket, key);
+            self.runtime
+                .block_on(self.multipart_upload(local_path, &key))
+                .with_context(|| format!("failed to upload '{}' to {}", local_path.display(), url))?;
+            fs::remove_file(local_path)?;
+            Ok(url)
+        }
+
+        /// Streams a file to `key` in parts, so large PDFs are never held in memory whole.
+        /// A failed upload is aborted, so the store doesn't keep its parts.
+        async fn multipart_upload(&self, local_path: &Path, key: &str) -> Result<()> {
+            let content_type = if local_path.extension().is_some_and(|ext| ext == "pdf") {
+                "application/pdf"
+            } else {
+                "application/octet-stream"
+            };
+            let upload = self
+                .client
+                .create_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .content_type(content_type)
+                .send()
+                .await?;
+            let upload_id = upload.upload_id().context("the store did not return an upload id")?;
+
+            match self.upload_parts(local_path, key, upload_id).await {
+                Ok(parts) => {
+                    self.client
+                        .complete_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+                        .send()
+                        .await?;
+                    Ok(())
+                }
+                Err(e) => {
+                    // The upload already failed; a failure to clean it up adds nothing actionable.
+                    let _ = self
+                        .client
+                        .abort_multipart_upload()
+                        .bucket(&self.bucket)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .send()
+                        .await;
+                    Err(e)
+                }
+            }
+        }
+
+        /// Uploads the parts of a file in order. An empty file is still uploaded as one (empty) part.
+        async fn upload_parts(&self, local_path: &Path, key: &str, upload_id: &str) -> Result<Vec<CompletedPart>> {
+            let mut file = File::open(local_path)?;
+            let mut parts = Vec::new();
+            loop {
+                let mut buf = Vec::with_capacity(PART_SIZE);
+                (&mut file).take(PART_SIZE as u64).read_to_end(&mut buf)?;
+                if buf.is_empty() && !parts.is_empty() {
+                    break;
+                }
+                let is_last = buf.len() < PART_SIZE;
+                let part_number = parts.len() as i32 + 1;
+                let part = self
+                    .client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .body(ByteStream::from(buf))
+                    .send()
+                    .await?;
+                parts.push(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(part.e_tag().map(str::to_string))
+                        .build(),
+                );
+                if is_last {
+                    break;
+                }
+            }
+            Ok(parts)
+        }
+    }
+}
+
+/// Stand-in for builds without the `s3` feature: connecting always fails, so no value ever exists.
+#[cfg(not(feature = "s3"))]
+pub enum S3Output {}
+
+#[cfg(not(feature = "s3"))]
+impl S3Output {
+    pub fn connect(url: &str) -> Result<(Self, std::path::PathBuf)> {
+        parse_s3_url(url)?;
+        bail!(
+            "cannot write to '{}': S3 output is not compiled into this build; reinstall with `cargo install supernote_pdf --features s3`",
+            url
+        )
+    }
+
+    pub fn upload(&self, _local_path: &Path) -> Result<String> {
+        match *self {}
+    }
+}
@@ -0,0 +1,152 @@
+//! `--serve`: a local HTTP service that converts `.note` files posted to it, for note editors and browser extensions.
+//!
+//! `POST /convert` takes the file as the request body and responds with the PDF. `GET /` is an upload form.
+//! Without the `serve` feature, `--serve` is rejected.
+
+#[cfg(feature = "serve")]
+pub use service::serve;
+
+#[cfg(feature = "serve")]
+mod service {
+    use anyhow::Result;
+    use axum::Router;
+    use axum::body::Bytes;
+    use axum::extract::{DefaultBodyLimit, Query, State};
+    use axum::http::{StatusCode, header};
+    use axum::response::{Html, IntoResponse, Response};
+    use axum::routing::{get, post};
+    use serde::Deserialize;
+    use std::io::{ErrorKind, Write};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use supernote_pdf::{ConvertOptions, ImageEncoding, Notebook, PageFormat, SupernoteError};
+    use tokio::sync::Semaphore;
+
+    /// Largest `.note` file accepted, well above what a notebook of a few hundred pages takes up. Uploads are held in
+    /// memory while they are converted.
+    const MAX_UPLOAD_BYTES: usize = 200 * 1024 * 1024;
+
+    /// Conversions that run at once; further requests wait for one of them to finish. Each conversion already renders
+    /// its pages on every core, so more would only hold more uploads and pages in memory.
+    const MAX_CONCURRENT_CONVERSIONS: usize = 4;
+
+    /// What every request shares.
+    struct ServerState {
+        /// The options the server was started with.
+        options: ConvertOptions,
+        /// One permit per conversion that may run, see [`MAX_CONCURRENT_CONVERSIONS`].
+        conversions: Semaphore,
+    }
+
+    const INDEX_HTML: &str = include_str!("../assets/serve_index.html");
+
+    /// Per-request changes to the options the server was started with, from the `/convert` query string,
+    /// e.g. `/convert?dpi=300&high_contrast=true`.
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct ConvertQuery {
+        dpi: Option<f32>,
+        scale: Option<f32>,
+        high_contrast: Option<bool>,
+        invert: Option<bool>,
+        skip_blank_pages: Option<bool>,
+        starred_only: Option<bool>,
+        crop_to_ink: Option<bool>,
+        pdf_layers: Option<bool>,
+        cover_page: Option<bool>,
+        /// `auto`, `flate`, `jpeg` or `ccitt`, as for `--page-format`.
+        page_format: Option<String>,
+    }
+
+    impl ConvertQuery {
+        /// The server's options with this request's changes applied.
+        fn apply(self, options: &ConvertOptions) -> std::result::Result<ConvertOptions, String> {
+            for (name, value) in [("dpi", self.dpi), ("scale", self.scale)] {
+                if value.is_some_and(|value| !(value.is_finite() && value > 0.0)) {
+                    return Err(format!("{} must be a positive number", name));
+                }
+            }
+            let mut options = options.clone();
+            options.dpi = self.dpi.or(options.dpi);
+            options.scale = self.scale.or(options.scale);
+            options.high_contrast = self.high_contrast.unwrap_or(options.high_contrast);
+            options.invert = self.invert.unwrap_or(options.invert);
+            options.skip_blank_pages = self.skip_blank_pages.unwrap_or(options.skip_blank_pages);
+            options.starred_only = self.starred_only.unwrap_or(options.starred_only);
+            options.crop_to_ink = self.crop_to_ink.unwrap_or(options.crop_to_ink);
+            options.pdf_layers = self.pdf_layers.unwrap_or(options.pdf_layers);
+            options.cover_page = self.cover_page.unwrap_or(options.cover_page);
+            if let Some(page_format) = self.page_format {
+                options.page_format = match page_format.as_str() {
+                    "auto" => PageFormat::Auto,
+                    "flate" => PageFormat::Fixed(ImageEncoding::Flate),
+                    "jpeg" => PageFormat::Fixed(ImageEncoding::Jpeg),
+                    "ccitt" => PageFormat::Fixed(ImageEncoding::Ccitt),
+                    other => return Err(format!("page_format must be 'auto', 'flate', 'jpeg' or 'ccitt', not '{}'", other)),
+                };
+            }
+            Ok(options)
+        }
+    }
+
+    /// Serves the converter on `addr` until the process is stopped, with `options` as the defaults for every request.
+    pub fn serve(addr: SocketAddr, options: ConvertOptions) -> Result<()> {
+        let app = Router::new()
+            .route("/", get(index))
+            .route("/convert", post(convert))
+            .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES))
+            .with_state(Arc::new(ServerState {
+                options,
+                conversions: Semaphore::new(MAX_CONCURRENT_CONVERSIONS),
+            }));
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            eprintln!("Listening on http://{} (press Ctrl-C to stop)", listener.local_addr()?);
+            axum::serve(listener, app).await?;
+            Ok(())
+        })
+    }
+
+    async fn index() -> Html<&'static str> {
+        Html(INDEX_HTML)
+    }
+
+    async fn convert(State(state): State<Arc<ServerState>>, Query(query): Query<ConvertQuery>, note: Bytes) -> Response {
+        let options = match query.apply(&state.options) {
+            Ok(options) => options,
+            Err(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+        };
+        let _permit = state.conversions.acquire().await.expect("the semaphore is never closed");
+        // Rendering is CPU-bound, so it runs off the threads that handle connections.
+        match tokio::task::spawn_blocking(move || convert_bytes(&note, &options)).await {
+            Ok(Ok(pdf)) => ([(header::CONTENT_TYPE, "application/pdf")], pdf).into_response(),
+            Ok(Err(e)) => {
+                // Bad uploads are the client's problem; failing to use the temporary file is ours.
+                // A truncated upload shows up as an unexpected end of file.
+                let status = match &e {
+                    SupernoteError::IoError(io) if io.kind() != ErrorKind::UnexpectedEof => StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => StatusCode::UNPROCESSABLE_ENTITY,
+                };
+                (status, e.to_string()).into_response()
+            }
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        }
+    }
+
+    /// Converts the contents of a `.note` file to the bytes of a PDF. Page bitmaps are read from a file as they are
+    /// rendered, so the upload is written to a temporary one first.
+    fn convert_bytes(note: &[u8], options: &ConvertOptions) -> supernote_pdf::Result<Vec<u8>> {
+        let mut file = tempfile::Builder::new().suffix(".note").tempfile()?;
+        file.write_all(note)?;
+        file.flush()?;
+        let notebook = Notebook::from_path(file.path())?;
+        notebook.render_to_pdf_with_options(file.path(), options)
+    }
+}
+
+/// Stand-in for builds without the `serve` feature.
+#[cfg(not(feature = "serve"))]
+pub fn serve(_addr: std::net::SocketAddr, _options: supernote_pdf::ConvertOptions) -> anyhow::Result<()> {
+    anyhow::bail!("--serve is not compiled into this build; reinstall with `cargo install supernote_pdf --features serve`")
+}
@@ -0,0 +1,46 @@
+use image::{Rgba, RgbaImage};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::error::{Result, SupernoteError};
+use crate::notebook::Notebook;
+
+/// Metadata written next to a converted PDF, so indexing tools don't need to parse `.note` files.
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    #[serde(flatten)]
+    notebook: &'a Notebook,
+    page_count: usize,
+    /// Fraction of pixels that aren't plain paper across all exported pages, from 0.0 to 1.0.
+    ink_coverage: f64,
+}
+
+/// Writes the JSON sidecar for a notebook, given the composited images of the pages that were exported
+/// and the color of their paper.
+pub(crate) fn write_sidecar(notebook: &Notebook, page_images: &[&RgbaImage], paper: Rgba<u8>, path: &Path) -> Result<()> {
+    let ink_pixels: u64 = page_images.par_iter().map(|image| count_ink_pixels(image, paper)).sum();
+    let total_pixels: u64 = page_images.iter().map(|image| image.width() as u64 * image.height() as u64).sum();
+    let sidecar = Sidecar {
+        notebook,
+        page_count: notebook.pages.len(),
+        ink_coverage: if total_pixels == 0 {
+            0.0
+        } else {
+            ink_pixels as f64 / total_pixels as f64
+        },
+    };
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, &sidecar).map_err(|e| SupernoteError::IoError(std::io::Error::other(e)))?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Counts the pixels of a composited page that aren't plain paper.
+fn count_ink_pixels(image: &RgbaImage, paper: Rgba<u8>) -> u64 {
+    image.pixels().filter(|&&p| p != paper).count() as u64
+}
@@ -0,0 +1,316 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::error::{Result, SupernoteError};
+
+/// A structural problem [`verify_pdf`] found in a PDF.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyWarning {
+    /// The cross-reference table or trailer could not be read, so nothing else was checked.
+    UnreadableXref(String),
+    /// The cross-reference table has a different number of entries than the trailer's `/Size`.
+    SizeMismatch { xref_entries: usize, trailer_size: usize },
+    /// The cross-reference table points object `object` at `offset`, but a different object (or none) starts there.
+    ObjectOffset { object: usize, offset: u64 },
+    /// A page has no `/Parent`, or its `/Parent` isn't a `/Pages` object.
+    PageParent { page: usize, parent: Option<usize> },
+    /// A stream doesn't end `/Length` bytes after it starts; `actual` is where it does end, if `endstream` was found.
+    StreamLength { object: usize, declared: u64, actual: Option<u64> },
+}
+
+impl fmt::Display for VerifyWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyWarning::UnreadableXref(msg) => write!(f, "unreadable cross-reference table: {}", msg),
+            VerifyWarning::SizeMismatch { xref_entries, trailer_size } => write!(
+                f,
+                "the cross-reference table has {} entries, but the trailer's /Size is {}",
+                xref_entries, trailer_size
+            ),
+            VerifyWarning::ObjectOffset { object, offset } => {
+                write!(f, "object {} is not at offset {} given by the cross-reference table", object, offset)
+            }
+            VerifyWarning::PageParent { page, parent: None } => write!(f, "page object {} has no /Parent", page),
+            VerifyWarning::PageParent { page, parent: Some(parent) } => {
+                write!(f, "page object {} has /Parent {} 0 R, which is not a /Pages object", page, parent)
+            }
+            VerifyWarning::StreamLength {
+                object,
+                declared,
+                actual: Some(actual),
+            } => write!(f, "stream of object {} has /Length {}, but is {} bytes long", object, declared, actual),
+            VerifyWarning::StreamLength {
+                object,
+                declared,
+                actual: None,
+            } => {
+                write!(f, "stream of object {} has /Length {}, but has no endstream", object, declared)
+            }
+        }
+    }
+}
+
+/// How far from the end of the file `startxref` is looked for.
+const TRAILER_SEARCH_LEN: u64 = 1024;
+/// How much is read at a time while looking for the end of an object's dictionary or stream.
+const READ_CHUNK_LEN: usize = 4096;
+
+/// Reads a PDF back and checks the structure a reader relies on: that every object is where the cross-reference
+/// table says, that the table has `/Size` entries, that every page's `/Parent` is a `/Pages` object, and that every
+/// stream's `/Length` is right.
+///
+/// Only object headers are read, by seeking to the offsets in the table, so this is cheap even for large PDFs. It
+/// understands the PDFs this crate writes (a single classic cross-reference table, no object streams) rather than
+/// PDFs in general. An empty list means nothing was wrong; an error means the file couldn't be read at all.
+pub fn verify_pdf(path: &Path) -> Result<Vec<VerifyWarning>> {
+    let mut file = File::open(path)?;
+    let offsets = match read_xref(&mut file) {
+        Ok((offsets, trailer_size)) if offsets.len() + 1 != trailer_size => {
+            return Ok(vec![VerifyWarning::SizeMismatch {
+                xref_entries: offsets.len() + 1,
+                trailer_size,
+            }]);
+        }
+        Ok((offsets, _)) => offsets,
+        Err(SupernoteError::MalformedMetadata(msg)) => return Ok(vec![VerifyWarning::UnreadableXref(msg)]),
+        Err(e) => return Err(e),
+    };
+
+    let mut warnings = Vec::new();
+    // Each object's dictionary, once it has been found where the table says.
+    let mut dicts: Vec<Option<String>> = Vec::with_capacity(offsets.len());
+    for (i, &offset) in offsets.iter().enumerate() {
+        let object = i + 1;
+        let Some(offset) = offset else {
+            dicts.push(None);
+            continue;
+        };
+        match read_object_header(&mut file, object, offset)? {
+            Some((dict, stream_start)) => {
+                if let Some(stream_start) = stream_start {
+                    check_stream(&mut file, object, &dict, stream_start, &mut warnings)?;
+                }
+                dicts.push(Some(dict));
+            }
+            None => {
+                warnings.push(VerifyWarning::ObjectOffset { object, offset });
+                dicts.push(None);
+            }
+        }
+    }
+
+    for (i, dict) in dicts.iter().enumerate() {
+        let Some(dict) = dict.as_deref().filter(|dict| type_is(dict, "/Page")) else {
+            continue;
+        };
+        let parent = reference(dict, "/Parent");
+        let parent_is_pages = parent
+            .and_then(|parent| dicts.get(parent.wrapping_sub(1)))
+            .and_then(|dict| dict.as_deref())
+            .is_some_and(|dict| type_is(dict, "/Pages"));
+        if !parent_is_pages {
+            warnings.push(VerifyWarning::PageParent { page: i + 1, parent });
+        }
+    }
+    Ok(warnings)
+}
+
+/// Reads the cross-reference table that `startxref` points at. Returns the offsets of objects 1, 2, ... (`None`
+/// for free ones) and the trailer's `/Size`. A table or trailer that doesn't parse is a
+/// [`SupernoteError::MalformedMetadata`].
+fn read_xref(file: &mut File) -> Result<(Vec<Option<u64>>, usize)> {
+    let malformed = |msg: &str| SupernoteError::MalformedMetadata(msg.to_string());
+
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let tail_start = file_len.saturating_sub(TRAILER_SEARCH_LEN);
+    let tail = read_at(file, tail_start, (file_len - tail_start) as usize)?;
+    let tail = String::from_utf8_lossy(&tail);
+    let startxref = tail.rfind("startxref").ok_or_else(|| malformed("no startxref"))?;
+    let xref_offset: u64 = tail[startxref + "startxref".len()..]
+        .split_whitespace()
+        .next()
+        .and_then(|offset| offset.parse().ok())
+        .ok_or_else(|| malformed("startxref has no offset"))?;
+    if xref_offset >= file_len {
+        return Err(malformed("startxref points past the end of the file"));
+    }
+
+    let table = read_at(file, xref_offset, (file_len - xref_offset) as usize)?;
+    let table = String::from_utf8_lossy(&table);
+    let mut lines = table.lines();
+    if lines.next().map(str::trim) != Some("xref") {
+        return Err(malformed("startxref doesn't point at an xref table"));
+    }
+    let count = lines
+        .next()
+        .and_then(|subsection| subsection.split_whitespace().nth(1))
+        .and_then(|count| count.parse::<usize>().ok())
+        .ok_or_else(|| malformed("the xref table has no subsection header"))?;
+    let mut offsets = Vec::with_capacity(count.saturating_sub(1));
+    for i in 0..count {
+        let entry = lines.next().ok_or_else(|| malformed("the xref table is cut short"))?;
+        let mut fields = entry.split_whitespace();
+        let offset = fields.next().and_then(|offset| offset.parse::<u64>().ok());
+        let kind = fields.nth(1);
+        match (i, offset, kind) {
+            // Object 0 is the head of the free list.
+            (0, Some(_), Some("f")) => {}
+            (1.., Some(_), Some("f")) => offsets.push(None),
+            (1.., Some(offset), Some("n")) => offsets.push(Some(offset)),
+            _ => return Err(malformed(&format!("xref entry {} is not valid", i))),
+        }
+    }
+
+    let trailer = &table[table.find("trailer").ok_or_else(|| malformed("no trailer after the xref table"))?..];
+    let size = trailer
+        .find("/Size")
+        .and_then(|size| trailer[size + "/Size".len()..].split_whitespace().next())
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| malformed("the trailer has no /Size"))?;
+    Ok((offsets, size))
+}
+
+/// Reads `object 0 obj << ... >>` at `offset`. Returns the dictionary's contents and, if a stream follows, the offset
+/// of its first byte; or `None` if some other object (or nothing) starts at `offset`.
+fn read_object_header(file: &mut File, object: usize, offset: u64) -> Result<Option<(String, Option<u64>)>> {
+    let expected = format!("{} 0 obj", object);
+    let mut bytes = Vec::new();
+    loop {
+        let chunk = read_at(file, offset + bytes.len() as u64, READ_CHUNK_LEN)?;
+        let at_end = chunk.len() < READ_CHUNK_LEN;
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() >= expected.len() && !bytes.starts_with(expected.as_bytes()) {
+            return Ok(None);
+        }
+        let body = &bytes[expected.len().min(bytes.len())..];
+        if body.trim_ascii_start().first().is_some_and(|&b| b != b'<') {
+            // Not a dictionary (this crate writes none, but numbers and arrays are objects too).
+            return Ok(Some((String::new(), None)));
+        }
+        if let Some(dict_end) = dict_end(body) {
+            let dict_start = expected.len();
+            let dict_end = dict_start + dict_end;
+            let dict = String::from_utf8_lossy(&bytes[dict_start..dict_end]);
+            let dict = dict.trim().trim_start_matches("<<").trim_end_matches(">>").trim().to_string();
+            // The stream keyword is followed by CRLF or LF, and then the data.
+            let rest = bytes[dict_end..].trim_ascii_start();
+            let stream_start = rest.starts_with(b"stream").then(|| {
+                let keyword_end = bytes.len() - rest.len() + "stream".len();
+                let eol_len = if bytes[keyword_end..].starts_with(b"\r\n") { 2 } else { 1 };
+                offset + (keyword_end + eol_len) as u64
+            });
+            return Ok(Some((dict, stream_start)));
+        }
+        if at_end {
+            return Ok(None);
+        }
+    }
+}
+
+/// The end (exclusive) of the dictionary that `bytes` starts with, after whitespace, or `None` if `bytes` doesn't
+/// hold all of it (or doesn't start with one). Literal strings are skipped, so `(<<)` in a title doesn't count as
+/// a nested dictionary.
+pub(crate) fn dict_end(bytes: &[u8]) -> Option<usize> {
+    let mut depth = 0;
+    let mut string_depth = 0;
+    let mut i = bytes.iter().position(|b| !b.is_ascii_whitespace())?;
+    if !bytes[i..].starts_with(b"<<") {
+        return None;
+    }
+    while i < bytes.len() {
+        match (string_depth, bytes[i]) {
+            (1.., b'\\') => i += 1,
+            (_, b'(') => string_depth += 1,
+            (1.., b')') => string_depth -= 1,
+            (0, b'<') if bytes.get(i + 1) == Some(&b'<') => {
+                depth += 1;
+                i += 1;
+            }
+            (0, b'>') if bytes.get(i + 1) == Some(&b'>') => {
+                depth -= 1;
+                i += 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Checks that the stream of `object`, starting at `stream_start`, is followed by `endstream` after `/Length` bytes
+/// and the end-of-line before it.
+fn check_stream(file: &mut File, object: usize, dict: &str, stream_start: u64, warnings: &mut Vec<VerifyWarning>) -> Result<()> {
+    let Some(declared) = value(dict, "/Length").and_then(|length| length.parse::<u64>().ok()) else {
+        warnings.push(VerifyWarning::StreamLength {
+            object,
+            declared: 0,
+            actual: None,
+        });
+        return Ok(());
+    };
+    let after = read_at(file, stream_start + declared, 16)?;
+    let ends_right = [&b"endstream"[..], b"\nendstream", b"\r\nendstream", b"\rendstream"]
+        .iter()
+        .any(|end| after.starts_with(end));
+    if ends_right {
+        return Ok(());
+    }
+
+    // Find where the stream does end, to report its real length.
+    let mut offset = stream_start;
+    let mut actual = None;
+    loop {
+        let chunk = read_at(file, offset, READ_CHUNK_LEN)?;
+        if let Some(pos) = chunk.windows(b"endstream".len()).position(|window| window == b"endstream") {
+            // Only the end-of-line right before `endstream` isn't part of the data.
+            let data = &chunk[..pos];
+            let eol_len = if data.ends_with(b"\r\n") {
+                2
+            } else {
+                usize::from(data.ends_with(b"\n") || data.ends_with(b"\r"))
+            };
+            actual = Some(offset + (pos - eol_len) as u64 - stream_start);
+            break;
+        }
+        if chunk.len() < READ_CHUNK_LEN {
+            break;
+        }
+        // Overlap the chunks so `endstream` isn't missed when it straddles two.
+        offset += (READ_CHUNK_LEN - b"endstream".len()) as u64;
+    }
+    warnings.push(VerifyWarning::StreamLength { object, declared, actual });
+    Ok(())
+}
+
+/// Whether a dictionary has `/Type type`, e.g. `/Page` but not `/Pages`.
+pub(crate) fn type_is(dict: &str, type_name: &str) -> bool {
+    value(dict, "/Type") == Some(type_name)
+}
+
+/// The token after `key` in a dictionary, e.g. `12` for `/Length 12`.
+pub(crate) fn value<'a>(dict: &'a str, key: &str) -> Option<&'a str> {
+    let start = dict.match_indices(key).find_map(|(i, _)| {
+        let after = &dict[i + key.len()..];
+        // `/Length` shouldn't match `/Length1`.
+        after.starts_with(|c: char| c.is_whitespace()).then_some(after)
+    })?;
+    start.split_whitespace().next()
+}
+
+/// The object referenced by `key` in a dictionary, e.g. `2` for `/Parent 2 0 R`.
+pub(crate) fn reference(dict: &str, key: &str) -> Option<usize> {
+    value(dict, key)?.parse().ok()
+}
+
+/// Reads up to `len` bytes at `offset`, fewer at the end of the file.
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = Vec::with_capacity(len);
+    file.take(len as u64).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
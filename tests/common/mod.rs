@@ -0,0 +1,32 @@
+//! Helpers shared by the integration tests.
+
+use std::fs;
+use std::path::Path;
+use supernote_pdf::encode_rle;
+
+/// Writes a 5-page A5X notebook, each page with a different black bar on transparent paper.
+pub fn write_note(path: &Path) {
+    let (width, height) = (1404, 1872);
+    let mut file = b"noteSN_FILE_VER_20200001".to_vec();
+    let block = |file: &mut Vec<u8>, data: &[u8]| {
+        let address = file.len();
+        file.extend((data.len() as u32).to_le_bytes());
+        file.extend(data);
+        address
+    };
+    let header = block(&mut file, b"<MODULE_LABEL:SNFILE_FEATURE><FILE_TYPE:NOTE><APPLY_EQUIPMENT:A5X>");
+    let mut footer = format!("<FILE_FEATURE:{}>", header);
+    for page in 0..5 {
+        let mut pixels = vec![0x62; width * height];
+        for y in 100 + page * 50..300 + page * 50 {
+            pixels[y * width + 100..y * width + 800].fill(0x61);
+        }
+        let bitmap = block(&mut file, &encode_rle(&pixels));
+        let layer = block(&mut file, format!("<LAYERPROTOCOL:RATTA_RLE><LAYERBITMAP:{}>", bitmap).as_bytes());
+        let page_meta = block(&mut file, format!("<LAYERSEQ:MAINLAYER><MAINLAYER:{}>", layer).as_bytes());
+        footer.push_str(&format!("<PAGE{}:{}>", page + 1, page_meta));
+    }
+    let footer = block(&mut file, footer.as_bytes());
+    file.extend((footer as u32).to_le_bytes());
+    fs::write(path, file).unwrap();
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::write_note;
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn conversions_are_byte_identical() {
+    let dir = tempfile::tempdir().unwrap();
+    let note = dir.path().join("pages.note");
+    write_note(&note);
+
+    // The PDF's /Lang follows the locale unless --lang is given, so each run gets a different one.
+    let convert = |output: &str, locale: &str, flags: &[&str]| {
+        let output = dir.path().join(output);
+        let result = Command::new(env!("CARGO_BIN_EXE_supernote_pdf"))
+            .env("LC_ALL", locale)
+            .args(flags)
+            .arg("--no-progress")
+            .arg("-i")
+            .arg(&note)
+            .arg("-o")
+            .arg(&output)
+            .output()
+            .unwrap();
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        fs::read(output).unwrap()
+    };
+    let first = convert("first.pdf", "C", &["--deterministic", "--lang", "en-US"]);
+    let second = convert("second.pdf", "ja_JP.UTF-8", &["--deterministic", "--lang", "en-US"]);
+    assert_eq!(first.windows(12).filter(|&w| w == b"/Type /Page ").count(), 5);
+    assert!(first == second, "two --deterministic conversions of the same notebook differ");
+
+    // Rendering pages in parallel must not change the output either.
+    let parallel = convert("parallel.pdf", "de_DE.UTF-8", &["--lang", "en-US"]);
+    assert!(first == parallel, "parallel and --deterministic conversions of the same notebook differ");
+
+    let localized = convert("localized.pdf", "de_DE.UTF-8@euro", &["--deterministic"]);
+    // `de-DE`, as a UTF-16 text string.
+    let lang = b"/Lang <FEFF00640065002D00440045>";
+    assert!(localized.windows(lang.len()).any(|w| w == lang), "/Lang doesn't follow LC_ALL");
+}
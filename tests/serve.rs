@@ -0,0 +1,94 @@
+#![cfg(feature = "serve")]
+
+mod common;
+
+use common::write_note;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command, Stdio};
+
+/// A `--serve` process, stopped when dropped.
+struct Server {
+    process: Child,
+    addr: SocketAddr,
+}
+
+impl Server {
+    /// Starts a server on a free port and waits until it accepts connections.
+    fn start() -> Server {
+        let mut process = Command::new(env!("CARGO_BIN_EXE_supernote_pdf"))
+            .args(["--serve", "--port", "0"])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stderr = BufReader::new(process.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line).unwrap();
+        let addr = line
+            .strip_prefix("Listening on http://")
+            .and_then(|rest| rest.split(' ').next())
+            .unwrap_or_else(|| panic!("unexpected server output: {:?}", line))
+            .parse()
+            .unwrap();
+        Server { process, addr }
+    }
+
+    /// Posts `body` to `path` and returns the response's status code and body.
+    fn post(&self, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            path,
+            self.addr,
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let header_end = response.windows(4).position(|w| w == b"\r\n\r\n").expect("a complete response") + 4;
+        let status = String::from_utf8_lossy(&response[..header_end])
+            .split(' ')
+            .nth(1)
+            .unwrap()
+            .parse()
+            .unwrap();
+        (status, response.split_off(header_end))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+#[test]
+fn converts_a_posted_note() {
+    let dir = tempfile::tempdir().unwrap();
+    let note = dir.path().join("pages.note");
+    write_note(&note);
+    let server = Server::start();
+
+    let (status, body) = server.post("/convert", &fs::read(&note).unwrap());
+    assert_eq!(status, 200, "{}", String::from_utf8_lossy(&body));
+    assert!(body.starts_with(b"%PDF-"));
+
+    let (status, body) = server.post("/convert?page_format=ccitt&dpi=300", &fs::read(&note).unwrap());
+    assert_eq!(status, 200, "{}", String::from_utf8_lossy(&body));
+    assert!(body.starts_with(b"%PDF-"));
+}
+
+#[test]
+fn rejects_unknown_query_parameters() {
+    let server = Server::start();
+    let (status, body) = server.post("/convert?grayscale=true", b"");
+    assert_eq!(status, 400);
+    assert!(String::from_utf8_lossy(&body).contains("grayscale"));
+
+    let (status, _) = server.post("/convert?page_format=png", b"");
+    assert_eq!(status, 400);
+}